@@ -1,22 +1,17 @@
 use crate::{
     compact_printer::CompactHomologies,
     external,
+    input::open_input,
     matching::solve_matching_problem,
     score_calc::ScoringCtxt,
-    structures::{AdderPayload, CrucibleCtxt},
+    structures::{metadata_path, AdderPayload, CrucibleCtxt},
 };
 use ahash::AHashMap;
 use anyhow::bail;
 use itertools::Itertools;
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use seq_io::{fasta::OwnedRecord, BaseRecord};
-use std::{
-    cell::RefCell,
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
-    sync::Arc,
-};
+use std::{cell::RefCell, fs::File, io::BufWriter, path::PathBuf, sync::Arc};
 use thread_local::ThreadLocal;
 use tracing::info;
 
@@ -43,13 +38,12 @@ impl AdderContext {
         })
     }
     pub fn manual_construction(base_dir: &PathBuf) -> anyhow::Result<Self> {
-        let hmm_ctxt_path = base_dir.join("melt.json");
+        let hmm_ctxt = CrucibleCtxt::from_path(metadata_path(base_dir))?;
         let scores_path = base_dir.join("scores.json");
         let queries_path = base_dir.parent().unwrap().join("queries.fasta");
-        let hmm_ctxt = serde_json::from_reader(BufReader::new(File::open(&hmm_ctxt_path)?))?;
         let transposed = AdderPayload::from_path(&scores_path)?.transpose(&hmm_ctxt);
         let queries_failiable: Result<Vec<_>, _> =
-            seq_io::fasta::Reader::new(File::open(&queries_path)?)
+            seq_io::fasta::Reader::new(open_input(&queries_path)?)
                 .records()
                 .collect();
         let queries = queries_failiable?;
@@ -94,9 +88,7 @@ impl Subweights {
 
 impl AdderContext {
     pub fn hmm_path(&self, hmm_id: u32) -> PathBuf {
-        self.base_dir
-            .join("subsets")
-            .join(format!("{}.hmm", hmm_id))
+        self.hmm_ctxt.hmm_path(&self.base_dir, hmm_id)
     }
 
     pub fn process_one_hmm(
@@ -105,11 +97,12 @@ impl AdderContext {
         subweights: &mut Subweights,
         // hits: &[(u32, f64)],
     ) -> anyhow::Result<()> {
-        let metadata = &self.hmm_ctxt.metadata[hmm_id as usize];
         let hits = &self.transposed_scores[hmm_id as usize];
         if hits.is_empty() {
             return Ok(());
         }
+        // the columns of this HMM only, copied out of the counts file if it is mapped
+        let columns = self.hmm_ctxt.column_counts(hmm_id as usize).collect_vec();
         let queries_for_hmm = hits
             .iter()
             .map(|&(seq_id, _)| &self.queries[seq_id as usize]);
@@ -137,9 +130,8 @@ impl AdderContext {
                         column_ix += 1;
                     }
                     _ if c.is_ascii_uppercase() => {
-                        let weight_delta =
-                            seq_weight * metadata.chars_cnt[column_ix as usize] as f64;
-                        let global_column = metadata.column_poitions[column_ix as usize];
+                        let (global_column, count) = columns[column_ix as usize];
+                        let weight_delta = seq_weight * count as f64;
                         subweights.weights[seq_id as usize]
                             .entry((residue_ix, global_column as u32))
                             .and_modify(|w| *w += weight_delta)
@@ -155,7 +147,7 @@ impl AdderContext {
                     }
                 }
             }
-            assert_eq!(column_ix, metadata.column_poitions.len() as u32);
+            assert_eq!(column_ix, columns.len() as u32);
             record_id += 1;
         }
         Ok(())
@@ -192,8 +184,12 @@ pub fn add_queries(
     outfile: &PathBuf,
     base_alignment_path: &PathBuf,
 ) -> anyhow::Result<()> {
+    // a backbone from outside the melt has to be the alignment it was made from
+    if !base_alignment_path.starts_with(&ctxt.base_dir) {
+        ctxt.hmm_ctxt.check_alignment(base_alignment_path)?;
+    }
     let subweights = unoptimized_process_transposed_payload(&ctxt)?;
-    let m = ctxt.hmm_ctxt.metadata[0].column_poitions.len();
+    let m = ctxt.hmm_ctxt.num_consensus_columns();
     let dp_solutions: Vec<Vec<i32>> = subweights
         .weights
         .into_par_iter()
@@ -0,0 +1,363 @@
+use crate::input::decode_text;
+use anyhow::bail;
+use clap::{ArgEnum, Args};
+use seq_io::fasta::OwnedRecord;
+use serde::{Deserialize, Serialize};
+
+/// the residues an alignment may consist of
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Alphabet {
+    /// any byte but a gap is a residue, nucleotides being told from amino acids
+    /// by which residues make up the alignment
+    #[default]
+    Any,
+    /// nucleotides and their IUPAC ambiguity codes
+    Dna,
+    /// like `dna`, with U in place of T
+    Rna,
+    /// amino acids, their ambiguity codes and stop (`*`)
+    Protein,
+}
+
+impl Alphabet {
+    /// the residues (in upper case) of the alphabet, `None` meaning anything goes
+    pub fn residues(self) -> Option<&'static [u8]> {
+        match self {
+            Alphabet::Any => None,
+            Alphabet::Dna => Some(b"ACGTRYSWKMBDHVN"),
+            Alphabet::Rna => Some(b"ACGURYSWKMBDHVN"),
+            Alphabet::Protein => Some(b"ACDEFGHIKLMNPQRSTVWYBZJUOX*"),
+        }
+    }
+
+    /// guesses the alphabet of `records` from their counted characters: nucleotides
+    /// if nine in ten of them are A, C, G, T, U or N (RNA if there is U but no T),
+    /// amino acids otherwise
+    pub fn detect(records: &[OwnedRecord], counter: &CharCounter) -> Alphabet {
+        let mut counts = [0usize; 256];
+        for r in records {
+            for &c in &r.seq {
+                if counter.counts(c) {
+                    counts[c.to_ascii_uppercase() as usize] += 1;
+                }
+            }
+        }
+        let total = counts.iter().sum::<usize>();
+        let nucleotides = b"ACGTUN".iter().map(|&c| counts[c as usize]).sum::<usize>();
+        if nucleotides * 10 < total * 9 {
+            Alphabet::Protein
+        } else if counts[b'U' as usize] > 0 && counts[b'T' as usize] == 0 {
+            Alphabet::Rna
+        } else {
+            Alphabet::Dna
+        }
+    }
+
+    /// the alphabet given, or the one detected from `records` for `any`
+    pub fn resolve(self, records: &[OwnedRecord], counter: &CharCounter) -> Alphabet {
+        match self {
+            Alphabet::Any => Alphabet::detect(records, counter),
+            alphabet => alphabet,
+        }
+    }
+
+    /// the option telling HMMER tools the alphabet, if it is known
+    pub fn hmmer_flag(self) -> Option<&'static str> {
+        match self {
+            Alphabet::Any => None,
+            Alphabet::Dna => Some("--dna"),
+            Alphabet::Rna => Some("--rna"),
+            Alphabet::Protein => Some("--amino"),
+        }
+    }
+}
+
+/// what lowercase characters, which aligners like MAFFT (`--add`) and hmmalign use for
+/// insertions, stand for
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LowercasePolicy {
+    /// residues like any other
+    #[default]
+    Regular,
+    /// missing data, not counted
+    Missing,
+    /// insertions, whose columns are removed before decomposing
+    Strip,
+}
+
+impl LowercasePolicy {
+    pub fn is_regular(&self) -> bool {
+        *self == LowercasePolicy::Regular
+    }
+}
+
+/// which characters of the alignment count towards the per-column character counts
+#[derive(Args, Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct CharacterOptions {
+    /// Alphabet of the alignment; characters outside it (other than `.` and `?`)
+    /// are an error
+    #[clap(long, arg_enum, default_value = "any")]
+    #[serde(default)]
+    pub alphabet: Alphabet,
+    /// Characters counted as missing data instead of residues, e.g. NX?. (letters
+    /// in either case)
+    #[clap(long, default_value = "", hide_default_value = true)]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub missing: String,
+    /// Characters taken as gaps besides `-`, e.g. .?
+    #[clap(long, default_value = "", hide_default_value = true)]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub gaps: String,
+    /// What lowercase characters are: regular residues, missing data, or
+    /// insertions whose columns are stripped
+    #[clap(long, arg_enum, default_value = "regular")]
+    #[serde(default, skip_serializing_if = "LowercasePolicy::is_regular")]
+    pub lowercase: LowercasePolicy,
+}
+
+impl CharacterOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// groups of residues whose counts are kept apart, each ending with all the other
+/// counted characters
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResidueClasses {
+    /// A, C, G and T (or U)
+    Nucleotide,
+    /// the six groups of amino acids of Dayhoff: AGPST, C, DENQ, HKR, ILMV and FWY
+    Dayhoff,
+}
+
+impl ResidueClasses {
+    /// the residues (in upper case) of every class, but for the last
+    fn groups(self) -> &'static [&'static [u8]] {
+        match self {
+            ResidueClasses::Nucleotide => &[b"A", b"C", b"G", b"TU"],
+            ResidueClasses::Dayhoff => &[b"AGPST", b"C", b"DENQ", b"HKR", b"ILMV", b"FWY"],
+        }
+    }
+
+    /// the names of the classes, in order
+    pub fn names(self) -> Vec<String> {
+        self.groups()
+            .iter()
+            .map(|g| String::from_utf8_lossy(g).into_owned())
+            .chain(std::iter::once("other".to_string()))
+            .collect()
+    }
+
+    pub fn num_classes(self) -> usize {
+        self.groups().len() + 1
+    }
+
+    /// the class of every byte, the last one for those in no group
+    pub fn table(self) -> [u8; 256] {
+        let groups = self.groups();
+        let mut table = [groups.len() as u8; 256];
+        for (i, group) in groups.iter().enumerate() {
+            for &c in group.iter() {
+                table[c as usize] = i as u8;
+                table[c.to_ascii_lowercase() as usize] = i as u8;
+            }
+        }
+        table
+    }
+}
+
+/// the most characters not counted for which `CharCounter::add_counts` compares
+/// whole vectors of bytes against them instead of looking every byte up
+const VECTOR_UNCOUNTED: usize = 4;
+
+/// a lookup table telling the counted characters from gaps and missing data
+#[derive(Clone)]
+pub struct CharCounter {
+    counted: [bool; 256],
+    valid: [bool; 256],
+    gap: [bool; 256],
+    /// the bytes not counted
+    uncounted: Vec<u8>,
+    alphabet: Alphabet,
+}
+
+impl CharCounter {
+    pub fn new(options: &CharacterOptions) -> Self {
+        let mut counted = [true; 256];
+        let mut valid = [true; 256];
+        if let Some(residues) = options.alphabet.residues() {
+            valid = [false; 256];
+            for &c in residues.iter().chain(b".?") {
+                valid[c as usize] = true;
+                valid[c.to_ascii_lowercase() as usize] = true;
+            }
+        }
+        if options.lowercase == LowercasePolicy::Missing {
+            for c in b'a'..=b'z' {
+                counted[c as usize] = false;
+            }
+        }
+        let mut gap = [false; 256];
+        for &c in options.missing.as_bytes() {
+            for c in [c.to_ascii_uppercase(), c.to_ascii_lowercase()] {
+                valid[c as usize] = true;
+                counted[c as usize] = false;
+            }
+        }
+        for &c in b"-".iter().chain(options.gaps.as_bytes()) {
+            valid[c as usize] = true;
+            counted[c as usize] = false;
+            gap[c as usize] = true;
+        }
+        Self {
+            counted,
+            valid,
+            gap,
+            uncounted: (0..=255).filter(|&c| !counted[c as usize]).collect(),
+            alphabet: options.alphabet,
+        }
+    }
+
+    /// whether `c` counts as a character of its column
+    #[inline]
+    pub fn counts(&self, c: u8) -> bool {
+        self.counted[c as usize]
+    }
+
+    /// whether `c` is a gap
+    #[inline]
+    pub fn is_gap(&self, c: u8) -> bool {
+        self.gap[c as usize]
+    }
+
+    /// rewrites every gap of `records` to `-`, the one gap character all tools agree on
+    pub fn normalize_gaps(&self, records: &mut [OwnedRecord]) {
+        for r in records {
+            for c in r.seq.iter_mut() {
+                if self.is_gap(*c) {
+                    *c = b'-';
+                }
+            }
+        }
+    }
+
+    /// adds one to `counts[j]` for every counted character `seq[j]`, 16 columns at a
+    /// time with SSE2 when only a few characters are not counted (as with the
+    /// default options, where only gaps are not)
+    pub fn add_counts(&self, counts: &mut [u32], seq: &[u8]) {
+        let width = counts.len().min(seq.len());
+        let (counts, seq) = (&mut counts[..width], &seq[..width]);
+        #[cfg(target_arch = "x86_64")]
+        let done = if self.uncounted.len() <= VECTOR_UNCOUNTED {
+            // SAFETY: SSE2 is part of every x86-64 processor, and `counts` is as long
+            // as `seq`
+            unsafe { add_counts_sse2(counts, seq, &self.uncounted) }
+        } else {
+            0
+        };
+        #[cfg(not(target_arch = "x86_64"))]
+        let done = 0;
+        for (x, &c) in counts[done..].iter_mut().zip(&seq[done..]) {
+            *x += self.counts(c) as u32;
+        }
+    }
+
+    /// the number of counted characters in `seq`
+    pub fn count(&self, seq: &[u8]) -> usize {
+        seq.iter().filter(|&&c| self.counts(c)).count()
+    }
+
+    /// fails on the first character of `records` outside the alphabet
+    pub fn validate(&self, records: &[OwnedRecord]) -> anyhow::Result<()> {
+        for r in records {
+            if let Some(&c) = r.seq.iter().find(|&&c| !self.valid[c as usize]) {
+                bail!(
+                    "sequence {} has the character {:?}, which is not in the {} alphabet \
+                     (list it in --missing to take it as missing data)",
+                    decode_text(&r.head),
+                    c as char,
+                    format!("{:?}", self.alphabet).to_lowercase()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `CharCounter::add_counts` over the whole blocks of 16 bytes of `seq`, counting all
+/// but the (at most `VECTOR_UNCOUNTED`) bytes of `uncounted`; returns the number of
+/// bytes it counted. `counts` must be as long as `seq`
+#[cfg(target_arch = "x86_64")]
+unsafe fn add_counts_sse2(counts: &mut [u32], seq: &[u8], uncounted: &[u8]) -> usize {
+    use std::arch::x86_64::*;
+    let zero = _mm_setzero_si128();
+    let one = _mm_set1_epi8(1);
+    let mut needles = [zero; VECTOR_UNCOUNTED];
+    for (needle, &c) in needles.iter_mut().zip(uncounted) {
+        *needle = _mm_set1_epi8(c as i8);
+    }
+    let needles = &needles[..uncounted.len()];
+    let blocks = seq.len() / 16;
+    for b in 0..blocks {
+        let bytes = _mm_loadu_si128(seq.as_ptr().add(b * 16) as *const __m128i);
+        let mut hits = zero;
+        for &needle in needles {
+            hits = _mm_or_si128(hits, _mm_cmpeq_epi8(bytes, needle));
+        }
+        // one for every counted byte, widened to 32 bits in four vectors
+        let counted = _mm_andnot_si128(hits, one);
+        let (low, high) = (
+            _mm_unpacklo_epi8(counted, zero),
+            _mm_unpackhi_epi8(counted, zero),
+        );
+        let widened = [
+            _mm_unpacklo_epi16(low, zero),
+            _mm_unpackhi_epi16(low, zero),
+            _mm_unpacklo_epi16(high, zero),
+            _mm_unpackhi_epi16(high, zero),
+        ];
+        let out = counts.as_mut_ptr().add(b * 16) as *mut __m128i;
+        for (i, &part) in widened.iter().enumerate() {
+            let out = out.add(i);
+            _mm_storeu_si128(out, _mm_add_epi32(_mm_loadu_si128(out), part));
+        }
+    }
+    blocks * 16
+}
+
+/// removes every column with a lowercase character from `records` and the per-column
+/// `reference` annotation, returning the columns removed
+pub fn strip_lowercase_columns(
+    records: &mut [OwnedRecord],
+    reference: Option<&mut Vec<u8>>,
+) -> Vec<usize> {
+    let width = records.iter().map(|r| r.seq.len()).max().unwrap_or(0);
+    let mut keep = vec![true; width];
+    for r in records.iter() {
+        for (j, c) in r.seq.iter().enumerate() {
+            if c.is_ascii_lowercase() {
+                keep[j] = false;
+            }
+        }
+    }
+    let removed = (0..width).filter(|&j| !keep[j]).collect::<Vec<_>>();
+    if removed.is_empty() {
+        return removed;
+    }
+    let strip = |seq: &mut Vec<u8>| {
+        let mut j = 0;
+        seq.retain(|_| {
+            j += 1;
+            keep[j - 1]
+        });
+    };
+    records.iter_mut().for_each(|r| strip(&mut r.seq));
+    if let Some(rf) = reference {
+        strip(rf);
+    }
+    removed
+}
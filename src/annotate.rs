@@ -0,0 +1,218 @@
+use crate::{input::decode_text, structures::HmmMeta, tree_ops::EditableTree};
+use ogcat::ogtree::Tree;
+use seq_io::fasta::OwnedRecord;
+use std::{
+    cmp::Reverse,
+    io::{self, Write},
+};
+
+/// the smallest subset (as ranges of `reordered_taxa`) each of `ntaxa` taxa is in,
+/// the first of them on ties; `None` for the taxa in no subset
+pub fn finest_subsets(
+    ranges: &[(usize, usize)],
+    reordered_taxa: &[usize],
+    ntaxa: usize,
+) -> Vec<Option<usize>> {
+    let mut finest: Vec<Option<usize>> = vec![None; ntaxa];
+    for (i, &(lb, ub)) in ranges.iter().enumerate() {
+        for &t in &reordered_taxa[lb..ub] {
+            let smaller = match finest[t] {
+                Some(j) => ub - lb < ranges[j].1 - ranges[j].0,
+                None => true,
+            };
+            if smaller {
+                finest[t] = Some(i);
+            }
+        }
+    }
+    finest
+}
+
+/// a color for the subset numbered `i`, hues a golden angle apart telling
+/// neighboring subsets apart
+pub fn subset_color(i: usize) -> String {
+    let hue = (i as f64 * 137.507_764) % 360.0;
+    let (s, l) = (0.65, 0.5);
+    let c = (1.0 - (2.0 * l - 1.0f64).abs()) * s;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match (hue / 60.0) as usize {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    let byte = |v: f64| ((v + m) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", byte(r), byte(g), byte(b))
+}
+
+/// writes an iTOL color strip dataset coloring every taxon named by `names` by its
+/// subset in `subsets` (see `finest_subsets`)
+pub fn write_itol_colorstrip<W: Write>(
+    mut writer: W,
+    names: &[String],
+    subsets: &[Option<usize>],
+) -> io::Result<()> {
+    writeln!(writer, "DATASET_COLORSTRIP")?;
+    writeln!(writer, "SEPARATOR TAB")?;
+    writeln!(writer, "DATASET_LABEL\tcrucible subsets")?;
+    writeln!(writer, "COLOR\t#000000")?;
+    writeln!(writer, "DATA")?;
+    for (name, subset) in names.iter().zip(subsets) {
+        if let Some(i) = subset {
+            writeln!(writer, "{}\t{}\tsubset {}", name, subset_color(*i), i)?;
+        }
+    }
+    Ok(())
+}
+
+/// `tree` as a Nexus tree block for FigTree, every leaf annotated with its subset
+/// in `subsets` (see `finest_subsets`) and colored by it
+pub fn figtree_nexus(tree: &Tree, names: &[String], subsets: &[Option<usize>]) -> String {
+    let annotated = names
+        .iter()
+        .zip(subsets)
+        .map(|(name, subset)| match subset {
+            Some(i) => format!("{}[&subset={},!color={}]", name, i, subset_color(*i)),
+            None => name.clone(),
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "#NEXUS\nbegin trees;\n\ttree decomposition = [&R] {}\nend;\n",
+        EditableTree::from_tree(tree).to_newick(&annotated)
+    )
+}
+
+/// the smallest other subset containing each subset (as ranges of the sorted
+/// sequences), an earlier one for identical ranges, found in one sweep as the ranges of
+/// a decomposition are nested or disjoint
+pub fn subset_parents(ranges: &[(usize, usize)]) -> Vec<Option<usize>> {
+    let mut order = (0..ranges.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (ranges[i].0, Reverse(ranges[i].1), i));
+    let mut parents = vec![None; ranges.len()];
+    let mut stack: Vec<usize> = vec![];
+    for i in order {
+        while let Some(&top) = stack.last() {
+            if ranges[top].1 >= ranges[i].1 {
+                break;
+            }
+            stack.pop();
+        }
+        parents[i] = stack.last().copied();
+        stack.push(i);
+    }
+    parents
+}
+
+/// writes the nesting of the subsets in `metadata` as a Graphviz graph, every subset
+/// labelled by its size and its first few sequences of `records`
+pub fn write_decomposition_dot<W: Write>(
+    mut writer: W,
+    metadata: &[HmmMeta],
+    records: &[OwnedRecord],
+) -> io::Result<()> {
+    const SHOWN: usize = 3;
+    let ranges = metadata
+        .iter()
+        .map(|m| m.sequence_range)
+        .collect::<Vec<_>>();
+    writeln!(writer, "digraph decomposition {{")?;
+    writeln!(writer, "  node [shape=box];")?;
+    for (i, hmm) in metadata.iter().enumerate() {
+        let (lb, ub) = hmm.sequence_range;
+        let mut taxa = records[lb..ub.min(lb + SHOWN)]
+            .iter()
+            .map(|r| decode_text(&r.head).into_owned())
+            .collect::<Vec<_>>();
+        if ub - lb > SHOWN {
+            taxa.push("...".to_string());
+        }
+        let mut label = format!("{}: {} sequences", i, ub - lb);
+        if let Some(name) = &hmm.label {
+            label = format!("{} ({})", label, name);
+        }
+        if hmm.outlier {
+            label.push_str(", outliers");
+        }
+        let label = format!("{}\n{}", label, taxa.join(", "));
+        writeln!(
+            writer,
+            "  s{} [label=\"{}\"];",
+            i,
+            label
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        )?;
+    }
+    for (i, parent) in subset_parents(&ranges).into_iter().enumerate() {
+        if let Some(parent) = parent {
+            writeln!(writer, "  s{} -> s{};", parent, i)?;
+        }
+    }
+    writeln!(writer, "}}")
+}
+
+/// how deep each subset is nested, given its parent (see `subset_parents`), the
+/// outermost subsets being at level 0
+pub fn subset_depths(parents: &[Option<usize>]) -> Vec<usize> {
+    let mut depths: Vec<Option<usize>> = vec![None; parents.len()];
+    for i in 0..parents.len() {
+        let mut path = vec![];
+        let mut v = Some(i);
+        while let Some(u) = v {
+            if depths[u].is_some() {
+                break;
+            }
+            path.push(u);
+            v = parents[u];
+        }
+        let base = match v {
+            Some(u) => depths[u].unwrap() + 1,
+            None => 0,
+        };
+        for (d, &u) in path.iter().rev().enumerate() {
+            depths[u] = Some(base + d);
+        }
+    }
+    depths.into_iter().map(Option::unwrap).collect()
+}
+
+/// `field` quoted for CSV if it has to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// writes which subsets in `metadata` every sequence of `records` is in as CSV, one
+/// `taxon,subset_index,level` row per subset of each taxon, `level` being its depth
+/// in the nesting of the subsets (see `subset_depths`)
+pub fn write_membership_csv<W: Write>(
+    mut writer: W,
+    metadata: &[HmmMeta],
+    records: &[OwnedRecord],
+) -> io::Result<()> {
+    let ranges = metadata
+        .iter()
+        .map(|m| m.sequence_range)
+        .collect::<Vec<_>>();
+    let depths = subset_depths(&subset_parents(&ranges));
+    writeln!(writer, "taxon,subset_index,level")?;
+    for (i, &(lb, ub)) in ranges.iter().enumerate() {
+        for r in &records[lb..ub] {
+            writeln!(
+                writer,
+                "{},{},{}",
+                csv_field(&decode_text(&r.head)),
+                i,
+                depths[i]
+            )?;
+        }
+    }
+    Ok(())
+}
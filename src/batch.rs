@@ -0,0 +1,209 @@
+use crate::{
+    decomposition::DecompositionStrategy,
+    melt::{oneshot_melt, MeltOptions},
+    output::{check_outdir, RUN_MARKER},
+};
+use anyhow::bail;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, read_dir, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+use tracing::{info, info_span, warn};
+
+/// extensions of the alignments `melt-batch` picks up
+pub const ALIGNMENT_EXTENSIONS: &[&str] = &[
+    "fasta", "fa", "fas", "fna", "faa", "afa", "aln", "phy", "phylip", "sto", "stk", "nex", "nexus",
+];
+
+/// extensions of the trees `melt-batch` pairs with the alignments
+pub const TREE_EXTENSIONS: &[&str] = &["nwk", "newick", "tre", "tree", "treefile"];
+
+/// the name of `path` without its compression and format extensions, along with
+/// the format extension, e.g. (`gene1`, `fasta`) for `gene1.fasta.gz`
+pub fn split_extension(path: &Path) -> Option<(String, String)> {
+    let mut name = path.file_name()?.to_str()?;
+    for compressed in [".gz", ".bgz", ".zst"] {
+        if let Some(stripped) = name.strip_suffix(compressed) {
+            name = stripped;
+            break;
+        }
+    }
+    let (stem, extension) = name.rsplit_once('.')?;
+    Some((stem.to_string(), extension.to_ascii_lowercase()))
+}
+
+/// a gene of a batch: its alignment and, if found, its tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locus {
+    pub name: String,
+    pub alignment: PathBuf,
+    pub tree: Option<PathBuf>,
+}
+
+/// pairs the alignments in `dir` with the trees sharing their name (compressed or
+/// not), e.g. `gene1.fasta` with `gene1.nwk.gz`, sorted by name; the `extra_trees`
+/// are matched the same way, whatever their extension, and take precedence
+pub fn discover_loci(dir: &Path, extra_trees: &[PathBuf]) -> anyhow::Result<Vec<Locus>> {
+    let mut alignments = vec![];
+    let mut trees = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some((stem, extension)) = split_extension(&path) {
+            if ALIGNMENT_EXTENSIONS.contains(&extension.as_str()) {
+                alignments.push((stem, path));
+            } else if TREE_EXTENSIONS.contains(&extension.as_str()) {
+                trees.push((stem, path));
+            }
+        }
+    }
+    alignments.sort();
+    let mut explicit = vec![];
+    for path in extra_trees {
+        match split_extension(path) {
+            Some((stem, _)) if alignments.iter().any(|(name, _)| *name == stem) => {
+                explicit.push((stem, path.clone()))
+            }
+            _ => bail!(
+                "no alignment in {} for the tree {}",
+                dir.display(),
+                path.display()
+            ),
+        }
+    }
+    if let Some(((name, a), (_, b))) = alignments
+        .iter()
+        .tuple_windows()
+        .find(|((x, _), (y, _))| x == y)
+    {
+        bail!(
+            "more than one alignment is named {}: {} and {}",
+            name,
+            a.display(),
+            b.display()
+        );
+    }
+    let loci = alignments
+        .into_iter()
+        .map(|(name, alignment)| {
+            let matching = |trees: &[(String, PathBuf)]| {
+                trees
+                    .iter()
+                    .filter(|(stem, _)| *stem == name)
+                    .map(|(_, path)| path.clone())
+                    .min()
+            };
+            let tree = matching(&explicit).or_else(|| matching(&trees));
+            Locus {
+                name,
+                alignment,
+                tree,
+            }
+        })
+        .collect_vec();
+    if loci.is_empty() {
+        bail!("no alignments found in {}", dir.display());
+    }
+    Ok(loci)
+}
+
+/// how melting a gene of a batch went
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocusSummary {
+    pub name: String,
+    pub alignment: PathBuf,
+    pub tree: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_sequences: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_hmms: Option<usize>,
+    /// why the gene could not be melted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchSummary {
+    pub loci: Vec<LocusSummary>,
+}
+
+/// melts every gene found in `dir` (see `discover_loci`) in parallel, each into
+/// `outdir/<gene>` (estimating its tree if `options` say so), and
+/// writes a summary of them all to `outdir/batch.json`; a gene failing does not
+/// stop the others, but fails the batch in the end
+pub fn oneshot_melt_batch(
+    dir: &Path,
+    trees: &[PathBuf],
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+    outdir: &Path,
+) -> anyhow::Result<BatchSummary> {
+    if options.dry_run {
+        bail!("--dry-run plans a single melt only");
+    }
+    let owned = check_outdir(outdir, options.force)?;
+    let loci = discover_loci(dir, trees)?;
+    info!(num_loci = loci.len(), "found alignments");
+    create_dir_all(outdir)?;
+    if owned {
+        File::create(outdir.join(RUN_MARKER))?;
+    }
+    let summaries = loci
+        .par_iter()
+        .map(|locus| {
+            let _span = info_span!("locus", name = locus.name.as_str()).entered();
+            let result = oneshot_melt(
+                &locus.alignment,
+                locus.tree.as_ref().filter(|_| !options.no_tree),
+                strategy,
+                options,
+                &outdir.join(&locus.name),
+            );
+            let mut summary = LocusSummary {
+                name: locus.name.clone(),
+                alignment: locus.alignment.clone(),
+                tree: locus.tree.clone(),
+                num_sequences: None,
+                num_hmms: None,
+                error: None,
+            };
+            match result {
+                Ok(ctxt) => {
+                    summary.num_sequences = ctxt.metadata.iter().map(|m| m.sequence_range.1).max();
+                    summary.num_hmms = Some(ctxt.num_hmms());
+                }
+                Err(e) => {
+                    warn!(error = %format!("{:#}", e), "failed to melt");
+                    summary.error = Some(format!("{:#}", e));
+                }
+            }
+            summary
+        })
+        .collect::<Vec<_>>();
+    let summary = BatchSummary { loci: summaries };
+    serde_json::to_writer(
+        BufWriter::new(File::create(outdir.join("batch.json"))?),
+        &summary,
+    )?;
+    let failed = summary
+        .loci
+        .iter()
+        .filter(|l| l.error.is_some())
+        .collect_vec();
+    if let Some(first) = failed.first() {
+        bail!(
+            "{} of {} genes failed to melt, e.g. {}: {}",
+            failed.len(),
+            summary.loci.len(),
+            first.name,
+            first.error.as_deref().unwrap()
+        );
+    }
+    Ok(summary)
+}
@@ -0,0 +1,77 @@
+use crate::{decomposition::DecompositionOptions, melt::finish_hierarchy, structures::*};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// the member of `members` farthest from `from`, the first one on ties
+fn farthest(distances: &[Vec<f64>], members: &[usize], from: usize) -> usize {
+    let mut best = members[0];
+    for &m in members {
+        if distances[from][m] > distances[from][best] {
+            best = m;
+        }
+    }
+    best
+}
+
+/// splits `members` in place around two mutually distant seeds, members closer to the
+/// first seed (or equally close) coming first; returns the size of the first part,
+/// or `None` if all members are indistinguishable
+fn bisect(distances: &[Vec<f64>], members: &mut [usize]) -> Option<usize> {
+    let a = farthest(distances, members, members[0]);
+    let b = farthest(distances, members, a);
+    if distances[a][b] <= 0.0 {
+        return None;
+    }
+    members.sort_by_key(|&m| distances[a][m] > distances[b][m]);
+    Some(
+        members
+            .iter()
+            .take_while(|&&m| distances[a][m] <= distances[b][m])
+            .count(),
+    )
+}
+
+/// a tree-free decomposition: repeatedly bisects the largest cluster of sequences
+/// by their `distances` until the stopping rule of `options` holds, ties between
+/// equally large clusters going to the leftmost one; taxon ids are the indices
+/// into `distances`
+pub fn cluster_decomp(distances: &[Vec<f64>], options: &DecompositionOptions) -> TaxaHierarchy {
+    let n = distances.len();
+    let mut reordered_taxa = (0..n).collect::<Vec<_>>();
+    let mut decomposition_ranges = vec![(0usize, n)];
+    let mut disjoint_ranges: Vec<(usize, usize)> = Vec::new();
+    let mut splits: Vec<(usize, usize, usize)> = Vec::new();
+    let min_side = options.min_size.max(1);
+    let mut pq = BinaryHeap::new();
+    pq.push((n, Reverse((0usize, n))));
+    while let Some((size, Reverse((lb, ub)))) = pq.pop() {
+        if options.should_stop(size, pq.len() + 1 + disjoint_ranges.len(), n) {
+            disjoint_ranges.push((lb, ub));
+            break;
+        }
+        let mid = match bisect(distances, &mut reordered_taxa[lb..ub]) {
+            Some(left) if left >= min_side && size - left >= min_side => lb + left,
+            _ => {
+                disjoint_ranges.push((lb, ub));
+                continue;
+            }
+        };
+        splits.push((lb, mid, ub));
+        if mid - lb >= 2 {
+            decomposition_ranges.push((lb, mid));
+        }
+        if ub - mid > 2 {
+            decomposition_ranges.push((mid, ub));
+        }
+        pq.push((mid - lb, Reverse((lb, mid))));
+        pq.push((ub - mid, Reverse((mid, ub))));
+    }
+    disjoint_ranges.extend(pq.into_iter().map(|(_, Reverse(range))| range));
+    finish_hierarchy(
+        reordered_taxa,
+        n,
+        decomposition_ranges,
+        disjoint_ranges,
+        splits,
+        options,
+    )
+}
@@ -1,16 +1,13 @@
 use crate::{
     adder::{add_queries, AdderContext},
-    melt::oneshot_melt,
+    decomposition::{BalancedCut, DecompositionOptions, SizeSpec},
+    melt::{self, oneshot_melt, MeltOptions},
+    output::unpack_directory,
     score_calc::ScoringCtxt,
-    structures::CrucibleCtxt,
+    structures::{metadata_path, CrucibleCtxt},
 };
 use anyhow::bail;
-use std::{
-    fs::{self, File},
-    io::BufReader,
-    path::PathBuf,
-    time::Instant,
-};
+use std::{fs, path::PathBuf, time::Instant};
 use tracing::info;
 pub fn combined_analysis(
     input_path: PathBuf,
@@ -26,9 +23,14 @@ pub fn combined_analysis(
     }
     // we first decide the eHMM path and also the backbone MSA path
     let (actual_backbone_path, ehmm_ctxt, ehmm_path) = if fs::metadata(&backbone_path)?.is_dir() {
-        let crucible_ctxt: CrucibleCtxt =
-            serde_json::from_reader(BufReader::new(File::open(backbone_path.join("melt.json"))?))?;
-        let bb_path = backbone_path.join("subsets").join("0.afa");
+        let crucible_ctxt = CrucibleCtxt::from_path(metadata_path(&backbone_path))?;
+        if let Some(tree_path) = &tree_path {
+            crucible_ctxt.check_tree(tree_path)?;
+        }
+        if unpack_directory(&backbone_path.join("subsets"))? {
+            info!("unpacked the archived subsets");
+        }
+        let bb_path = melt::backbone_path(&backbone_path, &crucible_ctxt);
         (bb_path, crucible_ctxt, backbone_path)
     } else {
         let actual_ehmm_dir = if let Some(ehmm_path) = ehmm_path {
@@ -40,8 +42,15 @@ pub fn combined_analysis(
         };
         let ctxt = oneshot_melt(
             &backbone_path,
-            &tree_path.expect("building eHMM must use a backbone tree"),
-            10,
+            Some(&tree_path.expect("building eHMM must use a backbone tree")),
+            &BalancedCut,
+            &MeltOptions {
+                decomposition: DecompositionOptions {
+                    max_size: Some(SizeSpec::Count(10)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
             &actual_ehmm_dir,
         )?;
         (backbone_path, ctxt, actual_ehmm_dir)
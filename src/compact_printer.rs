@@ -7,6 +7,8 @@ use seq_io::{
     BaseRecord,
 };
 
+use crate::input::open_input;
+
 /// data structure for keeping track of singleton columns efficiently
 pub struct CompactHomologies {
     pub num_columns: usize,
@@ -146,7 +148,7 @@ impl FormattedHomologies {
     where
         W: Write,
     {
-        let mut reader = Reader::from_path(base_alignment_path)?;
+        let mut reader = Reader::new(open_input(base_alignment_path)?);
         let base_pos_map = self.positions.last().unwrap();
         while let Some(record_iffy) = reader.next() {
             let record = record_iffy?;
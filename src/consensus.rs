@@ -0,0 +1,202 @@
+use crate::tree_ops::EditableTree;
+use ahash::AHashMap;
+use anyhow::bail;
+use clap::ArgEnum;
+use fixedbitset::FixedBitSet;
+use ogcat::ogtree::*;
+
+/// how the splits of several trees are combined into one tree
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsensusMode {
+    /// keep the splits found in every tree
+    Strict,
+    /// keep the splits found in more than half of the trees
+    Majority,
+    /// add splits from most to least frequent as long as they are compatible
+    Greedy,
+}
+
+/// how often a split was seen across the trees and the lengths given to it
+struct SplitStats {
+    count: usize,
+    total_length: f64,
+    num_lengths: usize,
+}
+
+/// the non-trivial clusters of `tree` (normalized to exclude taxon 0) with their edge
+/// lengths, each once: the two edges below a bifurcating root make the same split,
+/// whose length is then theirs together
+fn tree_splits(tree: &Tree, ntaxa: usize) -> Vec<(FixedBitSet, f64)> {
+    let mut below: Vec<FixedBitSet> = vec![FixedBitSet::default(); tree.num_nodes()];
+    let mut splits: Vec<(FixedBitSet, f64)> = vec![];
+    let mut seen: AHashMap<FixedBitSet, usize> = AHashMap::new();
+    for v in tree.postorder() {
+        let mut cluster = FixedBitSet::with_capacity(ntaxa);
+        if tree.is_leaf(v) {
+            cluster.insert(tree.taxa[v] as usize);
+        } else {
+            for c in tree.children(v) {
+                cluster.union_with(&below[c]);
+            }
+        }
+        let size = cluster.count_ones(..);
+        if !tree.is_root(v) && size > 1 && size + 1 < ntaxa {
+            let mut normalized = cluster.clone();
+            if normalized.contains(0) {
+                normalized.toggle_range(..);
+            }
+            let length = tree.lengths[v];
+            match seen.get(&normalized) {
+                Some(&i) => {
+                    let total = &mut splits[i].1;
+                    if !(total.is_finite() && *total >= 0.0) {
+                        *total = length;
+                    } else if length.is_finite() && length >= 0.0 {
+                        *total += length;
+                    }
+                }
+                None => {
+                    seen.insert(normalized.clone(), splits.len());
+                    splits.push((normalized, length));
+                }
+            }
+        }
+        below[v] = cluster;
+    }
+    splits
+}
+
+fn compatible(a: &FixedBitSet, b: &FixedBitSet) -> bool {
+    a.is_disjoint(b) || a.is_subset(b) || b.is_subset(a)
+}
+
+/// the node of the last (so smallest) of `cluster_nodes`, added by decreasing size,
+/// whose cluster satisfies `contains`
+fn smallest_containing<F>(cluster_nodes: &[(usize, &FixedBitSet)], contains: F) -> Option<usize>
+where
+    F: Fn(&FixedBitSet) -> bool,
+{
+    cluster_nodes
+        .iter()
+        .rev()
+        .find(|(_, c)| contains(c))
+        .map(|&(node, _)| node)
+}
+
+/// combines every tree of `collection` into one (possibly multifurcating) tree,
+/// each edge carrying the percentage of trees having it as support and its mean length
+pub fn consensus_tree(
+    collection: &mut TreeCollection,
+    mode: ConsensusMode,
+) -> anyhow::Result<Tree> {
+    let ntaxa = collection.ntaxa();
+    let ntrees = collection.trees.len();
+    let mut stats: AHashMap<FixedBitSet, SplitStats> = AHashMap::new();
+    let mut pendant_lengths = vec![(0.0, 0usize); ntaxa];
+    for tree in &collection.trees {
+        if tree.ntaxa != ntaxa {
+            bail!("consensus requires every tree to have all {} taxa", ntaxa);
+        }
+        for (split, length) in tree_splits(tree, ntaxa) {
+            let entry = stats.entry(split).or_insert(SplitStats {
+                count: 0,
+                total_length: 0.0,
+                num_lengths: 0,
+            });
+            entry.count += 1;
+            if length.is_finite() && length >= 0.0 {
+                entry.total_length += length;
+                entry.num_lengths += 1;
+            }
+        }
+        for v in tree.postorder() {
+            let length = tree.lengths[v];
+            if tree.is_leaf(v) && length.is_finite() && length >= 0.0 {
+                let (total, cnt) = &mut pendant_lengths[tree.taxa[v] as usize];
+                *total += length;
+                *cnt += 1;
+            }
+        }
+    }
+    let mut ranked = stats.into_iter().collect::<Vec<_>>();
+    // most frequent first, ties broken by the split itself to stay deterministic
+    ranked.sort_by(|(a, sa), (b, sb)| sb.count.cmp(&sa.count).then_with(|| a.ones().cmp(b.ones())));
+    let mut accepted: Vec<(FixedBitSet, SplitStats)> = vec![];
+    for (split, s) in ranked {
+        let keep = match mode {
+            ConsensusMode::Strict => s.count == ntrees,
+            ConsensusMode::Majority => 2 * s.count > ntrees,
+            ConsensusMode::Greedy => accepted.iter().all(|(a, _)| compatible(a, &split)),
+        };
+        if keep {
+            accepted.push((split, s));
+        }
+    }
+    // the accepted clusters are nested or disjoint; hang each under the smallest one containing it
+    accepted.sort_by_key(|(split, _)| std::cmp::Reverse(split.count_ones(..)));
+    let mut editable = EditableTree {
+        children: vec![vec![]; ntaxa + 1],
+        taxa: (0..ntaxa as i32).chain([-1]).collect(),
+        lengths: pendant_lengths
+            .iter()
+            .map(|&(total, cnt)| {
+                if cnt > 0 {
+                    total / cnt as f64
+                } else {
+                    f64::NAN
+                }
+            })
+            .chain([f64::NAN])
+            .collect(),
+        support: vec![f64::NAN; ntaxa + 1],
+        root: ntaxa,
+    };
+    let mut cluster_nodes: Vec<(usize, &FixedBitSet)> = vec![];
+    for (split, s) in &accepted {
+        let parent = smallest_containing(&cluster_nodes, |c| split.is_subset(c)).unwrap_or(ntaxa);
+        let node = editable.add_node(vec![]);
+        editable.children[parent].push(node);
+        editable.support[node] = 100.0 * s.count as f64 / ntrees as f64;
+        editable.lengths[node] = if s.num_lengths > 0 {
+            s.total_length / s.num_lengths as f64
+        } else {
+            f64::NAN
+        };
+        cluster_nodes.push((node, split));
+    }
+    for taxon in 0..ntaxa {
+        let parent = smallest_containing(&cluster_nodes, |c| c.contains(taxon)).unwrap_or(ntaxa);
+        editable.children[parent].push(taxon);
+    }
+    Ok(editable.into_tree(&mut collection.taxon_set))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newick::parse_trees;
+
+    #[test]
+    fn rooted_trees_support_their_root_split_once() {
+        let mut collection = parse_trees("((A,B),(C,(D,E)));\n((A,B),(C,(D,E)));").unwrap();
+        let ntaxa = collection.ntaxa();
+        let mut expected = tree_splits(&collection.trees[0], ntaxa)
+            .into_iter()
+            .map(|(split, _)| split.ones().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(expected.len(), 2);
+        let consensus = consensus_tree(&mut collection, ConsensusMode::Strict).unwrap();
+        let mut splits = tree_splits(&consensus, ntaxa)
+            .into_iter()
+            .map(|(split, _)| split.ones().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        splits.sort();
+        assert_eq!(splits, expected);
+        for v in consensus.postorder() {
+            if !consensus.is_leaf(v) && !consensus.is_root(v) {
+                assert_eq!(consensus.support[v], 100.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use crate::tree_ops::{is_multifurcation, leaf_nodes, tree_mrca};
+use ahash::AHashSet;
+use anyhow::bail;
+use clap::{ArgEnum, Args};
+use ogcat::ogtree::*;
+use ordered_float::OrderedFloat;
+use std::{cmp::Reverse, fs, path::PathBuf, str::FromStr};
+
+/// the part of the tree currently being split: the subtree under `root` with
+/// all previously cut clades removed
+pub struct Component<'a> {
+    pub tree: &'a Tree,
+    pub root: usize,
+    /// number of taxa in the component
+    pub size: usize,
+    /// number of taxa below each node, restricted to the component
+    pub clade_sizes: &'a [u64],
+    /// total weight of the taxa in the component
+    pub weight: u64,
+    /// weight of the taxa below each node, restricted to the component;
+    /// the same as `clade_sizes` unless balancing by residues
+    pub clade_weights: &'a [u64],
+    cuts: &'a AHashSet<usize>,
+    options: &'a DecompositionOptions,
+    constraints: &'a DecompositionConstraints,
+    supported_only: bool,
+}
+
+impl<'a> Component<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        tree: &'a Tree,
+        root: usize,
+        size: usize,
+        clade_sizes: &'a [u64],
+        weight: u64,
+        clade_weights: &'a [u64],
+        cuts: &'a AHashSet<usize>,
+        options: &'a DecompositionOptions,
+        constraints: &'a DecompositionConstraints,
+    ) -> Self {
+        Self {
+            tree,
+            root,
+            size,
+            clade_sizes,
+            weight,
+            clade_weights,
+            cuts,
+            options,
+            constraints,
+            supported_only: false,
+        }
+    }
+
+    /// the same component, but only offering edges that pass `min_support`
+    pub fn supported_only(&self) -> Self {
+        Self {
+            supported_only: true,
+            ..*self
+        }
+    }
+
+    /// nodes (in postorder) whose parent edge lies inside the component and
+    /// actually splits it, leaving at least `min_size` (and one) taxa on both sides
+    /// without breaking up a keep-together group
+    pub fn candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        let root = self.root;
+        let min_side = self.options.min_size.max(1) as u64;
+        PostorderIterator::from_node_excluding(self.tree, root, self.cuts).filter(move |&i| {
+            i != root
+                && self.clade_size(i) >= min_side
+                && self.remainder(i) >= min_side
+                && self.constraints.allows_cut(i)
+                && (!self.supported_only || self.is_supported(i))
+        })
+    }
+
+    /// candidates whose removal cuts off a clade: internal nodes, plus every child
+    /// of a multifurcation under `PolytomyPolicy::ChildCuts`
+    pub fn clade_candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        self.candidates().filter(move |&i| {
+            !self.tree.is_leaf(i)
+                || (self.options.polytomy == PolytomyPolicy::ChildCuts
+                    && self.hangs_off_multifurcation(i))
+        })
+    }
+
+    fn hangs_off_multifurcation(&self, node: usize) -> bool {
+        matches!(self.tree.ancestors(node).next(), Some(p) if is_multifurcation(self.tree, p))
+    }
+
+    /// whether the edge above `node` meets `min_support`; pendant edges always
+    /// do, while internal edges without a support value never do
+    pub fn is_supported(&self, node: usize) -> bool {
+        match self.options.min_support {
+            Some(threshold) => self.tree.is_leaf(node) || self.tree.support[node] >= threshold,
+            None => true,
+        }
+    }
+
+    /// taxa on the lower side of the edge above `node`
+    pub fn clade_size(&self, node: usize) -> u64 {
+        self.clade_sizes[node]
+    }
+
+    /// taxa left in the component after cutting above `node`
+    pub fn remainder(&self, node: usize) -> u64 {
+        self.size as u64 - self.clade_sizes[node]
+    }
+
+    /// weight of the lower side of the edge above `node`
+    pub fn clade_weight(&self, node: usize) -> u64 {
+        self.clade_weights[node]
+    }
+
+    /// weight left in the component after cutting above `node`
+    pub fn remainder_weight(&self, node: usize) -> u64 {
+        self.weight - self.clade_weights[node]
+    }
+
+    /// weight difference between the two sides of the edge above `node`
+    pub fn imbalance(&self, node: usize) -> u64 {
+        self.remainder_weight(node)
+            .abs_diff(self.clade_weight(node))
+    }
+
+    /// length of the edge above `node`, missing or negative lengths being zero
+    pub fn branch_length(&self, node: usize) -> f64 {
+        let l = self.tree.lengths[node];
+        if l.is_finite() && l > 0.0 {
+            l
+        } else {
+            0.0
+        }
+    }
+}
+
+/// cut-selection logic used by `hierarchical_decomp`
+pub trait DecompositionStrategy: Send + Sync {
+    /// picks the node whose parent edge splits `component`, or `None` to leave it intact
+    fn choose_cut(&self, component: &Component) -> Option<usize>;
+}
+
+/// cut above the internal node that splits the component most evenly
+pub struct BalancedCut;
+
+impl DecompositionStrategy for BalancedCut {
+    fn choose_cut(&self, component: &Component) -> Option<usize> {
+        component
+            .clade_candidates()
+            .min_by_key(|&i| component.imbalance(i))
+    }
+}
+
+/// PASTA/SEPP-style centroid edge: any edge (including pendant edges) minimizing the larger side
+pub struct CentroidEdgeCut;
+
+impl DecompositionStrategy for CentroidEdgeCut {
+    fn choose_cut(&self, component: &Component) -> Option<usize> {
+        component
+            .candidates()
+            .min_by_key(|&i| component.clade_weight(i).max(component.remainder_weight(i)))
+    }
+}
+
+/// cut the longest internal edge, breaking ties by balance
+pub struct LongestBranchCut;
+
+impl DecompositionStrategy for LongestBranchCut {
+    fn choose_cut(&self, component: &Component) -> Option<usize> {
+        component.clade_candidates().max_by_key(|&i| {
+            (
+                OrderedFloat(component.branch_length(i)),
+                Reverse(component.imbalance(i)),
+            )
+        })
+    }
+}
+
+/// cut the internal edge maximizing its length times the size of the smaller side,
+/// so long edges are preferred without peeling off tiny clades
+pub struct LengthWeightedCut;
+
+impl DecompositionStrategy for LengthWeightedCut {
+    fn choose_cut(&self, component: &Component) -> Option<usize> {
+        component.clade_candidates().max_by_key(|&i| {
+            let smaller_side = component.clade_weight(i).min(component.remainder_weight(i));
+            (
+                OrderedFloat(component.branch_length(i) * smaller_side as f64),
+                Reverse(component.imbalance(i)),
+            )
+        })
+    }
+}
+
+/// built-in strategies selectable from the command line
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecompositionMode {
+    /// cut above the internal node that splits the current subtree most evenly
+    Balanced,
+    /// PASTA/SEPP-style centroid edge: any edge (including pendant edges) minimizing the larger side
+    Centroid,
+    /// cut the longest internal edge of the current subtree
+    LongestBranch,
+    /// balance against branch lengths, separating divergent clades first
+    LengthWeighted,
+}
+
+impl DecompositionMode {
+    pub fn strategy(&self) -> Box<dyn DecompositionStrategy> {
+        match self {
+            DecompositionMode::Balanced => Box::new(BalancedCut),
+            DecompositionMode::Centroid => Box::new(CentroidEdgeCut),
+            DecompositionMode::LongestBranch => Box::new(LongestBranchCut),
+            DecompositionMode::LengthWeighted => Box::new(LengthWeightedCut),
+        }
+    }
+}
+
+/// how multifurcating nodes of the guide tree are handled
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolytomyPolicy {
+    /// every child of a multifurcation, leaves included, is a candidate cut
+    ChildCuts,
+    /// randomly resolve multifurcations into binary subtrees before decomposing
+    Resolve,
+}
+
+/// what the two sides of a cut are balanced by
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BalanceWeight {
+    /// every taxon counts the same
+    Taxa,
+    /// taxa count by their number of non-gap characters
+    Residues,
+}
+
+/// a subset size given either as a number of taxa or as a fraction of all taxa
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeSpec {
+    Count(usize),
+    Fraction(f64),
+}
+
+impl SizeSpec {
+    /// the number of taxa this stands for in a tree of `ntaxa` taxa, at least one
+    pub fn resolve(&self, ntaxa: usize) -> usize {
+        match *self {
+            SizeSpec::Count(c) => c,
+            SizeSpec::Fraction(f) => ((f * ntaxa as f64).ceil() as usize).max(1),
+        }
+    }
+}
+
+impl FromStr for SizeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(c) = s.parse::<usize>() {
+            return Ok(SizeSpec::Count(c));
+        }
+        let f = s.parse::<f64>()?;
+        if !(f > 0.0 && f <= 1.0) {
+            bail!("fractional size {} must be in (0, 1]", s);
+        }
+        Ok(SizeSpec::Fraction(f))
+    }
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct DecompositionOptions {
+    /// Stop splitting once every subset has at most this many taxa; values
+    /// like 0.1 are taken as a fraction of all taxa
+    #[clap(
+        short = 's',
+        long,
+        required_unless_present_any = &["num-subsets", "levels"]
+    )]
+    pub max_size: Option<SizeSpec>,
+    /// Instead of bounding subset sizes, keep splitting the largest subset until
+    /// about this many disjoint subsets exist
+    #[clap(long, conflicts_with = "max-size")]
+    pub num_subsets: Option<usize>,
+    /// Build a UPP-style ensemble: emit the disjoint subsets of each of these
+    /// subset size bounds (e.g. 10,25,50,100), all from one decomposition
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        conflicts_with_all = &["max-size", "num-subsets"]
+    )]
+    pub levels: Vec<SizeSpec>,
+    /// Never cut off fewer than this many taxa; smaller pieces stay with their sibling
+    #[clap(long, default_value = "0")]
+    pub min_size: usize,
+    /// Prefer cutting edges with at least this support (e.g. 70 for bootstrap),
+    /// falling back to the best unsupported edge when none qualifies
+    #[clap(long)]
+    pub min_support: Option<f64>,
+    /// What cut strategies balance both sides of a cut by
+    #[clap(long, arg_enum, default_value = "taxa")]
+    pub balance_by: BalanceWeight,
+    /// How multifurcations in the guide tree are handled
+    #[clap(long, arg_enum, default_value = "child-cuts")]
+    pub polytomy: PolytomyPolicy,
+    /// File of clades to cut off before any automatic cut, one per line, each
+    /// given by taxa (usually a pair) whose MRCA roots the clade
+    #[clap(long)]
+    pub forced_cuts: Option<PathBuf>,
+    /// File of taxon groups that must never be split across subsets, one group
+    /// per line; a forced cut splitting one is an error
+    #[clap(long)]
+    pub keep_together: Option<PathBuf>,
+    /// Only emit the leaf-level disjoint subsets instead of every level of the hierarchy
+    #[clap(long)]
+    pub disjoint: bool,
+    /// Seed for randomized choices (e.g. resolving multifurcations); the same inputs,
+    /// options and seed always give the same subsets and numbering
+    #[clap(long, default_value = "0")]
+    pub seed: u64,
+}
+
+impl Default for DecompositionOptions {
+    fn default() -> Self {
+        Self {
+            max_size: Some(SizeSpec::Count(10)),
+            num_subsets: None,
+            levels: vec![],
+            min_size: 0,
+            min_support: None,
+            balance_by: BalanceWeight::Taxa,
+            polytomy: PolytomyPolicy::ChildCuts,
+            forced_cuts: None,
+            keep_together: None,
+            disjoint: false,
+            seed: 0,
+        }
+    }
+}
+
+impl DecompositionOptions {
+    /// the subset size bound for a tree of `ntaxa` taxa, if any; the finest
+    /// level when building an ensemble
+    pub fn max_size_for(&self, ntaxa: usize) -> Option<usize> {
+        if !self.levels.is_empty() {
+            return self.levels.iter().map(|l| l.resolve(ntaxa)).min();
+        }
+        self.max_size.map(|m| m.resolve(ntaxa))
+    }
+
+    /// asks `strategy` for a cut in `component`, restricted to well-supported
+    /// edges first when `min_support` is set
+    pub fn choose_cut(
+        &self,
+        strategy: &dyn DecompositionStrategy,
+        component: &Component,
+    ) -> Option<usize> {
+        if self.min_support.is_some() {
+            if let Some(cut) = strategy.choose_cut(&component.supported_only()) {
+                return Some(cut);
+            }
+        }
+        strategy.choose_cut(component)
+    }
+
+    /// whether to stop with the largest remaining subset having `size` taxa and
+    /// `num_subsets` disjoint subsets existing so far in a tree of `ntaxa` taxa
+    pub fn should_stop(&self, size: usize, num_subsets: usize, ntaxa: usize) -> bool {
+        if let Some(target) = self.num_subsets {
+            num_subsets >= target
+        } else {
+            size <= self.max_size_for(ntaxa).unwrap_or(usize::MAX)
+        }
+    }
+}
+
+/// tree-specific constraints on the decomposition, loaded from the files named
+/// in `DecompositionOptions`
+#[derive(Debug, Clone, Default)]
+pub struct DecompositionConstraints {
+    /// nodes whose parent edges are cut before any automatic cut, in order
+    pub forced_cuts: Vec<usize>,
+    /// nodes whose parent edges would split a keep-together group
+    pub atomic: AHashSet<usize>,
+}
+
+impl DecompositionConstraints {
+    pub fn load(
+        options: &DecompositionOptions,
+        tree: &Tree,
+        taxon_set: &TaxonSet,
+    ) -> anyhow::Result<Self> {
+        let mut constraints = Self::default();
+        let mut forced_lines = vec![];
+        if let Some(path) = &options.forced_cuts {
+            for (lineno, nodes) in read_taxon_groups(path, tree, taxon_set)? {
+                let mrca = tree_mrca(tree, &nodes).expect("groups are never empty");
+                if tree.is_root(mrca) {
+                    bail!("forced cut on line {} spans the whole tree", lineno);
+                }
+                constraints.forced_cuts.push(mrca);
+                forced_lines.push(lineno);
+            }
+        }
+        if let Some(path) = &options.keep_together {
+            for (lineno, nodes) in read_taxon_groups(path, tree, taxon_set)? {
+                // the group is split exactly by the edges strictly below its MRCA
+                // that lead to one of its members
+                let mrca = tree_mrca(tree, &nodes).expect("groups are never empty");
+                let splitting = nodes
+                    .iter()
+                    .flat_map(|&v| {
+                        std::iter::once(v)
+                            .chain(tree.ancestors(v))
+                            .take_while(move |&a| a != mrca)
+                    })
+                    .collect::<AHashSet<_>>();
+                // forced cuts are applied as given, so they may not split a group
+                if let Some(i) = constraints
+                    .forced_cuts
+                    .iter()
+                    .position(|f| splitting.contains(f))
+                {
+                    bail!(
+                        "the forced cut on line {} of {} splits the keep-together group on line {} of {}",
+                        forced_lines[i],
+                        options.forced_cuts.as_ref().unwrap().display(),
+                        lineno,
+                        path.display()
+                    );
+                }
+                constraints.atomic.extend(splitting);
+            }
+        }
+        Ok(constraints)
+    }
+
+    /// whether cutting above `node` keeps every keep-together group intact
+    pub fn allows_cut(&self, node: usize) -> bool {
+        !self.atomic.contains(&node)
+    }
+}
+
+/// reads a file of taxon groups, one group per line with taxa separated by
+/// commas or whitespace, into (line number, leaf nodes of `tree`) pairs;
+/// blank lines and lines starting with `#` are skipped, and a line naming no
+/// taxa is an error
+fn read_taxon_groups(
+    path: &PathBuf,
+    tree: &Tree,
+    taxon_set: &TaxonSet,
+) -> anyhow::Result<Vec<(usize, Vec<usize>)>> {
+    let leaves = leaf_nodes(tree, taxon_set.len());
+    let mut groups = vec![];
+    for (lineno, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut nodes = vec![];
+        for name in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            if name.is_empty() {
+                continue;
+            }
+            match taxon_set.to_id.get(name).and_then(|&id| leaves[id]) {
+                Some(v) => nodes.push(v),
+                None => bail!(
+                    "line {} of {} names taxon {} not in the tree",
+                    lineno + 1,
+                    path.display(),
+                    name
+                ),
+            }
+        }
+        if nodes.is_empty() {
+            bail!("line {} of {} names no taxa", lineno + 1, path.display());
+        }
+        groups.push((lineno + 1, nodes));
+    }
+    Ok(groups)
+}
@@ -0,0 +1,74 @@
+use rayon::prelude::*;
+
+/// k-mer length used for guide-tree estimation unless overridden
+pub const DEFAULT_KMER_SIZE: usize = 6;
+
+/// FNV-1a, chosen over the std hashers for being stable across platforms and releases
+fn hash_kmer(kmer: &[u8]) -> u64 {
+    kmer.iter().fold(0xcbf29ce484222325, |h, &c| {
+        (h ^ c as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// the distinct k-mers of a sequence, ignoring gaps and case, as sorted hashes
+pub fn kmer_profile(seq: &[u8], k: usize) -> Vec<u64> {
+    let residues = seq
+        .iter()
+        .filter(|&&c| c != b'-' && c != b'.')
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<Vec<_>>();
+    let mut kmers = residues.windows(k).map(hash_kmer).collect::<Vec<_>>();
+    kmers.sort_unstable();
+    kmers.dedup();
+    kmers
+}
+
+fn shared_kmers(a: &[u64], b: &[u64]) -> usize {
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            i += 1;
+        } else if a[i] > b[j] {
+            j += 1;
+        } else {
+            shared += 1;
+            i += 1;
+            j += 1;
+        }
+    }
+    shared
+}
+
+/// Jaccard distance between the k-mer sets of every pair of sequences;
+/// sequences too short to have any k-mer are at distance 1 from everything
+pub fn kmer_distances<'a, I>(seqs: I, k: usize) -> Vec<Vec<f64>>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let profiles = seqs
+        .into_iter()
+        .map(|s| kmer_profile(s, k))
+        .collect::<Vec<_>>();
+    profiles
+        .par_iter()
+        .enumerate()
+        .map(|(i, a)| {
+            profiles
+                .iter()
+                .enumerate()
+                .map(|(j, b)| {
+                    if i == j {
+                        return 0.0;
+                    }
+                    let shared = shared_kmers(a, b);
+                    let union = a.len() + b.len() - shared;
+                    if union == 0 {
+                        1.0
+                    } else {
+                        1.0 - shared as f64 / union as f64
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,129 @@
+use crate::structures::{metadata_path, CrucibleCtxt};
+use std::path::Path;
+use tracing::info;
+
+/// the character counts of the HMMs of a melt as a long table, a row for every
+/// column of every HMM
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CountsTable {
+    pub hmm: Vec<i32>,
+    pub column: Vec<i32>,
+    pub count: Vec<i64>,
+    pub num_sequences: Vec<i64>,
+}
+
+impl CountsTable {
+    pub fn from_ctxt(ctxt: &CrucibleCtxt) -> Self {
+        let mut table = Self::default();
+        for (i, hmm) in ctxt.metadata.iter().enumerate() {
+            for (j, c) in ctxt.column_counts(i) {
+                table.hmm.push(i as i32);
+                table.column.push(j as i32);
+                table.count.push(c as i64);
+                table.num_sequences.push(hmm.num_seqs() as i64);
+            }
+        }
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.hmm.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hmm.is_empty()
+    }
+}
+
+/// what every column of the table has, kept in the metadata of the Parquet file
+#[cfg(feature = "parquet")]
+const COLUMN_DESCRIPTIONS: &[(&str, &str)] = &[
+    (
+        "hmm",
+        "the number of the HMM, as in the metadata of the melt",
+    ),
+    ("column", "the column of the whole alignment, from 0"),
+    (
+        "count",
+        "the number of sequences of the HMM with a counted character there",
+    ),
+    ("num_sequences", "the number of sequences of the HMM"),
+];
+
+#[cfg(feature = "parquet")]
+fn write_parquet(table: &CountsTable, output: &Path) -> anyhow::Result<()> {
+    use parquet::{
+        data_type::{Int32Type, Int64Type},
+        file::{metadata::KeyValue, properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use std::{fs::File, sync::Arc};
+
+    let schema = parse_message_type(
+        "message nchars {
+            REQUIRED INT32 hmm;
+            REQUIRED INT32 column;
+            REQUIRED INT64 count;
+            REQUIRED INT64 num_sequences;
+        }",
+    )?;
+    let mut key_values = vec![KeyValue::new(
+        "crucible.version".to_string(),
+        env!("CARGO_PKG_VERSION").to_string(),
+    )];
+    for (name, description) in COLUMN_DESCRIPTIONS {
+        key_values.push(KeyValue::new(
+            format!("crucible.column.{}", name),
+            description.to_string(),
+        ));
+    }
+    let properties = WriterProperties::builder()
+        .set_key_value_metadata(Some(key_values))
+        .build();
+    let mut writer = SerializedFileWriter::new(
+        File::create(output)?,
+        Arc::new(schema),
+        Arc::new(properties),
+    )?;
+    let mut row_group = writer.next_row_group()?;
+    let mut i = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match i {
+            0 => column
+                .typed::<Int32Type>()
+                .write_batch(&table.hmm, None, None)?,
+            1 => column
+                .typed::<Int32Type>()
+                .write_batch(&table.column, None, None)?,
+            2 => column
+                .typed::<Int64Type>()
+                .write_batch(&table.count, None, None)?,
+            _ => column
+                .typed::<Int64Type>()
+                .write_batch(&table.num_sequences, None, None)?,
+        };
+        column.close()?;
+        i += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(_table: &CountsTable, output: &Path) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "cannot write {}: crucible was built without the `parquet` feature",
+        output.display()
+    )
+}
+
+/// writes the character counts of the HMMs of the melt in `outdir` to `output` as
+/// Parquet, one row per column of every HMM (see `CountsTable`)
+pub fn oneshot_export_counts(outdir: &Path, output: &Path) -> anyhow::Result<()> {
+    let ctxt = CrucibleCtxt::open(&metadata_path(outdir))?;
+    let table = CountsTable::from_ctxt(&ctxt);
+    write_parquet(&table, output)?;
+    info!(num_rows = table.len(), "exported the character counts");
+    Ok(())
+}
@@ -1,11 +1,16 @@
+use crate::alphabet::Alphabet;
 use ahash::AHashMap;
 use anyhow::bail;
 use lazy_static::lazy_static;
 use regex::Regex;
 use seq_io::fasta::OwnedRecord;
 use seq_io::BaseRecord;
+use std::io::{BufWriter, Write};
 use std::process::Stdio;
-use std::{path::PathBuf, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
 use tracing::debug;
 
 pub fn hmmalign<'a, R>(hmm_path: &PathBuf, seqs: R) -> anyhow::Result<Vec<u8>>
@@ -36,29 +41,38 @@ where
     Ok(output.stdout)
 }
 
-pub fn hmmbuild<'a, R>(seqs: R, name: &str, outpath: &PathBuf) -> anyhow::Result<()>
+/// builds an HMM of the `alphabet` from aligned `seqs`; given a `reference` annotation
+/// or `weights` (one per sequence) the sequences are passed on as Stockholm so that
+/// the HMM carries the annotation and uses the weights as given. The match states
+/// are the columns the `reference` marks if `hand` (`hmmbuild --hand`), else every
+/// column with a residue
+pub fn hmmbuild<'a, R>(
+    seqs: R,
+    reference: Option<&[u8]>,
+    hand: bool,
+    weights: Option<&[f64]>,
+    alphabet: Alphabet,
+    name: &str,
+    outpath: &PathBuf,
+) -> anyhow::Result<()>
 where
     R: Iterator<Item = &'a OwnedRecord>,
 {
-    let mut child = Command::new("hmmbuild")
-        .arg("--cpu")
-        .arg("0")
-        .arg("--informat")
-        .arg("afa")
-        .arg("--ere")
-        .arg("0.59")
-        .arg("--symfrac")
-        .arg("0.0")
-        .arg("-n")
-        .arg(name)
-        .arg(outpath)
+    let stockholm = reference.is_some() || weights.is_some();
+    let informat = if stockholm { "stockholm" } else { "afa" };
+    let hand = hand && reference.is_some();
+    let mut child = hmmbuild_command(informat, weights.is_some(), hand, alphabet, name, outpath)
         .arg("-")
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()?;
     if let Some(mut stdin) = child.stdin.take() {
-        for s in seqs {
-            s.write(&mut stdin)?;
+        if stockholm {
+            write_stockholm(seqs, reference, weights, &mut stdin)?;
+        } else {
+            for s in seqs {
+                s.write(&mut stdin)?;
+            }
         }
     } else {
         bail!("Failed to get stdin handle");
@@ -70,6 +84,152 @@ where
     Ok(())
 }
 
+/// builds an HMM as `hmmbuild` does (with no weights), of `num_rows` aligned sequences
+/// passed on one at a time: `row(i, buf)` puts the sequence of row `i` into `buf`,
+/// returning its header, so that the rows need never all be in memory at once
+pub fn hmmbuild_rows<'a, F>(
+    num_rows: usize,
+    mut row: F,
+    reference: Option<&[u8]>,
+    hand: bool,
+    alphabet: Alphabet,
+    name: &str,
+    outpath: &PathBuf,
+) -> anyhow::Result<()>
+where
+    F: FnMut(usize, &mut Vec<u8>) -> &'a [u8],
+{
+    let informat = if reference.is_some() {
+        "stockholm"
+    } else {
+        "afa"
+    };
+    let hand = hand && reference.is_some();
+    let mut child = hmmbuild_command(informat, false, hand, alphabet, name, outpath)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = match child.stdin.take() {
+        Some(stdin) => BufWriter::new(stdin),
+        None => bail!("Failed to get stdin handle"),
+    };
+    if reference.is_some() {
+        stdin.write_all(STOCKHOLM_HEADER)?;
+    }
+    let mut buf = vec![];
+    for i in 0..num_rows {
+        let head = row(i, &mut buf);
+        match reference {
+            Some(_) => write_stockholm_row(&mut stdin, head, &buf)?,
+            None => seq_io::fasta::write(&mut stdin, head, &buf)?,
+        }
+    }
+    if let Some(reference) = reference {
+        write_stockholm_end(&mut stdin, Some(reference))?;
+    }
+    // closing its input lets hmmbuild finish
+    drop(stdin.into_inner().map_err(|e| e.into_error())?);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("hmmbuild failed: {:?}", output);
+    }
+    Ok(())
+}
+
+/// builds an HMM of the `alphabet` from the aligned FASTA file at `alignment`, as
+/// `hmmbuild` does from sequences in memory
+pub fn hmmbuild_file(
+    alignment: &Path,
+    alphabet: Alphabet,
+    name: &str,
+    outpath: &PathBuf,
+) -> anyhow::Result<()> {
+    let output = hmmbuild_command("afa", false, false, alphabet, name, outpath)
+        .arg(alignment)
+        .output()?;
+    if !output.status.success() {
+        bail!("hmmbuild failed: {:?}", output);
+    }
+    Ok(())
+}
+
+/// hmmbuild writing the HMM `name` to `outpath`, all but its input given
+fn hmmbuild_command(
+    informat: &str,
+    weighted: bool,
+    hand: bool,
+    alphabet: Alphabet,
+    name: &str,
+    outpath: &PathBuf,
+) -> Command {
+    let mut command = Command::new("hmmbuild");
+    command
+        .arg("--cpu")
+        .arg("0")
+        .arg("--informat")
+        .arg(informat)
+        .args(weighted.then_some("--wgiven"))
+        .args(hand.then_some("--hand"))
+        .arg("--ere")
+        .arg("0.59")
+        .arg("--symfrac")
+        .arg("0.0")
+        .args(alphabet.hmmer_flag())
+        .arg("-n")
+        .arg(name)
+        .arg(outpath);
+    command
+}
+
+/// writes `seqs` as a single-block Stockholm alignment, annotated with `reference`
+/// and with the `weights` of the sequences (`#=GS <name> WT`) if given
+pub fn write_stockholm<'a, R, W>(
+    seqs: R,
+    reference: Option<&[u8]>,
+    weights: Option<&[f64]>,
+    w: &mut W,
+) -> std::io::Result<()>
+where
+    R: Iterator<Item = &'a OwnedRecord>,
+    W: Write,
+{
+    let seqs = seqs.collect::<Vec<_>>();
+    w.write_all(STOCKHOLM_HEADER)?;
+    if let Some(weights) = weights {
+        for (s, weight) in seqs.iter().zip(weights) {
+            w.write_all(b"#=GS ")?;
+            w.write_all(&s.head)?;
+            writeln!(w, " WT {}", weight)?;
+        }
+    }
+    for s in seqs {
+        write_stockholm_row(w, &s.head, &s.seq)?;
+    }
+    write_stockholm_end(w, reference)
+}
+
+/// the first line of a Stockholm alignment
+pub const STOCKHOLM_HEADER: &[u8] = b"# STOCKHOLM 1.0\n";
+
+/// writes the row of a Stockholm alignment with the header `head` and `seq`
+pub fn write_stockholm_row<W: Write>(w: &mut W, head: &[u8], seq: &[u8]) -> std::io::Result<()> {
+    w.write_all(head)?;
+    w.write_all(b" ")?;
+    w.write_all(seq)?;
+    w.write_all(b"\n")
+}
+
+/// ends a Stockholm alignment after its rows, annotated with `reference` if given
+pub fn write_stockholm_end<W: Write>(w: &mut W, reference: Option<&[u8]>) -> std::io::Result<()> {
+    if let Some(reference) = reference {
+        w.write_all(b"#=GC RF ")?;
+        w.write_all(reference)?;
+        w.write_all(b"\n")?;
+    }
+    w.write_all(b"//\n")
+}
+
 pub fn hmmsearch<'a, R>(
     hmm_path: &PathBuf,
     seqs: R,
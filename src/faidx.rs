@@ -0,0 +1,371 @@
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::input::Compression;
+use flate2::read::MultiGzDecoder;
+
+/// one line of a samtools-style `.fai` index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaiRecord {
+    pub name: String,
+    /// number of residues
+    pub length: u64,
+    /// byte offset of the first residue
+    pub offset: u64,
+    /// residues per full line
+    pub line_bases: u64,
+    /// bytes per full line, line terminator included
+    pub line_width: u64,
+}
+
+impl FaiRecord {
+    /// bytes the sequence spans in the file, line terminators between lines included
+    fn span(&self) -> u64 {
+        if self.line_bases == 0 {
+            return 0;
+        }
+        let full_lines = self.length / self.line_bases;
+        let rest = self.length % self.line_bases;
+        if rest == 0 && full_lines > 0 {
+            (full_lines - 1) * self.line_width + self.line_bases
+        } else {
+            full_lines * self.line_width + rest
+        }
+    }
+}
+
+/// a FASTA index, the records in file order
+#[derive(Debug, Clone, Default)]
+pub struct FastaIndex {
+    pub records: Vec<FaiRecord>,
+    by_name: AHashMap<String, usize>,
+}
+
+/// the conventional index path of `fasta`, with `.fai` appended
+pub fn fai_path(fasta: &Path) -> PathBuf {
+    let mut path = fasta.as_os_str().to_owned();
+    path.push(".fai");
+    PathBuf::from(path)
+}
+
+impl FastaIndex {
+    fn from_records(records: Vec<FaiRecord>) -> anyhow::Result<Self> {
+        let mut by_name = AHashMap::with_capacity(records.len());
+        for (i, r) in records.iter().enumerate() {
+            if by_name.insert(r.name.clone(), i).is_some() {
+                bail!("{} is indexed twice", r.name);
+            }
+        }
+        Ok(Self { records, by_name })
+    }
+
+    /// indexes the FASTA at `path`, uncompressed or BGZF-compressed (offsets then
+    /// being into the decompressed data), naming sequences by their header up to
+    /// the first blank as samtools does; every line of a sequence but the last
+    /// must be of the same length
+    pub fn build<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(
+            File::open(path).with_context(|| format!("cannot open {}", path.display()))?,
+        );
+        let reader: Box<dyn BufRead> = match Compression::detect(file.fill_buf()?) {
+            Compression::None => Box::new(file),
+            Compression::Bgzf => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+            _ => bail!(
+                "cannot index {}: only uncompressed and bgzip-compressed files can be",
+                path.display()
+            ),
+        };
+        Self::build_from(reader, path)
+    }
+
+    fn build_from<R: BufRead>(mut reader: R, path: &Path) -> anyhow::Result<Self> {
+        let mut records: Vec<FaiRecord> = vec![];
+        // whether the current sequence already had a line shorter than its first
+        let mut ended = false;
+        let mut offset = 0u64;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let width = reader.read_until(b'\n', &mut line)? as u64;
+            if width == 0 {
+                break;
+            }
+            let bases = line
+                .iter()
+                .rev()
+                .skip_while(|&&c| c == b'\n' || c == b'\r')
+                .count() as u64;
+            if line[0] == b'>' {
+                let header = String::from_utf8_lossy(&line[1..bases as usize]);
+                let name = header.split_whitespace().next().unwrap_or_default();
+                records.push(FaiRecord {
+                    name: name.to_string(),
+                    length: 0,
+                    offset: offset + width,
+                    line_bases: 0,
+                    line_width: 0,
+                });
+                ended = false;
+            } else if let Some(r) = records.last_mut() {
+                if bases > 0 {
+                    if ended || (r.line_bases > 0 && bases > r.line_bases) {
+                        bail!("{} has lines of uneven length", r.name);
+                    }
+                    if r.line_bases == 0 {
+                        r.line_bases = bases;
+                        r.line_width = width;
+                    } else if bases < r.line_bases {
+                        ended = true;
+                    }
+                    r.length += bases;
+                } else {
+                    ended = true;
+                }
+            } else if bases > 0 {
+                bail!("{} does not start with a FASTA header", path.display());
+            }
+            offset += width;
+        }
+        Self::from_records(records)
+    }
+
+    /// reads a `.fai` file
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let reader = BufReader::new(
+            File::open(path).with_context(|| format!("cannot open {}", path.display()))?,
+        );
+        let mut records = vec![];
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() < 5 {
+                bail!(
+                    "line {} of {} has fewer than 5 fields",
+                    lineno + 1,
+                    path.display()
+                );
+            }
+            let number = |i: usize| -> anyhow::Result<u64> {
+                fields[i].parse().with_context(|| {
+                    format!(
+                        "field {} on line {} of {}",
+                        i + 1,
+                        lineno + 1,
+                        path.display()
+                    )
+                })
+            };
+            records.push(FaiRecord {
+                name: fields[0].to_string(),
+                length: number(1)?,
+                offset: number(2)?,
+                line_bases: number(3)?,
+                line_width: number(4)?,
+            });
+        }
+        Self::from_records(records)
+    }
+
+    /// writes the index in the `.fai` format
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for r in &self.records {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                r.name, r.length, r.offset, r.line_bases, r.line_width
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FaiRecord> {
+        self.by_name.get(name).map(|&i| &self.records[i])
+    }
+}
+
+/// the `.gzi` index of a BGZF file: where each block starts, in the file and
+/// in the decompressed data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GziIndex {
+    /// (compressed, uncompressed) offsets of every block, the first one included
+    pub blocks: Vec<(u64, u64)>,
+}
+
+/// the conventional block index path of `bgzf`, with `.gzi` appended
+pub fn gzi_path(bgzf: &Path) -> PathBuf {
+    let mut path = bgzf.as_os_str().to_owned();
+    path.push(".gzi");
+    PathBuf::from(path)
+}
+
+impl GziIndex {
+    /// locates the blocks of the BGZF file at `path` from their headers and
+    /// trailers, without decompressing them
+    pub fn build<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(File::open(path)?);
+        let mut blocks = vec![];
+        let (mut coffset, mut uoffset) = (0u64, 0u64);
+        let mut header = [0u8; 12];
+        loop {
+            match file.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            if header[..2] != [0x1f, 0x8b] || header[3] & 4 == 0 {
+                bail!("{} is not BGZF at offset {}", path.display(), coffset);
+            }
+            let mut extra = vec![0u8; u16::from_le_bytes([header[10], header[11]]) as usize];
+            file.read_exact(&mut extra)?;
+            // the BC subfield holds the block size minus one
+            let mut block_size = None;
+            let mut fields = &extra[..];
+            while fields.len() >= 4 {
+                let len = u16::from_le_bytes([fields[2], fields[3]]) as usize;
+                if &fields[..2] == b"BC" && len == 2 && fields.len() >= 6 {
+                    block_size = Some(u16::from_le_bytes([fields[4], fields[5]]) as u64 + 1);
+                }
+                fields = &fields[(4 + len).min(fields.len())..];
+            }
+            let block_size = match block_size {
+                Some(b) => b,
+                None => bail!(
+                    "{} has a gzip member without a BGZF block size",
+                    path.display()
+                ),
+            };
+            // the rest of the block up to its trailer, ending in the decompressed size
+            let consumed = 12 + extra.len() as u64;
+            let mut rest = vec![0u8; (block_size - consumed) as usize];
+            file.read_exact(&mut rest)?;
+            let isize = u32::from_le_bytes(rest[rest.len() - 4..].try_into()?) as u64;
+            blocks.push((coffset, uoffset));
+            coffset += block_size;
+            uoffset += isize;
+        }
+        Ok(Self { blocks })
+    }
+
+    /// reads a `.gzi` file, which leaves out the first block
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let word = |i: usize| -> anyhow::Result<u64> {
+            match bytes.get(i * 8..i * 8 + 8) {
+                Some(w) => Ok(u64::from_le_bytes(w.try_into()?)),
+                None => bail!("truncated .gzi index"),
+            }
+        };
+        let n = word(0)? as usize;
+        let mut blocks = vec![(0, 0)];
+        for i in 0..n {
+            blocks.push((word(1 + 2 * i)?, word(2 + 2 * i)?));
+        }
+        Ok(Self { blocks })
+    }
+
+    /// writes the index in the `.gzi` format of htslib
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let rest = self.blocks.get(1..).unwrap_or_default();
+        writer.write_all(&(rest.len() as u64).to_le_bytes())?;
+        for &(c, u) in rest {
+            writer.write_all(&c.to_le_bytes())?;
+            writer.write_all(&u.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// the block holding the decompressed byte at `uoffset`, as (compressed, uncompressed) offsets
+    pub fn block_of(&self, uoffset: u64) -> (u64, u64) {
+        let i = self.blocks.partition_point(|&(_, u)| u <= uoffset);
+        self.blocks
+            .get(i.wrapping_sub(1))
+            .copied()
+            .unwrap_or((0, 0))
+    }
+}
+
+/// random access to the sequences of an indexed FASTA file, uncompressed or BGZF
+pub struct IndexedFasta {
+    pub index: FastaIndex,
+    /// the block index of a BGZF file, `None` for uncompressed files
+    pub blocks: Option<GziIndex>,
+    file: File,
+}
+
+impl IndexedFasta {
+    /// opens `fasta` with its indices at `fasta.fai` (and `fasta.gzi` if BGZF),
+    /// building in memory those that are missing
+    pub fn open<P: AsRef<Path>>(fasta: P) -> anyhow::Result<Self> {
+        let fasta = fasta.as_ref();
+        let blocks = match Compression::of_file(fasta)? {
+            Compression::Bgzf if gzi_path(fasta).exists() => {
+                Some(GziIndex::from_path(gzi_path(fasta))?)
+            }
+            Compression::Bgzf => Some(GziIndex::build(fasta)?),
+            _ => None,
+        };
+        let fai = fai_path(fasta);
+        let index = if fai.exists() {
+            FastaIndex::from_path(&fai)?
+        } else {
+            FastaIndex::build(fasta)?
+        };
+        Ok(Self {
+            index,
+            blocks,
+            file: File::open(fasta)?,
+        })
+    }
+
+    /// the sequence named `name` without line terminators, read by seeking to it
+    /// and, in BGZF files, decompressing only from the block it starts in
+    pub fn fetch(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
+        match self.index.by_name.get(name) {
+            Some(&i) => self.fetch_nth(i),
+            None => bail!("{} is not in the index", name),
+        }
+    }
+
+    /// the sequence of the `i`th record of the file, as `fetch` reads it
+    pub fn fetch_nth(&mut self, i: usize) -> anyhow::Result<Vec<u8>> {
+        let record = self.index.records[i].clone();
+        let name = &record.name;
+        let mut raw = vec![0u8; record.span() as usize];
+        match &self.blocks {
+            Some(blocks) => {
+                let (coffset, uoffset) = blocks.block_of(record.offset);
+                self.file.seek(SeekFrom::Start(coffset))?;
+                let mut decoder = MultiGzDecoder::new(BufReader::new(&mut self.file));
+                std::io::copy(
+                    &mut (&mut decoder).take(record.offset - uoffset),
+                    &mut std::io::sink(),
+                )?;
+                decoder.read_exact(&mut raw)?;
+            }
+            None => {
+                self.file.seek(SeekFrom::Start(record.offset))?;
+                self.file.read_exact(&mut raw)?;
+            }
+        }
+        raw.retain(|&c| c != b'\n' && c != b'\r');
+        if raw.len() as u64 != record.length {
+            bail!("the index does not match the file at {}", name);
+        }
+        Ok(raw)
+    }
+}
@@ -0,0 +1,907 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::newick::{clean_newick, newick_label};
+use ahash::{AHashMap, AHashSet};
+use anyhow::{anyhow, bail, Context};
+use regex::Regex;
+use seq_io::fasta::{OwnedRecord, Reader};
+
+/// the text of a sequence header or name: the bytes as they are if they are UTF-8,
+/// and read as Latin-1 otherwise, which gives every byte a character of its own, so
+/// that names compare (and `encode_text` gives them back) byte for byte
+pub fn decode_text(bytes: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(bytes.iter().map(|&b| b as char).collect()),
+    }
+}
+
+/// the bytes of `text`, as Latin-1 if `latin1` (for text `decode_text` read as such)
+/// and as UTF-8 otherwise
+pub fn encode_text(text: &str, latin1: bool) -> Vec<u8> {
+    if latin1 && text.chars().all(|c| (c as u32) < 256) {
+        text.chars().map(|c| c as u8).collect()
+    } else {
+        text.as_bytes().to_vec()
+    }
+}
+
+/// whether `bytes` would be read as Latin-1 by `decode_text`
+pub fn is_latin1(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_err()
+}
+
+/// reads all of `reader` as text with `decode_text`, telling whether it was Latin-1
+pub fn read_text<R: Read>(mut reader: R) -> io::Result<(String, bool)> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let latin1 = is_latin1(&bytes);
+    Ok((decode_text(&bytes).into_owned(), latin1))
+}
+
+/// turns the names of `records`, parsed from text read as Latin-1, back into its bytes
+fn restore_latin1(records: &mut [OwnedRecord], latin1: bool) {
+    if latin1 {
+        for r in records {
+            r.head = encode_text(&String::from_utf8_lossy(&r.head), true);
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// the compression of an input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    /// blocked gzip as written by bgzip, which can be seeked into with an index
+    Bgzf,
+    Zstd,
+}
+
+impl Compression {
+    /// recognizes compressed files by their magic bytes rather than their extension,
+    /// so that misnamed files are still read correctly
+    pub fn detect(head: &[u8]) -> Self {
+        // BGZF blocks are gzip members with a `BC` extra subfield first
+        if head.starts_with(&GZIP_MAGIC)
+            && head.len() >= 14
+            && head[3] & 4 != 0
+            && &head[12..14] == b"BC"
+        {
+            Compression::Bgzf
+        } else if head.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// the compression of the file at `path`, from its first bytes
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        let mut head = Vec::with_capacity(14);
+        File::open(path)?.take(14).read_to_end(&mut head)?;
+        Ok(Self::detect(&head))
+    }
+}
+
+/// whether `path` is the URL of a remote object rather than a local file
+pub fn is_remote(path: &Path) -> bool {
+    match path.to_str().and_then(|p| p.split_once("://")) {
+        Some((scheme, _)) => {
+            !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+#[cfg(feature = "remote")]
+fn open_remote(path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    let url = path.to_str().unwrap_or_default();
+    Ok(Box::new(std::io::Cursor::new(crate::remote::fetch(url)?)))
+}
+
+#[cfg(not(feature = "remote"))]
+fn open_remote(path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    bail!(
+        "cannot read {}: crucible was built without the `remote` feature",
+        path.display()
+    )
+}
+
+/// opens `path` for reading, `-` meaning standard input and URLs remote objects
+/// (with the `remote` feature), transparently decompressing gzip and zstd files
+pub fn open_input<P: AsRef<Path>>(path: P) -> anyhow::Result<Box<dyn Read + Send>> {
+    let path = path.as_ref();
+    let mut reader: BufReader<Box<dyn Read + Send>> = if path == Path::new("-") {
+        BufReader::new(Box::new(std::io::stdin()))
+    } else if is_remote(path) {
+        BufReader::new(open_remote(path)?)
+    } else {
+        let file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+        BufReader::new(Box::new(file))
+    };
+    let compression = Compression::detect(reader.fill_buf()?);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        // multi-member, so that concatenated and bgzipped files are read whole
+        Compression::Gzip | Compression::Bgzf => {
+            Box::new(flate2::read::MultiGzDecoder::new(reader))
+        }
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// how the taxon name is taken from a sequence header
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum HeaderPolicy {
+    /// the whole header line
+    #[default]
+    Full,
+    /// the header up to the first blank
+    FirstToken,
+    /// field `field` (counting from 0) of the header split at `delimiter`
+    Delimiter { delimiter: String, field: usize },
+    /// the first capture group of a regex, or the whole match if it has none
+    Regex(String),
+}
+
+impl FromStr for HeaderPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(spec) = s.strip_prefix("delimiter:") {
+            // a trailing `:<number>` picks the field, otherwise the first one
+            let (delimiter, field) = match spec.rsplit_once(':') {
+                Some((d, f)) if !d.is_empty() && f.parse::<usize>().is_ok() => (d, f.parse()?),
+                _ => (spec, 0),
+            };
+            if delimiter.is_empty() {
+                bail!("empty header delimiter");
+            }
+            return Ok(HeaderPolicy::Delimiter {
+                delimiter: delimiter.to_string(),
+                field,
+            });
+        }
+        if let Some(pattern) = s.strip_prefix("regex:") {
+            Regex::new(pattern)?;
+            return Ok(HeaderPolicy::Regex(pattern.to_string()));
+        }
+        match s {
+            "full" => Ok(HeaderPolicy::Full),
+            "first-token" => Ok(HeaderPolicy::FirstToken),
+            _ => bail!(
+                "unknown header policy {}, expected full, first-token, delimiter:<DELIM>[:<FIELD>] or regex:<PATTERN>",
+                s
+            ),
+        }
+    }
+}
+
+impl HeaderPolicy {
+    /// replaces the header of every record by the taxon name taken from it,
+    /// returning how many headers got shorter
+    pub fn apply(&self, records: &mut [OwnedRecord]) -> anyhow::Result<usize> {
+        let regex = match self {
+            HeaderPolicy::Full => return Ok(0),
+            HeaderPolicy::Regex(pattern) => Some(Regex::new(pattern)?),
+            _ => None,
+        };
+        let mut truncated = 0;
+        for r in records.iter_mut() {
+            let latin1 = is_latin1(&r.head);
+            let header = decode_text(&r.head);
+            let name = match self {
+                HeaderPolicy::Full => Some(header.as_ref()),
+                HeaderPolicy::FirstToken => header.split_whitespace().next(),
+                HeaderPolicy::Delimiter { delimiter, field } => {
+                    header.split(delimiter.as_str()).nth(*field)
+                }
+                HeaderPolicy::Regex(_) => regex
+                    .as_ref()
+                    .unwrap()
+                    .captures(&header)
+                    .and_then(|c| c.get(1).or_else(|| c.get(0)))
+                    .map(|m| m.as_str()),
+            };
+            let name = match name.map(str::trim) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => bail!("no taxon name in the header {:?} under {:?}", header, self),
+            };
+            if name.len() < header.len() {
+                truncated += 1;
+                r.head = encode_text(&name, latin1);
+            }
+        }
+        Ok(truncated)
+    }
+}
+
+/// the on-disk formats an input alignment can be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentFormat {
+    Fasta,
+    /// relaxed Phylip, sequential or interleaved
+    Phylip,
+    /// the first alignment of a Stockholm file
+    Stockholm,
+    /// the matrix of a Nexus DATA or CHARACTERS block, and any TREES blocks
+    Nexus,
+}
+
+impl AlignmentFormat {
+    /// guesses the format from the first non-whitespace bytes of a file:
+    /// FASTA starts with a header line, Phylip with the matrix dimensions
+    /// and Stockholm and Nexus with their format lines
+    pub fn detect(head: &[u8]) -> anyhow::Result<Self> {
+        if head.starts_with(b"# STOCKHOLM") {
+            return Ok(AlignmentFormat::Stockholm);
+        }
+        if head.len() >= 6 && head[..6].eq_ignore_ascii_case(b"#NEXUS") {
+            return Ok(AlignmentFormat::Nexus);
+        }
+        match head.first() {
+            Some(b'>') => Ok(AlignmentFormat::Fasta),
+            Some(c) if c.is_ascii_digit() => Ok(AlignmentFormat::Phylip),
+            Some(_) => bail!("unrecognized alignment format"),
+            None => bail!("the alignment is empty"),
+        }
+    }
+}
+
+fn skip_whitespace<R: BufRead>(reader: &mut R) -> std::io::Result<()> {
+    loop {
+        let buf = reader.fill_buf()?;
+        let (n, len) = (
+            buf.iter().take_while(|c| c.is_ascii_whitespace()).count(),
+            buf.len(),
+        );
+        reader.consume(n);
+        if len == 0 || n < len {
+            return Ok(());
+        }
+    }
+}
+
+/// an input alignment along with the per-column annotation worth keeping
+#[derive(Debug, Clone, Default)]
+pub struct Alignment {
+    pub records: Vec<OwnedRecord>,
+    /// the reference annotation (Stockholm `#=GC RF`), one character per column
+    pub reference: Option<Vec<u8>>,
+    /// trees stored along with the sequences (Nexus TREES blocks) as Newick strings
+    pub trees: Vec<String>,
+}
+
+impl From<Vec<OwnedRecord>> for Alignment {
+    fn from(records: Vec<OwnedRecord>) -> Self {
+        Alignment {
+            records,
+            ..Default::default()
+        }
+    }
+}
+
+/// reads the alignment at `path`, auto-detecting the format and compression
+pub fn read_alignment<P: AsRef<Path>>(path: P) -> anyhow::Result<Alignment> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(open_input(path)?);
+    skip_whitespace(&mut reader)?;
+    let format = AlignmentFormat::detect(reader.fill_buf()?)
+        .with_context(|| format!("cannot read {}", path.display()))?;
+    match format {
+        AlignmentFormat::Fasta => Ok(Reader::new(reader)
+            .records()
+            .collect::<Result<Vec<_>, _>>()?
+            .into()),
+        AlignmentFormat::Phylip => Ok(read_phylip(reader)
+            .with_context(|| format!("malformed Phylip alignment {}", path.display()))?
+            .into()),
+        AlignmentFormat::Stockholm => read_stockholm(reader)
+            .with_context(|| format!("malformed Stockholm alignment {}", path.display())),
+        AlignmentFormat::Nexus => {
+            read_nexus(reader).with_context(|| format!("malformed Nexus file {}", path.display()))
+        }
+    }
+}
+
+/// the residues of a data line, dropping the spaces between blocks
+fn residues(line: &str) -> impl Iterator<Item = u8> + '_ {
+    line.bytes().filter(|c| !c.is_ascii_whitespace())
+}
+
+/// parses a relaxed Phylip alignment, where names are separated from the sequences
+/// by whitespace and thus can be of any length (but cannot contain spaces)
+pub fn read_phylip<R: BufRead>(reader: R) -> anyhow::Result<Vec<OwnedRecord>> {
+    let (text, latin1) = read_text(reader)?;
+    let lines = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let mut dims = lines
+        .first()
+        .map(|l| l.split_whitespace())
+        .into_iter()
+        .flatten()
+        .map(|t| t.parse::<usize>());
+    let (ntaxa, nchars) = match (dims.next(), dims.next()) {
+        (Some(Ok(ntaxa)), Some(Ok(nchars))) => (ntaxa, nchars),
+        _ => bail!("expected the number of taxa and characters on the first line"),
+    };
+    let data = &lines[1..];
+    let mut records = phylip_sequential(data, ntaxa, nchars)
+        .or_else(|| phylip_interleaved(data, ntaxa, nchars))
+        .ok_or_else(|| {
+            anyhow!(
+                "expected {} sequences of {} characters, sequential or interleaved",
+                ntaxa,
+                nchars
+            )
+        })?;
+    restore_latin1(&mut records, latin1);
+    Ok(records)
+}
+
+/// splits a line starting a sequence into the name and the residues after it
+fn split_name(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()))
+}
+
+fn record(name: &str, seq: Vec<u8>, nchars: usize) -> Option<OwnedRecord> {
+    (seq.len() == nchars).then(|| OwnedRecord {
+        head: name.as_bytes().to_vec(),
+        seq,
+    })
+}
+
+/// each sequence starts on its name line and may wrap onto the lines right after it
+fn phylip_sequential(data: &[String], ntaxa: usize, nchars: usize) -> Option<Vec<OwnedRecord>> {
+    let mut lines = data.iter();
+    let mut records = Vec::with_capacity(ntaxa);
+    for _ in 0..ntaxa {
+        let (name, first) = split_name(lines.next()?);
+        let mut seq = residues(first).collect::<Vec<_>>();
+        while seq.len() < nchars {
+            seq.extend(residues(lines.next()?));
+        }
+        records.push(record(name, seq, nchars)?);
+    }
+    lines.next().is_none().then_some(records)
+}
+
+/// the first block carries the names, later blocks continue the sequences in the same order
+fn phylip_interleaved(data: &[String], ntaxa: usize, nchars: usize) -> Option<Vec<OwnedRecord>> {
+    if ntaxa == 0 {
+        return None;
+    }
+    let mut blocks = data.chunks_exact(ntaxa);
+    if !blocks.remainder().is_empty() {
+        return None;
+    }
+    let mut names = Vec::with_capacity(ntaxa);
+    let mut seqs = Vec::with_capacity(ntaxa);
+    for line in blocks.next()? {
+        let (name, first) = split_name(line);
+        names.push(name);
+        seqs.push(residues(first).collect::<Vec<_>>());
+    }
+    for block in blocks {
+        for (seq, line) in seqs.iter_mut().zip(block) {
+            seq.extend(residues(line));
+        }
+    }
+    names
+        .into_iter()
+        .zip(seqs)
+        .map(|(name, seq)| record(name, seq, nchars))
+        .collect()
+}
+
+/// parses the first alignment of a Stockholm file, whose sequences may be split
+/// over several blocks; of the markup only the `#=GC RF` line is kept
+pub fn read_stockholm<R: BufRead>(reader: R) -> anyhow::Result<Alignment> {
+    let mut index: AHashMap<String, usize> = AHashMap::new();
+    let mut records: Vec<OwnedRecord> = Vec::new();
+    let mut reference: Option<Vec<u8>> = None;
+    let (text, latin1) = read_text(reader)?;
+    for line in text.lines() {
+        let line = line.trim();
+        if line == "//" {
+            break;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(markup) = line.strip_prefix('#') {
+            let mut tokens = markup.split_whitespace();
+            if let (Some("=GC"), Some("RF"), Some(rf)) =
+                (tokens.next(), tokens.next(), tokens.next())
+            {
+                reference
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(rf.as_bytes());
+            }
+            continue;
+        }
+        let (name, rest) = split_name(line);
+        let i = *index.entry(name.to_string()).or_insert_with(|| {
+            records.push(OwnedRecord {
+                head: name.as_bytes().to_vec(),
+                seq: Vec::new(),
+            });
+            records.len() - 1
+        });
+        records[i].seq.extend(residues(rest));
+    }
+    if let Some(first) = records.first() {
+        let width = first.seq.len();
+        if let Some(r) = records.iter().find(|r| r.seq.len() != width) {
+            bail!(
+                "{} has {} columns, expected {}",
+                String::from_utf8_lossy(&r.head),
+                r.seq.len(),
+                width
+            );
+        }
+        if let Some(rf) = &reference {
+            if rf.len() != width {
+                bail!("the RF line has {} columns, expected {}", rf.len(), width);
+            }
+        }
+    }
+    restore_latin1(&mut records, latin1);
+    Ok(Alignment {
+        records,
+        reference,
+        ..Default::default()
+    })
+}
+
+/// splits Nexus text into its commands (each ending in `;`), dropping `[...]`
+/// comments, which includes the `[&...]` annotations of BEAST trees
+fn nexus_commands(text: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut comment_depth = 0;
+    let mut quoted = false;
+    for c in text.chars() {
+        if quoted {
+            // an escaped quote ('') closes and reopens the quotes, keeping both
+            quoted = c != '\'';
+            current.push(c);
+            continue;
+        }
+        match c {
+            '[' => comment_depth += 1,
+            ']' if comment_depth > 0 => comment_depth -= 1,
+            _ if comment_depth > 0 => {}
+            '\'' => {
+                quoted = true;
+                current.push(c);
+            }
+            ';' => commands.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        commands.push(current);
+    }
+    commands
+}
+
+/// a Nexus name with its quotes removed, made a safe Newick label so that
+/// the names of the matrix and of the trees agree
+fn nexus_name(token: &str) -> String {
+    match token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        Some(inner) => newick_label(&inner.replace("''", "'")),
+        None => newick_label(token),
+    }
+}
+
+/// splits a Nexus command into tokens at blanks, with `=` and `,` being tokens
+/// of their own; quoted tokens are kept whole
+fn nexus_tokens(command: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in command.chars() {
+        if quoted {
+            quoted = c != '\'';
+            current.push(c);
+            continue;
+        }
+        match c {
+            '\'' => {
+                quoted = true;
+                current.push(c);
+            }
+            '=' | ',' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(c.to_string());
+            }
+            _ if c.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// the `KEY=value` settings (and bare flags, as empty values) of a Nexus command,
+/// keys upper-cased
+fn nexus_settings(tokens: &[String]) -> AHashMap<String, String> {
+    let mut settings = AHashMap::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let key = tokens[i].to_ascii_uppercase();
+        if tokens.get(i + 1).map(String::as_str) == Some("=") {
+            settings.insert(key, tokens.get(i + 2).cloned().unwrap_or_default());
+            i += 3;
+        } else {
+            settings.insert(key, String::new());
+            i += 1;
+        }
+    }
+    settings
+}
+
+/// renames the leaves of a Newick tree through the TRANSLATE table `translate`,
+/// leaves not in it keeping their names
+fn translate_newick(newick: &str, translate: &AHashMap<String, String>) -> String {
+    let mut out = String::with_capacity(newick.len());
+    for newick in clean_newick(newick) {
+        // labels right after `(` or `,` name leaves, others are internal labels or lengths
+        let mut leaf = true;
+        for token in newick.split_inclusive(|c| "(),:;".contains(c)) {
+            let (label, delimiter) = token.split_at(token.len() - 1);
+            match translate.get(label) {
+                Some(translated) if leaf => out.push_str(translated),
+                _ => out.push_str(label),
+            }
+            out.push_str(delimiter);
+            leaf = delimiter == "(" || delimiter == ",";
+        }
+    }
+    out
+}
+
+/// parses the rows of a Nexus MATRIX, which are interleaved (names repeating in
+/// every block) or sequential (sequences possibly wrapping over several lines)
+fn nexus_matrix(
+    body: &str,
+    nchars: Option<usize>,
+    interleaved: bool,
+) -> anyhow::Result<Vec<OwnedRecord>> {
+    let mut index: AHashMap<String, usize> = AHashMap::new();
+    let mut records: Vec<OwnedRecord> = Vec::new();
+    let lines = body.lines().map(nexus_tokens).filter(|t| !t.is_empty());
+    match nchars {
+        Some(nchars) if !interleaved => {
+            let mut tokens = lines.flatten();
+            while let Some(name) = tokens.next() {
+                let mut seq = Vec::with_capacity(nchars);
+                while seq.len() < nchars {
+                    match tokens.next() {
+                        Some(t) => seq.extend_from_slice(t.as_bytes()),
+                        None => bail!("the sequence of {} is truncated", nexus_name(&name)),
+                    }
+                }
+                records.push(OwnedRecord {
+                    head: nexus_name(&name).into_bytes(),
+                    seq,
+                });
+            }
+        }
+        _ => {
+            for line in lines {
+                let name = nexus_name(&line[0]);
+                let i = *index.entry(name.clone()).or_insert_with(|| {
+                    records.push(OwnedRecord {
+                        head: name.into_bytes(),
+                        seq: Vec::new(),
+                    });
+                    records.len() - 1
+                });
+                for t in &line[1..] {
+                    records[i].seq.extend_from_slice(t.as_bytes());
+                }
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// parses the first DATA (or CHARACTERS) block of a Nexus file as the alignment,
+/// with match characters expanded and gaps written as `-`, along with the trees
+/// of all TREES blocks
+pub fn read_nexus<R: BufRead>(reader: R) -> anyhow::Result<Alignment> {
+    let (text, latin1) = read_text(reader)?;
+    let text = text.trim_start();
+    let text = text
+        .get(6..)
+        .filter(|_| text[..6].eq_ignore_ascii_case("#NEXUS"));
+    let text = text.ok_or_else(|| anyhow!("missing the #NEXUS line"))?;
+    let mut block = String::new();
+    let mut records: Option<Vec<OwnedRecord>> = None;
+    let (mut ntaxa, mut nchars, mut interleaved) = (None, None, false);
+    let (mut gap, mut matchchar) = (b'-', None);
+    let mut translate: AHashMap<String, String> = AHashMap::new();
+    let mut trees = Vec::new();
+    for command in nexus_commands(text) {
+        let command = command.trim();
+        let (keyword, rest) =
+            command.split_at(command.find(char::is_whitespace).unwrap_or(command.len()));
+        let keyword = keyword.to_ascii_uppercase();
+        match (block.as_str(), keyword.as_str()) {
+            (_, "BEGIN") => {
+                block = rest.trim().to_ascii_uppercase();
+                translate.clear();
+            }
+            (_, "END") | (_, "ENDBLOCK") => block.clear(),
+            ("DATA", "DIMENSIONS") | ("CHARACTERS", "DIMENSIONS") => {
+                let settings = nexus_settings(&nexus_tokens(rest));
+                if let Some(n) = settings.get("NTAX") {
+                    ntaxa = Some(n.parse::<usize>().context("invalid NTAX")?);
+                }
+                if let Some(n) = settings.get("NCHAR") {
+                    nchars = Some(n.parse::<usize>().context("invalid NCHAR")?);
+                }
+            }
+            ("DATA", "FORMAT") | ("CHARACTERS", "FORMAT") => {
+                let settings = nexus_settings(&nexus_tokens(rest));
+                interleaved =
+                    matches!(settings.get("INTERLEAVE"), Some(v) if !v.eq_ignore_ascii_case("no"));
+                if let Some(g) = settings.get("GAP") {
+                    gap = *g.as_bytes().first().unwrap_or(&b'-');
+                }
+                matchchar = settings.get("MATCHCHAR").and_then(|m| m.bytes().next());
+            }
+            ("DATA", "MATRIX") | ("CHARACTERS", "MATRIX") if records.is_none() => {
+                records = Some(nexus_matrix(rest, nchars, interleaved)?);
+            }
+            ("TREES", "TRANSLATE") => {
+                let tokens = nexus_tokens(rest);
+                for pair in tokens.split(|t| t == ",") {
+                    if let [key, name] = pair {
+                        translate.insert(nexus_name(key), nexus_name(name));
+                    }
+                }
+            }
+            ("TREES", "TREE") | ("TREES", "UTREE") => match rest.find('=') {
+                Some(eq) => trees.push(translate_newick(&rest[eq + 1..], &translate)),
+                None => bail!("a tree without a Newick string: {}", command),
+            },
+            _ => {}
+        }
+    }
+    let mut records = records.ok_or_else(|| anyhow!("no DATA or CHARACTERS matrix"))?;
+    if let Some(ntaxa) = ntaxa.filter(|&n| n != records.len()) {
+        bail!("the matrix has {} taxa, expected {}", records.len(), ntaxa);
+    }
+    let width = records.first().map_or(0, |r| r.seq.len());
+    if let Some(r) = records
+        .iter()
+        .find(|r| r.seq.len() != nchars.unwrap_or(width))
+    {
+        bail!(
+            "{} has {} characters, expected {}",
+            String::from_utf8_lossy(&r.head),
+            r.seq.len(),
+            nchars.unwrap_or(width)
+        );
+    }
+    if let (Some(m), Some((first, rest))) = (matchchar, records.split_first_mut()) {
+        for r in rest {
+            for (c, &f) in r.seq.iter_mut().zip(&first.seq) {
+                if *c == m {
+                    *c = f;
+                }
+            }
+        }
+    }
+    for c in records.iter_mut().flat_map(|r| r.seq.iter_mut()) {
+        if *c == gap {
+            *c = b'-';
+        }
+    }
+    restore_latin1(&mut records, latin1);
+    Ok(Alignment {
+        records,
+        trees,
+        ..Default::default()
+    })
+}
+
+/// reads a two-column TSV of names and the canonical names they stand for,
+/// skipping blank lines and lines starting with `#`
+pub fn read_rename_table<P: AsRef<Path>>(path: P) -> anyhow::Result<AHashMap<String, String>> {
+    let path = path.as_ref();
+    let (text, _) =
+        read_text(File::open(path).with_context(|| format!("cannot read {}", path.display()))?)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+    let mut table = AHashMap::new();
+    for (lineno, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (from, to) = match line.split_once('\t') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                (from.trim(), to.trim())
+            }
+            _ => bail!(
+                "line {} of {} is not two tab-separated names",
+                lineno + 1,
+                path.display()
+            ),
+        };
+        if table.insert(from.to_string(), to.to_string()).is_some() {
+            bail!("{} is renamed twice in {}", from, path.display());
+        }
+    }
+    Ok(table)
+}
+
+/// reads a list of taxon names, one per line, skipping blank lines and lines
+/// starting with `#`
+pub fn read_taxon_list<P: AsRef<Path>>(path: P) -> anyhow::Result<AHashSet<String>> {
+    let path = path.as_ref();
+    let (text, _) =
+        read_text(File::open(path).with_context(|| format!("cannot read {}", path.display()))?)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// renames the records named in `table`, returning how many were renamed
+pub fn rename_records(records: &mut [OwnedRecord], table: &AHashMap<String, String>) -> usize {
+    let mut renamed = 0;
+    for r in records.iter_mut() {
+        if let Some(to) = table.get(decode_text(&r.head).as_ref()) {
+            r.head = encode_text(to, is_latin1(&r.head));
+            renamed += 1;
+        }
+    }
+    renamed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nexus(text: &str) -> anyhow::Result<Alignment> {
+        read_nexus(text.as_bytes())
+    }
+
+    #[test]
+    fn stockholm_blocks_join_along_with_the_rf_line() {
+        let alignment = read_stockholm(
+            "# STOCKHOLM 1.0
+#=GF ID test
+A    AC-T
+B    ac.t
+#=GC RF xx.x
+
+A    GG
+#=GS A DE a description
+B    G-
+#=GC RF x.
+//
+C    TTTTTT
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let rows = alignment
+            .records
+            .iter()
+            .map(|r| (r.head.as_slice(), r.seq.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [(&b"A"[..], &b"AC-TGG"[..]), (b"B", b"ac.tG-")]);
+        assert_eq!(alignment.reference.as_deref(), Some(&b"xx.xx."[..]));
+        let error = read_stockholm("A AC\nB ACG\n//\n".as_bytes())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("B has 3 columns, expected 2"), "{}", error);
+        let error = read_stockholm("A AC\n#=GC RF x\n//\n".as_bytes())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("the RF line has 1 columns"), "{}", error);
+    }
+
+    #[test]
+    fn interleaved_matrices_join_the_blocks_of_each_taxon() {
+        let alignment = nexus(
+            "#NEXUS
+            BEGIN DATA;
+              DIMENSIONS NTAX=2 NCHAR=8;
+              FORMAT DATATYPE=DNA INTERLEAVE GAP=. MATCHCHAR=*;
+              MATRIX
+                A ACGT
+                B AC.*
+                [the second block]
+                A GGCC
+                B **TT
+              ;
+            END;",
+        )
+        .unwrap();
+        let rows = alignment
+            .records
+            .iter()
+            .map(|r| (r.head.as_slice(), r.seq.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [(&b"A"[..], &b"ACGTGGCC"[..]), (b"B", b"AC-TGGTT")]);
+    }
+
+    #[test]
+    fn quoted_names_are_unquoted_and_made_labels() {
+        let alignment = nexus(
+            "#nexus
+            begin characters;
+              dimensions nchar=4;
+              matrix
+                'Homo sapiens' AC-T
+                'it''s; here' ACGT
+                plain AAAA;
+            end;
+            begin trees;
+              translate 1 'Homo sapiens', 2 'it''s; here', 3 plain;
+              tree one = ((1,2),3);
+            end;",
+        )
+        .unwrap();
+        let names = alignment
+            .records
+            .iter()
+            .map(|r| String::from_utf8_lossy(&r.head).into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Homo_sapiens", "it_s__here", "plain"]);
+        assert_eq!(
+            alignment.trees,
+            ["((Homo_sapiens,it_s__here),plain);".to_string()]
+        );
+    }
+
+    #[test]
+    fn dimensions_must_match_the_matrix() {
+        let matrix = |dimensions: &str| {
+            nexus(&format!(
+                "#NEXUS\nBEGIN DATA;\nDIMENSIONS {};\nMATRIX\nA ACGT\nB AC-T\n;\nEND;",
+                dimensions
+            ))
+        };
+        assert!(matrix("NTAX=2 NCHAR=4").is_ok());
+        let error = matrix("NTAX=3 NCHAR=4").unwrap_err().to_string();
+        assert!(error.contains("has 2 taxa, expected 3"), "{}", error);
+        let error = matrix("NTAX=2 NCHAR=5").unwrap_err().to_string();
+        assert!(error.contains("is truncated"), "{}", error);
+        let error = matrix("NTAX=2 NCHAR=3").unwrap_err().to_string();
+        assert!(error.contains("has 4 characters, expected 3"), "{}", error);
+        assert!(matrix("NTAX=two NCHAR=4").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//! # Crucible
+//!
+//! `crucible` aims to be an efficient implementation of the WITCH algorithm
+//! for aligning fragments to an existing alignment (called a "reference"
+//! or "backbone" alignment).
+pub mod adder;
+pub mod alphabet;
+pub mod annotate;
+pub mod batch;
+pub mod clustering;
+pub mod combined;
+pub mod compact_printer;
+pub mod consensus;
+pub mod decomposition;
+pub mod distance;
+pub mod export;
+pub mod external;
+pub mod faidx;
+pub mod input;
+pub mod matching;
+pub mod melt;
+pub mod newick;
+pub mod nj;
+pub mod output;
+pub mod overlap;
+pub mod packed;
+pub mod partition;
+pub mod profile;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod score_calc;
+pub mod stability;
+pub mod stats;
+pub mod streaming;
+pub mod structures;
+pub mod tree_ops;
+pub mod unmelt;
+pub mod verify;
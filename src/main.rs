@@ -1,46 +1,177 @@
-//! # Crucible
-//!
-//! `crucible` aims to be an efficient implementation of the WITCH algorithm
-//! for aligning fragments to an existing alignment (called a "reference"
-//! or "backbone" alignment).
-mod adder;
-mod combined;
-mod compact_printer;
-mod external;
-mod matching;
-mod melt;
-mod score_calc;
-mod structures;
-
+//! Command line front-end of `crucible`.
 use std::{path::PathBuf, time::Instant};
 
 use anyhow::Ok;
 use clap::{Parser, Subcommand};
-use melt::oneshot_melt;
+use crucible::{
+    batch::oneshot_melt_batch,
+    combined,
+    decomposition::{DecompositionMode, DecompositionOptions},
+    export::oneshot_export_counts,
+    faidx::{fai_path, gzi_path, FastaIndex, GziIndex},
+    input::Compression,
+    melt::{concatenated_melt, dry_run_melt, oneshot_melt, partitioned_melt, MeltOptions},
+    overlap::oneshot_overlap,
+    stability::oneshot_stability,
+    stats::oneshot_stats,
+    streaming::streaming_melt,
+    unmelt::oneshot_unmelt,
+    verify::oneshot_verify,
+};
 use tracing::info;
 
-use crate::{adder::oneshot_add_queries, score_calc::oneshot_score_queries};
+use crucible::{adder::oneshot_add_queries, score_calc::oneshot_score_queries};
 
-#[derive(Parser, Debug, Hash, PartialEq)]
+#[derive(Parser, Debug, PartialEq)]
 #[clap(author, version, about)]
 struct Args {
     #[clap(subcommand)]
     cmd: SubCommand,
 }
 
-#[derive(Subcommand, Debug, PartialEq, Hash)]
+// parsed once, so the size of the melt options does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug, PartialEq)]
 enum SubCommand {
-    // /// Decompose input alignment by a tree into MSAs ready to become HMMs
-    // Melt {
-    //     #[clap(short, long)]
-    //     input: PathBuf,
-    //     #[clap(short, long)]
-    //     tree: PathBuf,
-    //     #[clap(short, long)]
-    //     outdir: PathBuf,
-    //     #[clap(short = 's', long)]
-    //     max_size: usize,
-    // },
+    /// Decompose input alignment by a tree into MSAs ready to become HMMs
+    Melt {
+        /// Path to the input alignment (FASTA, Phylip, Stockholm or Nexus), optionally
+        /// compressed; `-` reads it from standard input
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Path to the guide tree in Newick format; optional for Nexus inputs with trees
+        #[clap(short, long, conflicts_with = "no-tree")]
+        tree: Option<PathBuf>,
+        #[clap(short, long)]
+        outdir: PathBuf,
+        /// How cut edges are chosen when decomposing the tree
+        #[clap(long, arg_enum, default_value = "balanced")]
+        decomposition: DecompositionMode,
+        /// Melt each locus a RAxML or Nexus partition file delimits in the input
+        /// into a directory of its own
+        #[clap(long)]
+        partitions: Option<PathBuf>,
+        /// Melt the loci of --partitions together as one concatenated alignment,
+        /// recording them in the metadata
+        #[clap(long, requires = "partitions")]
+        concatenated: bool,
+        /// Read the FASTA input twice instead of holding it in memory, first to count
+        /// the characters of the subsets, then to write their sequences (fetched
+        /// through its .fai index if it has one); needs --tree
+        #[clap(long, conflicts_with_all = &["partitions", "dry-run"])]
+        streaming: bool,
+        /// Memory-map the uncompressed FASTA input of --streaming, copying its records
+        /// into the subsets as they are when they need no cleanup
+        #[clap(long, requires = "streaming")]
+        mmap: bool,
+        #[clap(flatten)]
+        options: MeltOptions,
+    },
+
+    /// Melt every gene alignment in a directory, each with the tree of the same
+    /// name (e.g. gene1.fasta with gene1.nwk or gene1.nwk.gz), in parallel
+    MeltBatch {
+        /// Directory of the gene alignments and trees
+        dir: PathBuf,
+        /// Tree files to use instead of those in the directory, each matched to the
+        /// alignment of the same name
+        #[clap(long, multiple_values = true)]
+        trees: Vec<PathBuf>,
+        /// Output directory, getting a subdirectory per gene and a summary (batch.json)
+        #[clap(short, long)]
+        outdir: PathBuf,
+        /// How cut edges are chosen when decomposing the trees
+        #[clap(long, arg_enum, default_value = "balanced")]
+        decomposition: DecompositionMode,
+        #[clap(flatten)]
+        options: MeltOptions,
+    },
+
+    /// Report how often taxa share a subset when decomposing bootstrap replicate trees
+    Stability {
+        /// Path to the replicate trees in Newick format, one per line
+        #[clap(short, long)]
+        trees: PathBuf,
+        /// Output path of the JSON report
+        #[clap(short, long)]
+        output: PathBuf,
+        /// How cut edges are chosen when decomposing the trees
+        #[clap(long, arg_enum, default_value = "balanced")]
+        decomposition: DecompositionMode,
+        #[clap(flatten)]
+        options: DecompositionOptions,
+    },
+
+    /// Reconstruct the whole alignment from the subsets melt wrote, optionally checking
+    /// that it has the same sequences as the original, as melted
+    Unmelt {
+        /// Output directory of melt
+        outdir: PathBuf,
+        /// Output path of the reconstructed alignment (FASTA)
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Original alignment to compare the reconstructed one against, in any order
+        #[clap(short, long)]
+        source: Option<PathBuf>,
+    },
+
+    /// Check the output of melt against its metadata, e.g. for files truncated by a
+    /// failing file system
+    Verify {
+        /// Output directory of melt
+        outdir: PathBuf,
+        /// Input alignment to check is the one the melt was made from
+        #[clap(short, long)]
+        input: Option<PathBuf>,
+        /// Guide tree to check is the one the melt was made from
+        #[clap(short, long)]
+        tree: Option<PathBuf>,
+    },
+
+    /// Summarize the subsets of the output of melt (sizes, nesting, column occupancy)
+    /// as JSON
+    Stats {
+        /// Output directory of melt
+        outdir: PathBuf,
+        /// Output path of the JSON summary, the standard output if not given
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Compute which HMMs of the output of melt share taxa and well-occupied columns,
+    /// as a JSON adjacency list, e.g. for pruning redundant HMMs of an ensemble
+    Overlap {
+        /// Output directory of melt
+        outdir: PathBuf,
+        /// Output path of the adjacency list, the standard output if not given
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Fraction of the sequences of an HMM with a character in a column for it
+        /// to be well-occupied
+        #[clap(long, default_value = "0.5")]
+        min_occupancy: f32,
+    },
+
+    /// Export the character counts of every HMM of the output of melt as a Parquet
+    /// table (needs the `parquet` feature)
+    ExportCounts {
+        /// Output directory of melt
+        outdir: PathBuf,
+        /// Output path of the Parquet file
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Index an uncompressed or bgzip-compressed FASTA file for random access, as
+    /// samtools faidx does
+    Faidx {
+        /// Path to the FASTA file
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Output path of the index; defaults to the input path with ".fai" appended
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
 
     Add {
         /// Path to query sequences (fragments) in FASTA format
@@ -68,7 +199,6 @@ enum SubCommand {
         #[clap(long)]
         threads: Option<usize>,
     },
-
     // Score {
     //     #[clap(short, long)]
     //     root: PathBuf,
@@ -84,16 +214,131 @@ enum SubCommand {
 fn main() -> anyhow::Result<()> {
     let now = Instant::now();
     let args = Args::parse();
-    tracing_subscriber::fmt::init();
+    // the standard output is left for what commands print, e.g. JSON reports
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     match args.cmd {
-        // SubCommand::Melt {
-        //     input,
-        //     tree,
-        //     outdir,
-        //     max_size,
-        // } => {
-        //     oneshot_melt(&input, &tree, max_size, &outdir)?;
-        // }
+        SubCommand::Melt {
+            input,
+            tree,
+            outdir,
+            decomposition,
+            partitions,
+            concatenated,
+            streaming,
+            mmap,
+            options,
+        } => match partitions {
+            _ if options.dry_run => {
+                let plan = dry_run_melt(
+                    &input,
+                    tree.as_ref(),
+                    decomposition.strategy().as_ref(),
+                    &options,
+                )?;
+                println!("{}", serde_json::to_string_pretty(&plan)?);
+            }
+            None if streaming => {
+                streaming_melt(
+                    &input,
+                    tree.as_ref(),
+                    decomposition.strategy().as_ref(),
+                    &options,
+                    mmap,
+                    &outdir,
+                )?;
+            }
+            Some(partitions) if concatenated => {
+                concatenated_melt(
+                    &input,
+                    &partitions,
+                    tree.as_ref(),
+                    decomposition.strategy().as_ref(),
+                    &options,
+                    &outdir,
+                )?;
+            }
+            Some(partitions) => {
+                partitioned_melt(
+                    &input,
+                    &partitions,
+                    tree.as_ref(),
+                    decomposition.strategy().as_ref(),
+                    &options,
+                    &outdir,
+                )?;
+            }
+            None => {
+                oneshot_melt(
+                    &input,
+                    tree.as_ref(),
+                    decomposition.strategy().as_ref(),
+                    &options,
+                    &outdir,
+                )?;
+            }
+        },
+        SubCommand::MeltBatch {
+            dir,
+            trees,
+            outdir,
+            decomposition,
+            options,
+        } => {
+            oneshot_melt_batch(
+                &dir,
+                &trees,
+                decomposition.strategy().as_ref(),
+                &options,
+                &outdir,
+            )?;
+        }
+        SubCommand::Stability {
+            trees,
+            output,
+            decomposition,
+            options,
+        } => {
+            oneshot_stability(&trees, decomposition.strategy().as_ref(), &options, &output)?;
+        }
+        SubCommand::Unmelt {
+            outdir,
+            output,
+            source,
+        } => {
+            oneshot_unmelt(&outdir, output.as_deref(), source.as_deref())?;
+        }
+        SubCommand::Verify {
+            outdir,
+            input,
+            tree,
+        } => {
+            oneshot_verify(&outdir, input.as_deref(), tree.as_deref())?;
+        }
+        SubCommand::Stats { outdir, output } => {
+            oneshot_stats(&outdir, output.as_deref())?;
+        }
+        SubCommand::Overlap {
+            outdir,
+            output,
+            min_occupancy,
+        } => {
+            oneshot_overlap(&outdir, output.as_deref(), min_occupancy)?;
+        }
+        SubCommand::ExportCounts { outdir, output } => {
+            oneshot_export_counts(&outdir, &output)?;
+        }
+        SubCommand::Faidx { input, output } => {
+            let index = FastaIndex::build(&input)?;
+            index.write(output.unwrap_or_else(|| fai_path(&input)))?;
+            info!(num_sequences = index.records.len(), "indexed {:?}", input);
+            if Compression::of_file(&input)? == Compression::Bgzf {
+                let blocks = GziIndex::build(&input)?;
+                blocks.write(gzi_path(&input))?;
+                info!(num_blocks = blocks.blocks.len(), "indexed the BGZF blocks");
+            }
+        }
         // SubCommand::Score { root } => {
         //     oneshot_score_queries(&root)?;
         // }
@@ -1,78 +1,1137 @@
-use crate::{external::hmmbuild, structures::*};
-use ahash::AHashSet;
+use crate::{
+    alphabet::{
+        strip_lowercase_columns, Alphabet, CharCounter, CharacterOptions, LowercasePolicy,
+        ResidueClasses,
+    },
+    annotate::{
+        figtree_nexus, finest_subsets, write_decomposition_dot, write_itol_colorstrip,
+        write_membership_csv,
+    },
+    clustering::cluster_decomp,
+    consensus::{consensus_tree, ConsensusMode},
+    decomposition::{
+        BalanceWeight, Component, DecompositionConstraints, DecompositionOptions,
+        DecompositionStrategy, PolytomyPolicy,
+    },
+    distance::{kmer_distances, DEFAULT_KMER_SIZE},
+    external::{
+        hmmbuild, hmmbuild_rows, write_stockholm_end, write_stockholm_row, STOCKHOLM_HEADER,
+    },
+    input::{
+        decode_text, encode_text, is_latin1, is_remote, read_alignment, read_rename_table,
+        read_taxon_list, rename_records, Alignment, HeaderPolicy,
+    },
+    newick::{newick_label, parse_trees, read_trees},
+    nj::neighbor_joining,
+    output::{
+        check_outdir, checksum_files, create_output, find_output, input_checksum, pack_directory,
+        write_atomically, write_fasta, OutputCompression,
+    },
+    packed::PackedAlignment,
+    partition::read_partitions,
+    profile::{henikoff_weights, majority_consensus, subset_quality, write_frequency_table},
+    structures::*,
+    tree_ops::{
+        graft_copies, induced_subtree_newick, leaf_nodes, long_branch_taxa, midpoint_root,
+        outgroup_root, prune_taxa, rename_taxa, resolve_polytomies, tree_mrca, EditableTree,
+    },
+};
+use ahash::{AHashMap, AHashSet};
+use anyhow::bail;
+use clap::{ArgEnum, Args};
 use fixedbitset::FixedBitSet;
 use itertools::Itertools;
-use ndarray::{Array, ShapeBuilder};
+use ndarray::Array2;
 use ogcat::ogtree::*;
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use seq_io::fasta::{Reader, Record};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+    IntoParallelRefMutIterator, ParallelIterator,
+};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use seq_io::{fasta::OwnedRecord, BaseRecord};
 use thread_local::ThreadLocal;
 
 use std::{
     cell::RefCell,
-    collections::BinaryHeap,
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
     fs::{create_dir_all, File},
-    io::BufWriter,
-    path::PathBuf,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use tracing::info;
+use tracing::{info, warn};
+
+#[derive(Args, Debug, Clone, PartialEq, Default)]
+pub struct MeltOptions {
+    #[clap(flatten)]
+    pub decomposition: DecompositionOptions,
+    /// Decompose a consensus of all trees in the tree file instead of only the first
+    #[clap(long, arg_enum)]
+    pub consensus: Option<ConsensusMode>,
+    /// Midpoint-root the guide tree before decomposing it
+    #[clap(long)]
+    pub midpoint_root: bool,
+    /// Root the guide tree on the clade spanned by these taxa (comma separated)
+    #[clap(long, use_value_delimiter = true, conflicts_with = "midpoint-root")]
+    pub outgroup: Vec<String>,
+    /// Also train each HMM on this many phylogenetically nearest taxa from
+    /// outside its subset
+    #[clap(long, default_value = "0")]
+    pub padding: usize,
+    /// How taxon names are taken from sequence headers: full, first-token,
+    /// delimiter:<DELIM>[:<FIELD>] or regex:<PATTERN> (its first group)
+    #[clap(long, default_value = "full")]
+    pub header_id: HeaderPolicy,
+    /// Rename sequences and guide tree leaves by this two-column TSV (name, then
+    /// canonical name) before matching them up
+    #[clap(long)]
+    pub rename: Option<PathBuf>,
+    /// Match guide tree leaves to sequences whose names differ only in case, blanks
+    /// or underscores, when there is a single such sequence
+    #[clap(long)]
+    pub fuzzy_match: bool,
+    /// Only melt the taxa listed (one per line) in this file, pruning the guide tree to them
+    #[clap(long)]
+    pub include_taxa: Option<PathBuf>,
+    /// Leave out the taxa listed (one per line) in this file, pruning them from the guide tree
+    #[clap(long)]
+    pub exclude_taxa: Option<PathBuf>,
+    /// Estimate a guide tree from the alignment (k-mer distances and neighbor joining)
+    #[clap(long)]
+    pub no_tree: bool,
+    /// K-mer length for the distances behind an estimated guide tree [default: 6]
+    #[clap(long)]
+    pub kmer_size: Option<usize>,
+    /// Decompose by recursively bisecting k-mer clusters of the sequences instead of a tree
+    #[clap(long)]
+    pub cluster: bool,
+    /// Melt only the full-length sequences, those within this fraction of the median
+    /// sequence length (ignoring gaps), writing the others unaligned to
+    /// queries.fasta to be added later, as UPP does
+    #[clap(long)]
+    pub split_fragments: Option<f64>,
+    /// Move taxa whose terminal branch is longer than this many times the median
+    /// terminal branch into a separate outlier subset
+    #[clap(long)]
+    pub quarantine: Option<f64>,
+    /// Drop sequences whose length differs from that of most sequences instead of failing
+    #[clap(long)]
+    pub skip_invalid: bool,
+    /// What to do with sequences sharing a name
+    #[clap(long, arg_enum, default_value = "error")]
+    pub duplicates: DuplicatePolicy,
+    #[clap(flatten)]
+    pub characters: CharacterOptions,
+    /// What to do with alignment sequences missing from the guide tree
+    #[clap(long, arg_enum, default_value = "error")]
+    pub missing_taxa: MissingTaxaPolicy,
+    /// Also write the sequences of every subset, in this format, next to its HMM.
+    /// Stockholm subsets carry the `#=GC RF` line of the input or, if it has none, one
+    /// marking the columns of the subset's HMM, from which hmmbuild then takes the
+    /// match states (`--hand`); the input's own line is only carried as annotation
+    #[clap(long, arg_enum)]
+    pub subset_format: Option<SubsetFormat>,
+    /// Leave the columns with only gaps in a subset out of its files, recording the
+    /// columns kept in the metadata
+    #[clap(long, requires = "subset-format")]
+    pub trim_subsets: bool,
+    /// Also write the guide tree induced by every subset, as {i}.nwk
+    #[clap(long, conflicts_with = "cluster")]
+    pub subset_trees: bool,
+    /// Also write the guide tree colored by subset, as an iTOL color strip
+    /// (decomposition.itol.txt) and a FigTree Nexus tree (decomposition.nex)
+    #[clap(long, conflicts_with = "cluster")]
+    pub annotations: bool,
+    /// Also write how the subsets nest as a Graphviz graph, decomposition.dot
+    #[clap(long)]
+    pub dot: bool,
+    /// Also write the subsets of every taxon as membership.csv, for R or pandas
+    #[clap(long)]
+    pub membership: bool,
+    /// Also write the majority-rule consensus of every subset to
+    /// subset_consensus.fasta, e.g. as a database to prefilter queries with
+    #[clap(long)]
+    pub subset_consensus: bool,
+    /// Also write the residue frequencies in every column of the HMM of every subset,
+    /// as a table ({i}.tsv) next to it
+    #[clap(long)]
+    pub profiles: bool,
+    /// Also write the sequences of every subset with their gaps removed, as {i}.fasta
+    #[clap(long)]
+    pub ungapped_subsets: bool,
+    /// Order of the sequences within the subset files
+    #[clap(long, arg_enum, default_value = "decomposition")]
+    pub subset_order: SubsetOrder,
+    /// Compress the alignments and trees written for the subsets (the HMMs are
+    /// left as they are, for hmmsearch)
+    #[clap(long, arg_enum)]
+    pub compress_output: Option<OutputCompression>,
+    /// Pack the subset files into one subsets.tar archive, compressed with
+    /// --compress-output (zstd if not given) instead of every file on its own
+    #[clap(long)]
+    pub archive: bool,
+    /// Spread the subset files over this many directories, subsets/00, subsets/01...,
+    /// subset i going into the (i mod shards)-th
+    #[clap(long)]
+    pub shards: Option<usize>,
+    /// Name the subset files after this template instead of their number, e.g.
+    /// {clade}_{index}_{size}, which must have {index}; {clade} is the subset label
+    /// (see --clade-labels) and {size} its number of sequences
+    #[clap(long)]
+    pub name_template: Option<String>,
+    /// Wrap the sequences written at this many characters per line (60 if not
+    /// given), 0 putting every sequence on one line
+    #[clap(long)]
+    pub line_width: Option<usize>,
+    /// How the metadata is written: JSON (melt.json) or zstd-compressed MessagePack
+    /// (melt.msgpack.zst), much smaller for large alignments; either is read back
+    #[clap(long, arg_enum, default_value = "json")]
+    pub metadata_format: MetadataFormat,
+    /// Record, for every subset, its columns where fewer than this fraction of its
+    /// sequences have a counted character (masked_columns in melt.json)
+    #[clap(long)]
+    pub mask_occupancy: Option<f32>,
+    /// Also count the characters of every column of the subsets by residue class
+    #[clap(long, arg_enum)]
+    pub residue_classes: Option<ResidueClasses>,
+    /// Weight the sequences of every subset by this scheme, storing the weights in
+    /// the metadata and building the HMMs with them as given
+    #[clap(long, arg_enum)]
+    pub sequence_weights: Option<SequenceWeighting>,
+    /// Also score every subset by the mean pairwise identity and the effective
+    /// number of its sequences, for telling too diverse or redundant ones
+    #[clap(long)]
+    pub subset_quality: bool,
+    /// Count the characters of every subset on its own instead of through prefix
+    /// sums over the whole alignment, which take four bytes per cell of it but
+    /// count every sequence only once
+    #[clap(long)]
+    pub compact_counts: bool,
+    /// Count the characters of the subsets over blocks of this many columns at a
+    /// time, bounding the prefix sums to that many columns for alignments too wide
+    /// for them as a whole
+    #[clap(long, conflicts_with = "compact-counts")]
+    pub column_block: Option<usize>,
+    /// Hold the nucleotide sequences packed two bits per base, with a bitmap of their
+    /// gaps, once decomposed, counting and writing the subsets from the packed form
+    #[clap(long, conflicts_with = "column-block")]
+    pub packed: bool,
+    /// Write the column counts of the subsets to counts.bin, which is memory-mapped
+    /// when loaded, instead of into the metadata
+    #[clap(long)]
+    pub split_counts: bool,
+    /// Write into the output directory even if it has other files than those of an
+    /// earlier run, which are kept; the output then replaces none of them
+    #[clap(long)]
+    pub force: bool,
+    /// Only decompose the guide tree and print the planned subsets as JSON, without
+    /// reading the alignment or writing anything
+    #[clap(long, conflicts_with_all = &["cluster", "no-tree"])]
+    pub dry_run: bool,
+    /// Fail unless every subset is a clade of the (possibly rerooted) guide tree;
+    /// quarantined outliers are exempt
+    #[clap(long, conflicts_with = "cluster")]
+    pub strict_monophyly: bool,
+    /// Label subsets (in the metadata and HMM file names) by their MRCA in the guide tree
+    #[clap(long, conflicts_with = "cluster")]
+    pub clade_labels: bool,
+}
+
+impl MeltOptions {
+    /// the width sequences are wrapped at, `None` for not wrapping them
+    pub fn wrap_width(&self) -> Option<usize> {
+        match self.line_width {
+            None => Some(60),
+            Some(0) => None,
+            width => width,
+        }
+    }
+}
+
+/// the taxon set of the sequences in `records`, numbered in input order
+pub fn records_taxon_set(records: &[OwnedRecord]) -> anyhow::Result<TaxonSet> {
+    let mut taxon_set = TaxonSet::new();
+    for r in records {
+        let taxon_name = decode_text(&r.head).into_owned();
+        if taxon_set.to_id.contains_key(&taxon_name) {
+            bail!("duplicate sequence name {}", taxon_name);
+        }
+        taxon_set.request(taxon_name);
+    }
+    Ok(taxon_set)
+}
+
+/// a neighbor-joining tree over the k-mer distances between `records`, for when
+/// no guide tree is given
+pub fn estimate_guide_tree(records: &[OwnedRecord], k: usize) -> anyhow::Result<TreeCollection> {
+    let mut taxon_set = records_taxon_set(records)?;
+    let distances = kmer_distances(records.iter().map(|r| r.seq.as_slice()), k);
+    let tree = neighbor_joining(&distances).into_tree(&mut taxon_set);
+    info!(
+        k,
+        ntaxa = records.len(),
+        "estimated guide tree by neighbor joining"
+    );
+    Ok(TreeCollection {
+        taxon_set,
+        trees: vec![tree],
+    })
+}
+
+/// applies the tree-level preprocessing asked for in `options` to the first tree,
+/// replacing it by the consensus of all trees first if asked to
+pub fn prepare_guide_tree(
+    collection: &mut TreeCollection,
+    options: &MeltOptions,
+) -> anyhow::Result<()> {
+    if let Some(mode) = options.consensus {
+        let consensus = consensus_tree(collection, mode)?;
+        info!(
+            num_trees = collection.trees.len(),
+            ?mode,
+            "built consensus guide tree"
+        );
+        collection.trees[0] = consensus;
+    }
+    let decomposition = &options.decomposition;
+    if decomposition.polytomy == PolytomyPolicy::Resolve {
+        let resolved = resolve_polytomies(
+            &collection.trees[0],
+            &mut collection.taxon_set,
+            decomposition.seed,
+        );
+        collection.trees[0] = resolved;
+    }
+    if options.midpoint_root {
+        let rooted = midpoint_root(&collection.trees[0], &mut collection.taxon_set);
+        collection.trees[0] = rooted;
+        info!("midpoint-rooted guide tree");
+    } else if !options.outgroup.is_empty() {
+        let rooted = outgroup_root(
+            &collection.trees[0],
+            &mut collection.taxon_set,
+            &options.outgroup,
+        )?;
+        collection.trees[0] = rooted;
+        info!(outgroup = ?options.outgroup, "rooted guide tree on outgroup");
+    }
+    Ok(())
+}
+
+/// how the sequences of each subset are written to `subsets/`
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubsetFormat {
+    /// aligned FASTA, as `{i}.afa`
+    Afa,
+    /// Stockholm, as `{i}.sto`, with the `#=GC RF` line of the input or else one
+    /// marking the columns of the subset's HMM, the HMM then being built on it
+    Stockholm,
+}
+
+/// how the sequences of each subset are weighted (see `MeltOptions::sequence_weights`)
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SequenceWeighting {
+    /// the position-based weights of Henikoff & Henikoff, over the columns of the
+    /// subset's HMM
+    Henikoff,
+}
+
+/// the order the sequences are in within each subset file
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SubsetOrder {
+    /// that of the decomposition, i.e. of the guide tree
+    #[default]
+    Decomposition,
+    /// that of the input alignment
+    Input,
+    /// by name
+    Name,
+}
+
+impl SubsetOrder {
+    /// the positions of `records`, `input_rank` being their positions in the input,
+    /// in this order
+    pub fn arrange(self, records: &[OwnedRecord], input_rank: &[usize]) -> Vec<usize> {
+        let mut order = (0..records.len()).collect_vec();
+        match self {
+            SubsetOrder::Decomposition => {}
+            SubsetOrder::Input => order.sort_by_key(|&i| input_rank[i]),
+            SubsetOrder::Name => order.sort_by(|&a, &b| records[a].head.cmp(&records[b].head)),
+        }
+        order
+    }
+}
+
+/// `seq` with its gaps removed
+pub fn ungapped(seq: &[u8]) -> Vec<u8> {
+    seq.iter().copied().filter(|&c| c != b'-').collect()
+}
+
+/// the whole alignment as melted into `outdir`: `subsets/0.afa`, unless subset 0
+/// was written there on its own, not being every sequence, and `backbone.afa` has
+/// the alignment instead (either possibly compressed), `ctxt` being that of the melt
+pub fn backbone_path(outdir: &Path, ctxt: &CrucibleCtxt) -> PathBuf {
+    backbone_path_under(outdir, &outdir.join("subsets"), ctxt)
+}
+
+/// the whole alignment as `backbone_path` finds it, the subsets being under
+/// `subsets_root` instead (e.g. unpacked elsewhere)
+pub fn backbone_path_under(outdir: &Path, subsets_root: &Path, ctxt: &CrucibleCtxt) -> PathBuf {
+    let subset = subset_dir(subsets_root, ctxt.shards, 0).join(ctxt.subset_file_name(0, "afa"));
+    find_output(&outdir.join("backbone.afa"))
+        .or_else(|| find_output(&subset))
+        .unwrap_or(subset)
+}
+
+/// the `#=GC RF` line of the subset `hmm` of an alignment `width` columns wide:
+/// the `reference` of the input if it has one, else `x` marking the columns of
+/// the HMM
+pub fn subset_reference(hmm: &HmmMeta, reference: Option<&[u8]>, width: usize) -> Vec<u8> {
+    if let Some(reference) = reference {
+        return reference.to_vec();
+    }
+    let mut rf = vec![b'.'; width];
+    for &j in &hmm.column_poitions {
+        rf[j] = b'x';
+    }
+    rf
+}
+
+/// the compression of the files under `subsets/`, none if they are archived, an
+/// archive being compressed as a whole
+fn subset_compression(options: &MeltOptions) -> Option<OutputCompression> {
+    if options.archive {
+        None
+    } else {
+        options.compress_output
+    }
+}
+
+/// fails unless `template` names every subset apart, having `{index}`, and has
+/// no fields but those of `subset_file_name`
+fn check_name_template(template: &str) -> anyhow::Result<()> {
+    if !template.contains("{index}") {
+        bail!("the name template {} has no {{index}}", template);
+    }
+    let mut rest = template.to_string();
+    for field in NAME_TEMPLATE_FIELDS {
+        rest = rest.replace(field, "");
+    }
+    if rest.contains(['{', '}', '/']) {
+        bail!(
+            "the name template {} can only have the fields {}, and no /",
+            template,
+            NAME_TEMPLATE_FIELDS.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// writes the sequences of every subset in `metadata` (unpacked from `packed`, if
+/// given) to its directory under
+/// `subsets_root` (see `subset_dir`), in the `subset_format` of `options` (on the
+/// `subset_columns` only, if set) if given and with their gaps removed (as `.fasta`)
+/// if `ungapped_subsets`, compressing them (see `subset_compression`) and naming
+/// them after the `name_template` (see `subset_file_name`); Stockholm files carry
+/// the `reference` of the input, if any (see `subset_reference`)
+fn write_subsets(
+    records: &[OwnedRecord],
+    packed: Option<&PackedAlignment>,
+    reference: Option<&[u8]>,
+    input_rank: &[usize],
+    metadata: &[HmmMeta],
+    options: &MeltOptions,
+    subsets_root: &Path,
+) -> anyhow::Result<()> {
+    let width = packed.map_or(records[0].seq.len(), PackedAlignment::width);
+    let compression = subset_compression(options);
+    let template = options.name_template.as_deref();
+    metadata
+        .par_iter()
+        .enumerate()
+        .try_for_each(|(i, hmm)| -> anyhow::Result<()> {
+            let (lb, ub) = hmm.sequence_range;
+            let dir = subset_dir(subsets_root, options.shards, i);
+            let mut rf = subset_reference(hmm, reference, width);
+            if let Some(columns) = &hmm.subset_columns {
+                rf = columns.iter().map(|&j| rf[j]).collect();
+            }
+            // the headers are all the order needs, so the sequences are unpacked (and
+            // projected on the subset columns) one at a time as they are written
+            let order = options
+                .subset_order
+                .arrange(&records[lb..ub], &input_rank[lb..ub]);
+            let extension = match options.subset_format {
+                Some(SubsetFormat::Afa) => Some("afa"),
+                Some(SubsetFormat::Stockholm) => Some("sto"),
+                None => None,
+            };
+            let mut aligned = extension
+                .map(|extension| {
+                    create_output(
+                        &dir.join(subset_file_name(template, i, hmm, extension)),
+                        compression,
+                    )
+                })
+                .transpose()?;
+            let stockholm = options.subset_format == Some(SubsetFormat::Stockholm);
+            if let (Some(writer), true) = (&mut aligned, stockholm) {
+                writer.write_all(STOCKHOLM_HEADER)?;
+            }
+            let mut ungapped_writer = options
+                .ungapped_subsets
+                .then(|| {
+                    create_output(
+                        &dir.join(subset_file_name(template, i, hmm, "fasta")),
+                        compression,
+                    )
+                })
+                .transpose()?;
+            let (mut buf, mut projected) = (vec![], vec![]);
+            for o in order {
+                let r = &records[lb + o];
+                let mut seq = match packed {
+                    Some(packed) => {
+                        packed.unpack_into(lb + o, &mut buf);
+                        &buf[..]
+                    }
+                    None => &r.seq[..],
+                };
+                if let Some(columns) = &hmm.subset_columns {
+                    projected.clear();
+                    projected.extend(columns.iter().map(|&j| seq[j]));
+                    seq = &projected;
+                }
+                match &mut aligned {
+                    Some(writer) if stockholm => write_stockholm_row(writer, &r.head, seq)?,
+                    Some(writer) => write_fasta(writer, &r.head, seq, options.wrap_width())?,
+                    None => {}
+                }
+                if let Some(writer) = &mut ungapped_writer {
+                    write_fasta(writer, &r.head, &ungapped(seq), options.wrap_width())?;
+                }
+            }
+            if let Some(mut writer) = aligned {
+                if stockholm {
+                    write_stockholm_end(&mut writer, Some(&rf))?;
+                }
+                writer.finish()?;
+            }
+            if let Some(writer) = ungapped_writer {
+                writer.finish()?;
+            }
+            Ok(())
+        })
+}
+
+/// what to do with alignment sequences that are not in the guide tree
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MissingTaxaPolicy {
+    /// refuse to decompose
+    #[default]
+    Error,
+    /// leave them out of every subset, listing them in `unplaced.json`
+    Skip,
+    /// like `skip`, but also write them unaligned to `unplaced.fasta` for adding later
+    AssignByPlacementLater,
+}
 
-pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
-    let n = tree.ntaxa;
-    let mut reordered_taxa = (0..n).collect::<Vec<_>>();
-    let mut taxa_label = FixedBitSet::with_capacity(n); // Taxa ID -> is on the left
+/// what to do with sequences sharing a name, which can only be told apart by position
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DuplicatePolicy {
+    /// refuse to decompose
+    #[default]
+    Error,
+    /// keep the first sequence of each name, dropping the others
+    KeepFirst,
+    /// rename the second sequence of a name to `<name>_2`, the third to `<name>_3`, ...,
+    /// grafting a leaf for each next to that of the name in the guide tree
+    SuffixRename,
+}
+
+/// checks that `records` are all of one length, the length of most of them, dropping
+/// any others if `skip_invalid` and failing with a list of them otherwise; returns
+/// the number dropped. Input that looks unaligned altogether (lengths that vary
+/// without any gaps, or no length shared by half the sequences) always fails
+pub fn validate_geometry(
+    records: &mut Vec<OwnedRecord>,
+    counter: &CharCounter,
+    skip_invalid: bool,
+) -> anyhow::Result<usize> {
+    if records.is_empty() {
+        bail!("the input has no sequences");
+    }
+    let mut counts: AHashMap<usize, usize> = AHashMap::new();
+    for r in records.iter() {
+        *counts.entry(r.seq.len()).or_insert(0) += 1;
+    }
+    // ties go to the length seen first
+    let width = records
+        .iter()
+        .map(|r| r.seq.len())
+        .rev()
+        .max_by_key(|l| counts[l])
+        .unwrap();
+    let gapped = records
+        .iter()
+        .any(|r| r.seq.iter().any(|&c| counter.is_gap(c)));
+    if counts.len() > 1 && (!gapped || counts[&width] * 2 < records.len()) {
+        let (shortest, longest) = counts.keys().minmax().into_option().unwrap();
+        bail!(
+            "the input does not look aligned: its {} sequences have {} distinct lengths \
+             (from {} to {}){}; align them first, e.g. with MAFFT",
+            records.len(),
+            counts.len(),
+            shortest,
+            longest,
+            if gapped { "" } else { " and no gaps" }
+        );
+    }
+    let invalid = records
+        .iter()
+        .filter(|r| r.seq.len() != width)
+        .map(|r| format!("{} ({})", decode_text(&r.head), r.seq.len()))
+        .collect_vec();
+    if invalid.is_empty() {
+        return Ok(0);
+    }
+    if !skip_invalid {
+        bail!(
+            "{} sequences are not {} columns long like the others: {}{}; see --skip-invalid",
+            invalid.len(),
+            width,
+            invalid.iter().take(10).join(", "),
+            if invalid.len() > 10 { ", ..." } else { "" }
+        );
+    }
+    warn!(
+        num_skipped = invalid.len(),
+        width, "skipped sequences not as long as the others"
+    );
+    records.retain(|r| r.seq.len() == width);
+    Ok(invalid.len())
+}
+
+/// keeps the records named in `include` (if given) and not in `exclude`, names being
+/// matched after any renaming; returns the names of the records dropped
+pub fn filter_records(
+    records: &mut Vec<OwnedRecord>,
+    include: Option<&AHashSet<String>>,
+    exclude: Option<&AHashSet<String>>,
+) -> anyhow::Result<AHashSet<String>> {
+    let mut dropped = AHashSet::new();
+    for (list, flag) in [(include, "--include-taxa"), (exclude, "--exclude-taxa")] {
+        if let Some(list) = list {
+            let present: AHashSet<_> = records.iter().map(|r| decode_text(&r.head)).collect();
+            let absent = list
+                .iter()
+                .filter(|&t| !present.contains(t.as_str()))
+                .count();
+            if absent > 0 {
+                warn!(absent, "taxa listed by {} are not in the alignment", flag);
+            }
+        }
+    }
+    records.retain(|r| {
+        let name = decode_text(&r.head);
+        let included = match include {
+            Some(list) => list.contains(name.as_ref()),
+            None => true,
+        };
+        let kept = included && !matches!(exclude, Some(list) if list.contains(name.as_ref()));
+        if !kept {
+            dropped.insert(name.into_owned());
+        }
+        kept
+    });
+    if records.is_empty() {
+        bail!("no sequences left after filtering the taxa");
+    }
+    Ok(dropped)
+}
+
+/// finds the names shared by several of `records` and deals with them as `policy` says
+pub fn resolve_duplicates(
+    records: &mut Vec<OwnedRecord>,
+    policy: DuplicatePolicy,
+) -> anyhow::Result<Option<DuplicateNames>> {
+    let mut counts: AHashMap<&[u8], usize> = AHashMap::new();
+    let mut order = vec![];
+    for r in records.iter() {
+        let count = counts.entry(r.head.as_slice()).or_insert(0);
+        *count += 1;
+        if *count == 2 {
+            order.push(r.head.as_slice());
+        }
+    }
+    if order.is_empty() {
+        return Ok(None);
+    }
+    let names = order
+        .iter()
+        .map(|&h| (decode_text(h).into_owned(), counts[h]))
+        .collect_vec();
+    let mut copies = vec![];
+    let policy_name = match policy {
+        DuplicatePolicy::Error => bail!(
+            "{} sequence names occur more than once, e.g. {} ({} times); see --duplicates",
+            names.len(),
+            names[0].0,
+            names[0].1
+        ),
+        DuplicatePolicy::KeepFirst => {
+            let mut seen = AHashSet::new();
+            records.retain(|r| seen.insert(r.head.clone()));
+            "keep-first"
+        }
+        DuplicatePolicy::SuffixRename => {
+            let mut taken: AHashSet<Vec<u8>> = records.iter().map(|r| r.head.clone()).collect();
+            let mut seen: AHashMap<Vec<u8>, usize> = AHashMap::new();
+            for r in records.iter_mut() {
+                let occurrence = seen.entry(r.head.clone()).or_insert(0);
+                *occurrence += 1;
+                if *occurrence == 1 {
+                    continue;
+                }
+                let mut suffix = *occurrence;
+                let renamed = loop {
+                    let candidate = [r.head.as_slice(), format!("_{}", suffix).as_bytes()].concat();
+                    if !taken.contains(&candidate) {
+                        break candidate;
+                    }
+                    suffix += 1;
+                };
+                taken.insert(renamed.clone());
+                copies.push((
+                    decode_text(&r.head).into_owned(),
+                    decode_text(&renamed).into_owned(),
+                ));
+                r.head = renamed;
+            }
+            "suffix-rename"
+        }
+    };
+    warn!(
+        num_names = names.len(),
+        policy = policy_name,
+        "resolved sequence names occurring more than once"
+    );
+    Ok(Some(DuplicateNames {
+        policy: policy_name.to_string(),
+        names,
+        renamed: copies,
+    }))
+}
+
+/// removes from `records` the sequences that did not end up in `decomp`, returning them
+/// along with the position in `decomp` of every sequence left, which is looked up by
+/// name once here so that sorting by it needs no names
+fn take_unplaced(
+    records: &mut Vec<OwnedRecord>,
+    ts: &TaxonSet,
+    decomp: &TaxaHierarchy,
+) -> anyhow::Result<(Vec<OwnedRecord>, Vec<usize>)> {
+    let mut placed = Vec::with_capacity(records.len());
+    let mut positions = Vec::with_capacity(records.len());
+    let mut unplaced = vec![];
+    for r in records.drain(..) {
+        // names are borrowed from the headers, not copied, unless they are Latin-1
+        let position = ts
+            .to_id
+            .get(decode_text(&r.head).as_ref())
+            .and_then(|&id| decomp.taxa_positions.get(id));
+        match position {
+            Some(&p) if p != usize::MAX => {
+                placed.push(r);
+                positions.push(p);
+            }
+            _ => unplaced.push(r),
+        }
+    }
+    *records = placed;
+    Ok((unplaced, positions))
+}
+
+/// removes from `records` the fragmentary (or overlong) sequences, those whose
+/// length is not within `fraction` of the median length, returning them
+pub fn split_fragments(
+    records: &mut Vec<OwnedRecord>,
+    counter: &CharCounter,
+    fraction: f64,
+) -> Vec<OwnedRecord> {
+    let mut lengths = records.iter().map(|r| counter.count(&r.seq)).collect_vec();
+    lengths.sort_unstable();
+    let median = lengths[lengths.len() / 2] as f64;
+    let (lower, upper) = (median * (1.0 - fraction), median * (1.0 + fraction));
+    let (backbone, fragments): (Vec<_>, Vec<_>) = records.drain(..).partition(|r| {
+        let length = counter.count(&r.seq) as f64;
+        lower <= length && length <= upper
+    });
+    *records = backbone;
+    fragments
+}
+
+/// writes `queries` with their gaps removed to `queries.fasta` in `outdir`, wrapped
+/// at `width` (see `write_fasta`)
+fn write_queries(
+    queries: &[OwnedRecord],
+    width: Option<usize>,
+    outdir: &Path,
+) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(outdir.join("queries.fasta"))?);
+    for r in queries {
+        write_fasta(&mut writer, &r.head, &ungapped(&r.seq), width)?;
+    }
+    Ok(())
+}
+
+fn write_unplaced(
+    unplaced: &[OwnedRecord],
+    policy: MissingTaxaPolicy,
+    width: Option<usize>,
+    outdir: &Path,
+) -> anyhow::Result<()> {
+    let names = unplaced
+        .iter()
+        .map(|r| decode_text(&r.head).into_owned())
+        .collect_vec();
+    warn!(
+        num_unplaced = names.len(),
+        ?policy,
+        "left sequences missing from the guide tree out of the decomposition"
+    );
+    serde_json::to_writer(
+        BufWriter::new(File::create(outdir.join("unplaced.json"))?),
+        &names,
+    )?;
+    if policy == MissingTaxaPolicy::AssignByPlacementLater {
+        let mut writer = BufWriter::new(File::create(outdir.join("unplaced.fasta"))?);
+        for r in unplaced {
+            write_fasta(&mut writer, &r.head, &ungapped(&r.seq), width)?;
+        }
+    }
+    Ok(())
+}
+
+/// drops the leaves of taxa without a sequence in `records` from every tree,
+/// returning the names of the dropped taxa; those of `filtered` (left out on
+/// purpose) are not reported as missing
+pub fn prune_to_records(
+    collection: &mut TreeCollection,
+    records: &[OwnedRecord],
+    filtered: &AHashSet<String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut present = FixedBitSet::with_capacity(collection.taxon_set.len());
+    for r in records {
+        let taxon_name = decode_text(&r.head).into_owned();
+        if let Some(&id) = collection.taxon_set.to_id.get(&taxon_name) {
+            present.insert(id);
+        }
+    }
+    let absent: AHashSet<usize> = (0..collection.taxon_set.len())
+        .filter(|&t| !present.contains(t))
+        .collect();
+    if absent.is_empty() {
+        return Ok(vec![]);
+    }
+    if present.count_ones(..) == 0 {
+        bail!("no taxon of the guide tree names a sequence; see --header-id for taking names from headers");
+    }
+    for i in 0..collection.trees.len() {
+        let pruned = prune_taxa(&collection.trees[i], &mut collection.taxon_set, &absent);
+        collection.trees[i] = pruned;
+    }
+    let mut dropped = absent
+        .iter()
+        .map(|&t| collection.taxon_set.names[t].clone())
+        .collect_vec();
+    dropped.sort_unstable();
+    let (filtered, missing): (Vec<_>, Vec<_>) =
+        dropped.iter().partition(|&name| filtered.contains(name));
+    if !filtered.is_empty() {
+        info!(
+            num_filtered = filtered.len(),
+            "pruned guide tree leaves of the taxa filtered out"
+        );
+    }
+    if !missing.is_empty() {
+        warn!(
+            num_dropped = missing.len(),
+            dropped = ?missing.iter().take(10).collect_vec(),
+            "pruned guide tree leaves without a sequence in the alignment"
+        );
+    }
+    Ok(dropped)
+}
+
+/// `name` with case, surrounding blanks and the difference between blanks and
+/// underscores ignored
+fn fuzzy_key(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|t| !t.is_empty())
+        .join("_")
+}
+
+/// reports the guide tree leaves and sequences that did not match by name but would
+/// by `fuzzy_key`, and if `fuzzy` renames every leaf with a single such sequence
+/// after it; returns the number of leaves renamed
+pub fn match_near_misses(
+    collection: &mut TreeCollection,
+    records: &[OwnedRecord],
+    fuzzy: bool,
+) -> anyhow::Result<usize> {
+    let ts = &collection.taxon_set;
+    let mut unmatched: AHashMap<String, Vec<String>> = AHashMap::new();
+    for r in records {
+        let name = decode_text(&r.head);
+        if !ts.to_id.contains_key(name.as_ref()) {
+            unmatched
+                .entry(fuzzy_key(&name))
+                .or_default()
+                .push(name.into_owned());
+        }
+    }
+    if unmatched.is_empty() {
+        return Ok(0);
+    }
+    let names: AHashSet<&[u8]> = records.iter().map(|r| r.head.as_slice()).collect();
+    let mut near_misses = vec![];
+    let mut table = AHashMap::new();
+    for leaf in &ts.names {
+        if names.contains(leaf.as_bytes()) {
+            continue;
+        }
+        if let Some(candidates) = unmatched.get(&fuzzy_key(leaf)) {
+            near_misses.push(format!("{} ~ {}", leaf, candidates.join(" | ")));
+            if let [only] = candidates.as_slice() {
+                table.insert(leaf.clone(), only.clone());
+            }
+        }
+    }
+    if near_misses.is_empty() {
+        return Ok(0);
+    }
+    near_misses.sort_unstable();
+    if !fuzzy {
+        warn!(
+            num_near_misses = near_misses.len(),
+            near_misses = ?near_misses.iter().take(10).collect_vec(),
+            "guide tree leaves almost match sequence names; --fuzzy-match matches them up"
+        );
+        return Ok(0);
+    }
+    let renamed = rename_taxa(&mut collection.taxon_set, &table)?;
+    info!(
+        renamed,
+        near_misses = ?near_misses.iter().take(10).collect_vec(),
+        "matched guide tree leaves to sequences by similar names"
+    );
+    Ok(renamed)
+}
+
+/// errors out unless every range of `decomp` is exactly the taxa below some node of `tree`
+pub fn check_monophyly(tree: &Tree, decomp: &TaxaHierarchy) -> anyhow::Result<()> {
+    let leaves = leaf_nodes(tree, decomp.taxa_positions.len());
+    let mut clade_sizes = vec![0usize; tree.num_nodes()];
+    for v in tree.postorder() {
+        clade_sizes[v] = if tree.is_leaf(v) {
+            1
+        } else {
+            tree.children(v).map(|c| clade_sizes[c]).sum()
+        };
+    }
+    for (i, &(lb, ub)) in decomp.decomposition_ranges.iter().enumerate() {
+        let nodes = decomp.reordered_taxa[lb..ub]
+            .iter()
+            .map(|&t| leaves[t].expect("decomposed taxon missing from the tree"))
+            .collect_vec();
+        let mrca = tree_mrca(tree, &nodes).expect("ranges are never empty");
+        if clade_sizes[mrca] != ub - lb {
+            bail!(
+                "subset {} ({} taxa) is not a clade of the guide tree, its MRCA spans {} taxa",
+                i,
+                ub - lb,
+                clade_sizes[mrca]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// a label for each range of `decomp` naming its MRCA in `tree` by two of its
+/// taxa from different sides of that MRCA, or the taxon itself for singletons
+pub fn clade_labels(tree: &Tree, decomp: &TaxaHierarchy, ts: &TaxonSet) -> Vec<Option<String>> {
+    let leaves = leaf_nodes(tree, decomp.taxa_positions.len());
+    decomp
+        .decomposition_ranges
+        .iter()
+        .map(|&range| {
+            if decomp.outliers == Some(range) {
+                return Some("outliers".to_string());
+            }
+            let (lb, ub) = range;
+            let taxa = &decomp.reordered_taxa[lb..ub];
+            let nodes = taxa
+                .iter()
+                .map(|&t| leaves[t].expect("decomposed taxon missing from the tree"))
+                .collect_vec();
+            let mrca = tree_mrca(tree, &nodes).expect("ranges are never empty");
+            // the alphabetically first member below each child of the MRCA
+            let mut representatives: AHashMap<usize, &str> = AHashMap::new();
+            for (&t, &v) in taxa.iter().zip(&nodes) {
+                let side = std::iter::once(v)
+                    .chain(tree.ancestors(v))
+                    .take_while(|&a| a != mrca)
+                    .last()
+                    .unwrap_or(v);
+                let name = ts.names[t].as_str();
+                let e = representatives.entry(side).or_insert(name);
+                if name < *e {
+                    *e = name;
+                }
+            }
+            let mut names = representatives.values().copied().collect_vec();
+            names.sort_unstable();
+            Some(match names.as_slice() {
+                [only] => only.to_string(),
+                [a, b, ..] => format!("mrca({},{})", a, b),
+                [] => unreachable!("ranges are never empty"),
+            })
+        })
+        .collect()
+}
+
+/// the reordered positions of the `k` taxa nearest to each range of `decomp`
+pub fn padding_rows(tree: &Tree, decomp: &TaxaHierarchy, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]; decomp.decomposition_ranges.len()];
+    }
+    let leaves = leaf_nodes(tree, decomp.taxa_positions.len());
+    let editable = EditableTree::from_tree(tree);
+    decomp
+        .decomposition_ranges
+        .par_iter()
+        .map(|&(lb, ub)| {
+            // taxa kept out of the tree (e.g. quarantined ones) get no padding
+            let sources = decomp.reordered_taxa[lb..ub]
+                .iter()
+                .filter_map(|&t| leaves.get(t).copied().flatten())
+                .collect_vec();
+            if sources.is_empty() {
+                return vec![];
+            }
+            editable
+                .nearest_leaves(&sources, k)
+                .into_iter()
+                .map(|v| decomp.taxa_positions[editable.taxa[v] as usize])
+                .collect()
+        })
+        .collect()
+}
+
+/// splits `tree` top-down, first applying the forced cuts of `constraints`
+/// (in order) and then letting `strategy` pick the remaining cuts, balancing
+/// cuts by `taxon_weights` (indexed by taxon id) if given; the largest subset is
+/// split next, ties going to the leftmost one, so the output is fully determined
+/// by the inputs and options
+pub fn hierarchical_decomp(
+    tree: &Tree,
+    strategy: &dyn DecompositionStrategy,
+    options: &DecompositionOptions,
+    constraints: &DecompositionConstraints,
+    taxon_weights: Option<&[u64]>,
+) -> TaxaHierarchy {
+    // the taxa present in the tree, which need not be every taxon of its taxon set
+    let mut reordered_taxa = tree
+        .postorder()
+        .filter(|&v| tree.is_leaf(v))
+        .map(|v| tree.taxa[v] as usize)
+        .collect_vec();
+    reordered_taxa.sort_unstable();
+    let n = reordered_taxa.len();
+    let id_bound = reordered_taxa.last().map_or(0, |&t| t + 1);
+    let mut taxa_label = FixedBitSet::with_capacity(id_bound); // Taxa ID -> is on the left
     let mut pq = BinaryHeap::new();
     let mut cuts = AHashSet::new();
     let mut decomposition_ranges: Vec<(usize, usize)> = Vec::new();
     cuts.insert(0usize);
-    pq.push((tree.ntaxa, (0usize, tree.ntaxa), 0usize));
     let mut tree_sizes = vec![0u64; tree.taxa.len()];
+    let mut tree_weights = vec![0u64; tree.taxa.len()];
     for i in tree.postorder() {
         if tree.is_leaf(i) {
             tree_sizes[i] = 1;
+            tree_weights[i] = taxon_weights.map_or(1, |w| w[tree.taxa[i] as usize]);
         } else {
             tree.children(i).for_each(|c| {
                 tree_sizes[i] += tree_sizes[c];
+                tree_weights[i] += tree_weights[c];
             });
         }
     }
-    decomposition_ranges.push((0usize, tree.ntaxa));
-    while let Some((size, (lb, ub), root)) = pq.pop() {
+    pq.push((n, Reverse((0usize, n)), 0usize, tree_weights[0]));
+    decomposition_ranges.push((0usize, n));
+    // leaf-level subsets that will not be split any further
+    let mut disjoint_ranges: Vec<(usize, usize)> = Vec::new();
+    let mut splits: Vec<(usize, usize, usize)> = Vec::new();
+    // components without a forced cut wait while any other may still have one, so
+    // that every forced cut is applied before any stop test or automatic cut
+    let mut forcing = !constraints.forced_cuts.is_empty();
+    let mut deferred = vec![];
+    while let Some((size, Reverse((lb, ub)), root, weight)) = pq.pop().or_else(|| {
+        forcing = false;
+        pq.extend(deferred.drain(..));
+        pq.pop()
+    }) {
         assert_eq!(size, ub - lb);
-        if size <= max_size {
-            break;
-        }
-        let it = PostorderIterator::from_node_excluding(tree, root, &cuts);
-        let mut best_inbalance = u64::MAX;
-        let mut best_cut = 0usize;
-        let mut non_leaf = false;
-        for i in it {
-            if i == root {
-                continue;
+        // a forced cut belongs to this component if the first cut above it is its root
+        let forced = constraints.forced_cuts.iter().copied().find(|&f| {
+            !cuts.contains(&f)
+                && tree.ancestors(f).find(|a| cuts.contains(a)) == Some(root)
+                && tree_sizes[f] > 0
+                && (tree_sizes[f] as usize) < size
+        });
+        let best_cut = if let Some(f) = forced {
+            f
+        } else if forcing {
+            deferred.push((size, Reverse((lb, ub)), root, weight));
+            continue;
+        } else {
+            if options.should_stop(size, pq.len() + 1 + disjoint_ranges.len(), n) {
+                disjoint_ranges.push((lb, ub));
+                break;
             }
-            if tree.is_leaf(i) {
-            } else {
-                non_leaf = true;
-                let inbalance = (size as u64 - tree_sizes[i]).abs_diff(tree_sizes[i]);
-                if inbalance < best_inbalance {
-                    best_inbalance = inbalance;
-                    best_cut = i;
+            let component = Component::new(
+                tree,
+                root,
+                size,
+                &tree_sizes,
+                weight,
+                &tree_weights,
+                &cuts,
+                options,
+                constraints,
+            );
+            match options.choose_cut(strategy, &component) {
+                Some(c) => c,
+                None => {
+                    disjoint_ranges.push((lb, ub));
+                    continue;
                 }
             }
-        } // finding the best cut
-        if non_leaf {
-            assert_ne!(best_inbalance, u64::MAX, "No cut found");
-        } else {
-            continue;
-        }
+        };
         for a in tree.ancestors(best_cut) {
             if a == root {
                 break;
             }
             tree_sizes[a] -= tree_sizes[best_cut];
+            tree_weights[a] -= tree_weights[best_cut];
         }
         cuts.insert(best_cut);
+        splits.push((lb, lb + tree_sizes[best_cut] as usize, ub));
         for u in tree.postorder_from(best_cut) {
             if tree.is_leaf(u) {
                 let tid = tree.taxa[u] as usize;
@@ -80,7 +1139,8 @@ pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
             }
         }
         let view = &mut reordered_taxa[lb..ub];
-        view.sort_unstable_by_key(|e| !taxa_label[*e]);
+        // stable, so the order within each side does not depend on the sort implementation
+        view.sort_by_key(|e| !taxa_label[*e]);
         taxa_label.clear();
         if tree_sizes[best_cut] >= 2 {
             decomposition_ranges.push((lb, lb + tree_sizes[best_cut] as usize));
@@ -90,67 +1150,1018 @@ pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
         }
         pq.push((
             tree_sizes[best_cut] as usize,
-            (lb, lb + tree_sizes[best_cut] as usize),
+            Reverse((lb, lb + tree_sizes[best_cut] as usize)),
             best_cut,
+            tree_weights[best_cut],
         ));
         pq.push((
             size - tree_sizes[best_cut] as usize,
-            (lb + tree_sizes[best_cut] as usize, ub),
+            Reverse((lb + tree_sizes[best_cut] as usize, ub)),
             root,
+            weight - tree_weights[best_cut],
         ));
     }
-    let mut taxa_positions: Vec<usize> = vec![0; n];
+    disjoint_ranges.extend(pq.into_iter().map(|(_, Reverse(range), _, _)| range));
+    finish_hierarchy(
+        reordered_taxa,
+        id_bound,
+        decomposition_ranges,
+        disjoint_ranges,
+        splits,
+        options,
+    )
+}
+
+/// assembles the result of a top-down decomposition of taxa with ids below `id_bound`,
+/// applying the output options (disjoint subsets only, ensemble levels) of `options`
+pub fn finish_hierarchy(
+    reordered_taxa: Vec<usize>,
+    id_bound: usize,
+    mut decomposition_ranges: Vec<(usize, usize)>,
+    mut disjoint_ranges: Vec<(usize, usize)>,
+    splits: Vec<(usize, usize, usize)>,
+    options: &DecompositionOptions,
+) -> TaxaHierarchy {
+    let n = reordered_taxa.len();
+    disjoint_ranges.sort_unstable();
+    if options.disjoint {
+        decomposition_ranges = disjoint_ranges.clone();
+    }
+    let mut taxa_positions: Vec<usize> = vec![usize::MAX; id_bound];
     for (p, t) in reordered_taxa.iter().enumerate() {
         taxa_positions[*t] = p;
     }
+    let range_levels = vec![vec![]; decomposition_ranges.len()];
 
-    TaxaHierarchy {
+    let mut hierarchy = TaxaHierarchy {
         reordered_taxa,
         taxa_positions,
         decomposition_ranges,
+        disjoint_ranges,
+        splits,
+        range_levels,
+        outliers: None,
+    };
+    if !options.levels.is_empty() {
+        let levels = options.levels.iter().map(|l| l.resolve(n)).collect_vec();
+        hierarchy.use_ensemble_levels(&levels);
+    }
+    hierarchy
+}
+
+/// renames the sequences whose names cannot be Newick labels as they are
+/// to their `newick_label`, which is how they appear in cleaned trees
+fn label_records(records: &mut [OwnedRecord]) {
+    let mut renamed = 0;
+    for r in records.iter_mut() {
+        let name = decode_text(&r.head);
+        let label = newick_label(&name);
+        if label != name {
+            r.head = encode_text(&label, is_latin1(&r.head));
+            renamed += 1;
+        }
+    }
+    if renamed > 0 {
+        warn!(
+            renamed,
+            "replaced blanks and Newick punctuation in sequence names by underscores"
+        );
+    }
+}
+
+/// decomposes along the (preprocessed) guide tree from `tree`, failing that the
+/// `embedded` trees read along with the alignment, or one estimated from `records`
+fn tree_decomp(
+    records: &[OwnedRecord],
+    tree: Option<&PathBuf>,
+    embedded: &[String],
+    names: NameChanges,
+    counter: &CharCounter,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+) -> anyhow::Result<(TreeCollection, TaxaHierarchy)> {
+    let mut collection = match tree {
+        Some(tree) => read_trees(tree)?,
+        None if options.no_tree => {
+            estimate_guide_tree(records, options.kmer_size.unwrap_or(DEFAULT_KMER_SIZE))?
+        }
+        None if !embedded.is_empty() => {
+            info!(num_trees = embedded.len(), "using the trees of the input");
+            parse_trees(&embedded.concat())?
+        }
+        None => {
+            bail!("no guide tree given (--tree) or found in the input; --no-tree estimates one")
+        }
+    };
+    // an estimated tree is named after the already renamed sequences
+    if let Some(table) = names.rename.filter(|_| !options.no_tree) {
+        let renamed = rename_taxa(&mut collection.taxon_set, table)?;
+        info!(renamed, "renamed guide tree leaves");
+    }
+    if !options.no_tree {
+        match_near_misses(&mut collection, records, options.fuzzy_match)?;
+    }
+    if !options.no_tree && !names.copies.is_empty() {
+        // the decomposition is of the first tree
+        let mut grafted = 0;
+        for i in 0..collection.trees.len() {
+            let (tree, num_grafted) = graft_copies(
+                &collection.trees[i],
+                &mut collection.taxon_set,
+                names.copies,
+            );
+            collection.trees[i] = tree;
+            if i == 0 {
+                grafted = num_grafted;
+            }
+        }
+        info!(
+            grafted,
+            "grafted renamed sequences next to the leaves they were named after"
+        );
+    }
+    prune_to_records(&mut collection, records, names.filtered)?;
+    prepare_guide_tree(&mut collection, options)?;
+    let decomp_options = &options.decomposition;
+    if let Some(max_size) = decomp_options.max_size_for(collection.trees[0].ntaxa) {
+        info!(max_size, "resolved subset size bound");
     }
+    let mut quarantined = vec![];
+    if let Some(factor) = options.quarantine {
+        quarantined = long_branch_taxa(&collection.trees[0], factor);
+        if !quarantined.is_empty() {
+            let pruned = prune_taxa(
+                &collection.trees[0],
+                &mut collection.taxon_set,
+                &quarantined.iter().copied().collect(),
+            );
+            collection.trees[0] = pruned;
+        }
+        info!(
+            num_quarantined = quarantined.len(),
+            "quarantined long-branch taxa"
+        );
+    }
+    let constraints = DecompositionConstraints::load(
+        decomp_options,
+        &collection.trees[0],
+        &collection.taxon_set,
+    )?;
+    let ts = &collection.taxon_set;
+    let taxon_weights = match decomp_options.balance_by {
+        BalanceWeight::Taxa => None,
+        BalanceWeight::Residues => {
+            let mut weights = vec![0u64; ts.len()];
+            for r in records.iter() {
+                let taxon_name = decode_text(&r.head).into_owned();
+                if let Some(&id) = ts.to_id.get(&taxon_name) {
+                    weights[id] = counter.count(&r.seq) as u64;
+                }
+            }
+            Some(weights)
+        }
+    };
+    let mut decomp = hierarchical_decomp(
+        &collection.trees[0],
+        strategy,
+        decomp_options,
+        &constraints,
+        taxon_weights.as_deref(),
+    );
+    if options.strict_monophyly {
+        check_monophyly(&collection.trees[0], &decomp)?;
+    }
+    if !quarantined.is_empty() {
+        decomp.append_outliers(&quarantined);
+    }
+    Ok((collection, decomp))
+}
+
+/// an input alignment read and cleaned up as the melt options say, ready for decomposing
+#[derive(Clone)]
+pub struct PreparedAlignment {
+    pub alignment: Alignment,
+    pub rename: Option<AHashMap<String, String>>,
+    pub duplicates: Option<DuplicateNames>,
+    /// the names of the sequences left out by `--include-taxa` and `--exclude-taxa`
+    pub filtered: AHashSet<String>,
+    pub counter: CharCounter,
+    /// the `checksum` of the input file, if it is a local one
+    pub checksum: Option<String>,
+    /// the loci of the alignment, if it is melted as a concatenated one
+    pub loci: Vec<Locus>,
+    /// the header in the input of every sequence named otherwise, by its name
+    pub sources: AHashMap<String, String>,
+    /// the headers of the records of the input left out
+    pub left_out: Vec<String>,
+}
+
+/// runs `step` on `records`, which it may only leave some of out (keeping the others
+/// as they are, in order), moving the `sources` (input headers) of those left out
+/// to `left_out`
+fn track_left_out<T>(
+    records: &mut Vec<OwnedRecord>,
+    sources: &mut Vec<String>,
+    left_out: &mut Vec<String>,
+    step: impl FnOnce(&mut Vec<OwnedRecord>) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let before = records
+        .iter()
+        .map(|r| (r.head.clone(), r.seq.len()))
+        .collect_vec();
+    let result = step(records)?;
+    if records.len() < before.len() {
+        let mut kept = records.iter().peekable();
+        for (source, (head, len)) in std::mem::take(sources).into_iter().zip(before) {
+            match kept.peek() {
+                Some(r) if r.head == head && r.seq.len() == len => {
+                    kept.next();
+                    sources.push(source);
+                }
+                _ => left_out.push(source),
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// how the names of the sequences came to differ from those of the guide tree leaves
+#[derive(Debug, Clone, Copy)]
+struct NameChanges<'a> {
+    /// the table of `--rename`
+    rename: Option<&'a AHashMap<String, String>>,
+    /// the sequences renamed for sharing a name, as in `DuplicateNames::renamed`
+    copies: &'a [(String, String)],
+    /// the names of the sequences filtered out, as in `PreparedAlignment::filtered`
+    filtered: &'a AHashSet<String>,
+}
+
+/// reads the alignment at `input`, checking it and settling its sequence names
+pub fn prepare_alignment(input: &Path, options: &MeltOptions) -> anyhow::Result<PreparedAlignment> {
+    let mut alignment = read_alignment(input)?;
+    let records = &mut alignment.records;
+    let counter = CharCounter::new(&options.characters);
+    // the header in the input of every record left
+    let mut sources = records
+        .iter()
+        .map(|r| decode_text(&r.head).into_owned())
+        .collect_vec();
+    let mut left_out = vec![];
+    track_left_out(records, &mut sources, &mut left_out, |records| {
+        validate_geometry(records, &counter, options.skip_invalid)
+    })?;
+    let truncated = options.header_id.apply(records)?;
+    if truncated > 0 {
+        info!(truncated, "truncated sequence headers to taxon names");
+    }
+    let rename = options.rename.as_ref().map(read_rename_table).transpose()?;
+    if let Some(table) = &rename {
+        let renamed = rename_records(records, table);
+        info!(renamed, "renamed sequences");
+    }
+    let include = options
+        .include_taxa
+        .as_ref()
+        .map(read_taxon_list)
+        .transpose()?;
+    let exclude = options
+        .exclude_taxa
+        .as_ref()
+        .map(read_taxon_list)
+        .transpose()?;
+    let mut filtered = AHashSet::new();
+    if include.is_some() || exclude.is_some() {
+        filtered = track_left_out(records, &mut sources, &mut left_out, |records| {
+            filter_records(records, include.as_ref(), exclude.as_ref())
+        })?;
+        info!(dropped = filtered.len(), "filtered taxa");
+    }
+    // trees need names to be valid labels, which can make distinct names collide
+    if !options.cluster {
+        label_records(records);
+    }
+    let duplicates = track_left_out(records, &mut sources, &mut left_out, |records| {
+        resolve_duplicates(records, options.duplicates)
+    })?;
+    counter.validate(records)?;
+    counter.normalize_gaps(records);
+    let sources = records
+        .iter()
+        .zip(sources)
+        .filter(|(r, source)| decode_text(&r.head) != source.as_str())
+        .map(|(r, source)| (decode_text(&r.head).into_owned(), source))
+        .collect();
+    Ok(PreparedAlignment {
+        alignment,
+        rename,
+        duplicates,
+        filtered,
+        counter,
+        checksum: input_checksum(input)?,
+        loci: vec![],
+        sources,
+        left_out,
+    })
+}
+
+/// seconds since the Unix epoch
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// writes `run.json` into `outdir`, recording the version and command line the melt
+/// started at `started` was run with and the checksums of its input files
+pub fn write_provenance(
+    outdir: &Path,
+    inputs: &[&Path],
+    options: &MeltOptions,
+    started: SystemTime,
+) -> anyhow::Result<Provenance> {
+    let optional_inputs = [
+        &options.rename,
+        &options.include_taxa,
+        &options.exclude_taxa,
+        &options.decomposition.forced_cuts,
+        &options.decomposition.keep_together,
+    ];
+    let mut checksums = BTreeMap::new();
+    for path in inputs
+        .iter()
+        .copied()
+        .chain(optional_inputs.into_iter().flatten().map(PathBuf::as_path))
+    {
+        // remote inputs are not checksummed
+        if let Some(sum) = input_checksum(path)? {
+            checksums.insert(path.display().to_string(), sum);
+        }
+    }
+    let provenance = Provenance {
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: std::env::args().collect(),
+        inputs: checksums,
+        characters: options.characters.clone(),
+        started_at: unix_time(started),
+        finished_at: unix_time(SystemTime::now()),
+    };
+    let mut writer = BufWriter::new(File::create(outdir.join("run.json"))?);
+    serde_json::to_writer_pretty(&mut writer, &provenance)?;
+    writer.flush()?;
+    Ok(provenance)
 }
 
 pub fn oneshot_melt(
     input: &PathBuf,
-    tree: &PathBuf,
-    max_size: usize,
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
     outdir: &PathBuf,
 ) -> anyhow::Result<CrucibleCtxt> {
-    let collection = TreeCollection::from_newick(tree).expect("Failed to read tree");
-    let decomp = hierarchical_decomp(&collection.trees[0], max_size);
+    let started = SystemTime::now();
+    check_outdir(outdir, options.force)?;
+    let prepared = prepare_alignment(input, options)?;
+    write_atomically(outdir, |staging| {
+        let ctxt = melt_prepared(prepared, tree, strategy, options, staging)?;
+        let inputs = std::iter::once(input).chain(tree).map(PathBuf::as_path);
+        write_provenance(staging, &inputs.collect_vec(), options, started)?;
+        Ok(ctxt)
+    })
+}
+
+/// decomposes the guide tree `tree` alone, its leaves standing in for the sequences
+/// (renamed and filtered as `options` say), for the modes that do not read the
+/// alignment before decomposing (named `mode` in errors)
+pub fn tree_only_decomp(
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+    mode: &str,
+) -> anyhow::Result<(TreeCollection, TaxaHierarchy)> {
+    let tree = match tree {
+        Some(tree) => tree,
+        None => bail!(
+            "{} needs a guide tree (--tree), as it does not read the alignment first",
+            mode
+        ),
+    };
+    if options.decomposition.balance_by == BalanceWeight::Residues {
+        bail!(
+            "{} cannot balance by residues, as it does not read the alignment first",
+            mode
+        );
+    }
+    let rename = options.rename.as_ref().map(read_rename_table).transpose()?;
+    let mut collection = read_trees(tree)?;
+    if let Some(table) = &rename {
+        rename_taxa(&mut collection.taxon_set, table)?;
+    }
+    let mut records = collection
+        .taxon_set
+        .names
+        .iter()
+        .map(|name| OwnedRecord {
+            head: encode_text(name, false),
+            seq: vec![],
+        })
+        .collect_vec();
+    let include = options
+        .include_taxa
+        .as_ref()
+        .map(read_taxon_list)
+        .transpose()?;
+    let exclude = options
+        .exclude_taxa
+        .as_ref()
+        .map(read_taxon_list)
+        .transpose()?;
+    let filtered = filter_records(&mut records, include.as_ref(), exclude.as_ref())?;
+    let counter = CharCounter::new(&options.characters);
+    tree_decomp(
+        &records,
+        Some(tree),
+        &[],
+        NameChanges {
+            rename: rename.as_ref(),
+            copies: &[],
+            filtered: &filtered,
+        },
+        &counter,
+        strategy,
+        options,
+    )
+}
+
+/// fails for the alphabets and melt options that need the sequences unpacked
+fn check_packable(options: &MeltOptions, alphabet: Alphabet) -> anyhow::Result<()> {
+    if alphabet == Alphabet::Protein {
+        bail!("--packed packs nucleotide sequences only");
+    }
+    let unsupported = [
+        ("--padding", options.padding > 0),
+        ("--trim-subsets", options.trim_subsets),
+        ("--profiles", options.profiles),
+        ("--subset-consensus", options.subset_consensus),
+        ("--residue-classes", options.residue_classes.is_some()),
+        ("--sequence-weights", options.sequence_weights.is_some()),
+        ("--subset-quality", options.subset_quality),
+        ("--column-block", options.column_block.is_some()),
+    ];
+    if let Some((flag, _)) = unsupported.iter().find(|&&(_, set)| set) {
+        bail!("--packed does not support {}", flag);
+    }
+    Ok(())
+}
+
+/// plans the melt of the alignment at `input` by decomposing the guide tree `tree`
+/// alone, without reading the alignment: the sequences are taken to be the leaves of
+/// the tree, and the bytes of each subset estimated as its share of the input file
+/// (not estimated for standard input or remote inputs, whose sizes are unknown)
+pub fn dry_run_melt(
+    input: &Path,
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+) -> anyhow::Result<DecompositionPlan> {
+    let (collection, decomp) = tree_only_decomp(tree, strategy, options, "--dry-run")?;
+    let ntaxa = decomp.reordered_taxa.len();
+    let labels = clade_labels(&collection.trees[0], &decomp, &collection.taxon_set);
+    let input_bytes = if input == Path::new("-") || is_remote(input) {
+        None
+    } else {
+        Some(std::fs::metadata(input)?.len())
+    };
+    let share = |size: usize| {
+        input_bytes.map(|bytes| (bytes as f64 * size as f64 / ntaxa.max(1) as f64) as u64)
+    };
+    let subsets = decomp
+        .decomposition_ranges
+        .iter()
+        .zip(labels)
+        .map(|(&(lb, ub), label)| PlannedSubset {
+            size: ub - lb,
+            label,
+            outlier: decomp.outliers == Some((lb, ub)),
+            estimated_bytes: share(ub - lb),
+        })
+        .collect_vec();
+    Ok(DecompositionPlan {
+        num_taxa: ntaxa,
+        max_size: options.decomposition.max_size_for(ntaxa),
+        estimated_bytes: input_bytes.map(|_| subsets.iter().flat_map(|s| s.estimated_bytes).sum()),
+        subsets,
+    })
+}
+
+/// melts the alignment at `input` as a whole, recording the loci `partitions` (a
+/// RAxML or Nexus partition file) delimits in it in the metadata
+pub fn concatenated_melt(
+    input: &Path,
+    partitions: &Path,
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+    outdir: &Path,
+) -> anyhow::Result<CrucibleCtxt> {
+    let started = SystemTime::now();
+    check_outdir(outdir, options.force)?;
+    let mut prepared = prepare_alignment(input, options)?;
+    let width = prepared.alignment.records[0].seq.len();
+    prepared.loci = read_partitions(partitions)?
+        .iter()
+        .map(|partition| {
+            Ok(Locus {
+                name: partition.name.clone(),
+                columns: partition.columns(width)?,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    write_atomically(outdir, |staging| {
+        let ctxt = melt_prepared(prepared, tree, strategy, options, staging)?;
+        let inputs = [input, partitions]
+            .into_iter()
+            .chain(tree.map(PathBuf::as_path));
+        write_provenance(staging, &inputs.collect_vec(), options, started)?;
+        Ok(ctxt)
+    })
+}
+
+/// melts each locus of the alignment at `input`, as `partitions` (a RAxML or Nexus
+/// partition file) delimits them, into a directory of its own under `outdir`; the
+/// sequences without any character in a locus are left out of it
+pub fn partitioned_melt(
+    input: &Path,
+    partitions: &Path,
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+    outdir: &Path,
+) -> anyhow::Result<PartitionedCtxt> {
+    let started = SystemTime::now();
+    check_outdir(outdir, options.force)?;
+    let prepared = prepare_alignment(input, options)?;
+    let partition_file = partitions;
+    let partitions = read_partitions(partitions)?;
+    // the directory of every locus is staged along with it
+    write_atomically(outdir, |outdir| {
+        let width = prepared.alignment.records[0].seq.len();
+        let mut loci = vec![];
+        let mut covered = vec![false; width];
+        for partition in &partitions {
+            let columns = partition.columns(width)?;
+            for &j in &columns {
+                covered[j] = true;
+            }
+            let mut locus = prepared.clone();
+            let slice = |seq: &[u8]| columns.iter().map(|&j| seq[j]).collect_vec();
+            let counter = &prepared.counter;
+            locus.alignment.records = prepared
+                .alignment
+                .records
+                .iter()
+                .filter(|r| columns.iter().any(|&j| counter.counts(r.seq[j])))
+                .map(|r| OwnedRecord {
+                    head: r.head.clone(),
+                    seq: slice(&r.seq),
+                })
+                .collect();
+            locus.alignment.reference = prepared.alignment.reference.as_deref().map(slice);
+            let left_out = prepared.alignment.records.len() - locus.alignment.records.len();
+            info!(
+                locus = partition.name.as_str(),
+                num_columns = columns.len(),
+                left_out,
+                "melting locus"
+            );
+            let name = partition.dir_name();
+            let ctxt = melt_prepared(locus, tree, strategy, options, &outdir.join(&name))?;
+            loci.push(LocusCtxt {
+                name,
+                columns,
+                ctxt,
+            });
+        }
+        let uncovered = covered.iter().filter(|&&c| !c).count();
+        if uncovered > 0 {
+            warn!(uncovered, "left columns in no partition out");
+        }
+        let ctxt = PartitionedCtxt::new(loci);
+        write_metadata(&ctxt, outdir, options.metadata_format)?;
+        let inputs = [input, partition_file]
+            .into_iter()
+            .chain(tree.map(PathBuf::as_path));
+        write_provenance(outdir, &inputs.collect_vec(), options, started)?;
+        Ok(ctxt)
+    })
+}
+
+/// prefix sums over the rows of `records` of the characters `add` counts into a row
+/// (from a sequence) in each of `columns`, laid out row-major as `CrucibleCtxt::retrieve_nchars_noalloc` reads two
+/// rows at a time; blocks of rows are summed in parallel from zero, then shifted by
+/// the totals of the blocks before them
+fn column_prefix_sums(
+    records: &[OwnedRecord],
+    columns: Range<usize>,
+    add: impl Fn(&mut [u32], &[u8]) + Sync,
+) -> Array2<u32> {
+    let (n, k) = (records.len(), columns.len());
+    let mut prefix = Array2::<u32>::zeros((n + 1, k));
+    if n == 0 || k == 0 {
+        return prefix;
+    }
+    let block_rows = n.div_ceil(rayon::current_num_threads());
+    // the first row, of no records, stays zero
+    let rows = &mut prefix.as_slice_mut().expect("a new array is contiguous")[k..];
+    rows.par_chunks_mut(block_rows * k)
+        .zip(records.par_chunks(block_rows))
+        .for_each(|(block, records)| {
+            for (i, r) in records.iter().enumerate() {
+                let (done, row) = block.split_at_mut(i * k);
+                let row = &mut row[..k];
+                if i > 0 {
+                    row.copy_from_slice(&done[(i - 1) * k..]);
+                }
+                add(row, &r.seq[columns.clone()]);
+            }
+        });
+    let mut offsets = vec![vec![0u32; k]];
+    for block in rows.chunks(block_rows * k) {
+        let last = &block[block.len() - k..];
+        let offset = offsets[offsets.len() - 1]
+            .iter()
+            .zip(last)
+            .map(|(&o, &c)| o + c);
+        offsets.push(offset.collect());
+    }
+    rows.par_chunks_mut(block_rows * k)
+        .zip(offsets.par_iter())
+        .skip(1)
+        .for_each(|(block, offset)| {
+            for row in block.chunks_exact_mut(k) {
+                for (x, &o) in row.iter_mut().zip(offset) {
+                    *x += o;
+                }
+            }
+        });
+    prefix
+}
+
+/// prefix sums over the rows of `records` of the counted characters in each column,
+/// row `i` having the counts of the first `i` records, for
+/// `CrucibleCtxt::retrieve_nchars_range`
+pub fn nchars_prefix_sums(records: &[OwnedRecord], counter: &CharCounter) -> Array2<u32> {
+    let k = records.first().map_or(0, |r| r.seq.len());
+    column_prefix_sums(records, 0..k, |row, seq| counter.add_counts(row, seq))
+}
+
+/// prefix sums over the rows of `records` of the counted characters of every class
+/// of `classes` in each of `columns`
+fn class_prefix_sums(
+    records: &[OwnedRecord],
+    counter: &CharCounter,
+    classes: ResidueClasses,
+    columns: Range<usize>,
+) -> Vec<Array2<u32>> {
+    let table = classes.table();
+    (0..classes.num_classes())
+        .map(|class| {
+            column_prefix_sums(records, columns.clone(), |row, seq| {
+                for (x, &c) in row.iter_mut().zip(seq) {
+                    *x += (counter.counts(c) && table[c as usize] as usize == class) as u32;
+                }
+            })
+        })
+        .collect()
+}
+
+/// the counts `buf` of the columns from `start` on with a counted character in the
+/// rows of a subset or in its `padding` rows (which only decide which columns the
+/// HMM has), and those columns
+fn sparse_counts(
+    buf: &[u32],
+    start: usize,
+    padding: &[usize],
+    records: &[OwnedRecord],
+    counter: &CharCounter,
+) -> (Vec<u32>, Vec<usize>) {
+    let mut nonzero_counts: Vec<u32> = vec![];
+    let mut column_positions: Vec<usize> = vec![];
+    for (i, &c) in buf.iter().enumerate() {
+        let j = start + i;
+        if c > 0 || padding.iter().any(|&p| counter.counts(records[p].seq[j])) {
+            nonzero_counts.push(c);
+            column_positions.push(j);
+        }
+    }
+    (nonzero_counts, column_positions)
+}
+
+/// the counts and columns (as `sparse_counts`) of the subsets `ranges` of `records`,
+/// with the `padding` rows of each, counted over blocks of `block` columns at a time
+/// from prefix sums of each block alone
+fn blocked_counts(
+    records: &[OwnedRecord],
+    ranges: &[(usize, usize)],
+    padding: &[Vec<usize>],
+    counter: &CharCounter,
+    block: usize,
+) -> Vec<(Vec<u32>, Vec<usize>)> {
+    let k = records.first().map_or(0, |r| r.seq.len());
+    let mut counts = ranges.iter().map(|_| (vec![], vec![])).collect_vec();
+    for start in (0..k).step_by(block) {
+        let columns = start..(start + block).min(k);
+        let prefix = column_prefix_sums(records, columns.clone(), |row, seq| {
+            counter.add_counts(row, seq)
+        });
+        counts
+            .par_iter_mut()
+            .zip(ranges.par_iter().zip(padding.par_iter()))
+            .for_each(|((nonzero_counts, column_positions), (&range, padding))| {
+                let mut buf = vec![0u32; columns.len()];
+                CrucibleCtxt::retrieve_nchars_noalloc(&prefix, range, &mut buf);
+                let (c, j) = sparse_counts(&buf, start, padding, records, counter);
+                nonzero_counts.extend(c);
+                column_positions.extend(j);
+            });
+    }
+    counts
+}
+
+/// the metadata of the subsets `decomp` makes of the aligned `records`, `ts` being
+/// the taxa of the tree it decomposed, to which the records are matched by name,
+/// with their characters counted as `characters` says, built in memory without
+/// reading or writing any file, nor building HMMs
+pub fn melt_in_memory<R: BaseRecord + Sync>(
+    records: &[R],
+    ts: &TaxonSet,
+    decomp: &TaxaHierarchy,
+    characters: &CharacterOptions,
+) -> anyhow::Result<CrucibleCtxt> {
+    if decomp.reordered_taxa.len() != records.len() {
+        bail!(
+            "the decomposition has {} taxa, but there are {} sequences",
+            decomp.reordered_taxa.len(),
+            records.len()
+        );
+    }
+    // the record of each taxon, by taxon id
+    let mut order = vec![usize::MAX; ts.names.len()];
+    for (i, r) in records.iter().enumerate() {
+        let name = decode_text(r.head());
+        match ts.to_id.get(name.as_ref()) {
+            Some(&t) if order[t] == usize::MAX => order[t] = i,
+            Some(_) => bail!("{} has more than one sequence", name),
+            None => bail!("{} is not a taxon of the tree", name),
+        }
+    }
+    if let Some(t) = order.iter().position(|&i| i == usize::MAX) {
+        bail!("{} has no sequence", ts.names[t]);
+    }
+    let seqs = order.iter().map(|&i| records[i].full_seq()).collect_vec();
+    let k = seqs.first().map_or(0, |s| s.len());
+    if let Some(t) = seqs.iter().position(|s| s.len() != k) {
+        bail!(
+            "{} is {} columns wide instead of {}",
+            ts.names[t],
+            seqs[t].len(),
+            k
+        );
+    }
+    let counter = CharCounter::new(characters);
+    let mut metadata = decomp
+        .decomposition_ranges
+        .par_iter()
+        .map(|&(lb, ub)| {
+            let mut counts = vec![0u32; k];
+            for &t in &decomp.reordered_taxa[lb..ub] {
+                counter.add_counts(&mut counts, &seqs[t]);
+            }
+            let column_positions = (0..k).filter(|&j| counts[j] > 0).collect_vec();
+            let nonzero_counts = column_positions.iter().map(|&j| counts[j]).collect();
+            let mut hmm = HmmMeta::new((lb, ub), nonzero_counts, column_positions);
+            hmm.outlier = decomp.outliers == Some((lb, ub));
+            hmm.stats = Some(SubsetStats::from_counts(ub - lb, &counts));
+            hmm
+        })
+        .collect::<Vec<_>>();
+    for (hmm, levels) in metadata.iter_mut().zip(&decomp.range_levels) {
+        hmm.levels = levels.clone();
+    }
+    let mut ranges = decomp.decomposition_ranges.clone();
+    ranges.sort_unstable();
+    let disjoint = ranges.windows(2).all(|w| w[0].1 <= w[1].0);
+    let mut ctxt = CrucibleCtxt::new(metadata, disjoint);
+    ctxt.taxon_names = ts.names.clone();
+    if let Some((lb, ub)) = decomp.outliers {
+        ctxt.quarantined = decomp.reordered_taxa[lb..ub]
+            .iter()
+            .map(|&t| ctxt.taxon_names[t].clone())
+            .collect();
+    }
+    ctxt.characters = characters.clone();
+    ctxt.num_columns = k;
+    ctxt.reordered_taxa = decomp.reordered_taxa.clone();
+    Ok(ctxt)
+}
+
+/// decomposes a prepared alignment into `outdir`, writing its subsets, their HMMs and
+/// the metadata (`melt.json`) there
+pub fn melt_prepared(
+    prepared: PreparedAlignment,
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+    outdir: &Path,
+) -> anyhow::Result<CrucibleCtxt> {
+    let PreparedAlignment {
+        alignment:
+            Alignment {
+                mut records,
+                mut reference,
+                trees,
+            },
+        rename,
+        duplicates,
+        filtered,
+        counter,
+        checksum,
+        loci,
+        sources,
+        mut left_out,
+    } = prepared;
+    let records = &mut records;
+    let stripped_columns = if options.characters.lowercase == LowercasePolicy::Strip {
+        let stripped = strip_lowercase_columns(records, reference.as_mut());
+        if !stripped.is_empty() && !loci.is_empty() {
+            bail!("cannot strip insertion columns, which shift the loci, from a concatenated alignment");
+        }
+        info!(stripped = stripped.len(), "stripped insertion columns");
+        stripped
+    } else {
+        vec![]
+    };
+    let source_of = |r: &OwnedRecord| {
+        let name = decode_text(&r.head);
+        sources
+            .get(name.as_ref())
+            .cloned()
+            .unwrap_or_else(|| name.into_owned())
+    };
+    let queries = match options.split_fragments {
+        Some(fraction) => {
+            let queries = split_fragments(records, &counter, fraction);
+            if records.is_empty() {
+                bail!(
+                    "no sequence is full-length under --split-fragments {}",
+                    fraction
+                );
+            }
+            info!(
+                num_backbone = records.len(),
+                num_queries = queries.len(),
+                "split off fragmentary sequences"
+            );
+            queries
+        }
+        None => vec![],
+    };
+    let alphabet = options.characters.alphabet.resolve(records, &counter);
+    if options.characters.alphabet == Alphabet::Any {
+        info!(?alphabet, "detected the alphabet");
+    }
+    if options.packed {
+        check_packable(options, alphabet)?;
+    }
+    let decomp_options = &options.decomposition;
+    let (collection, decomp) = if options.cluster {
+        if options.padding > 0 {
+            bail!("padding subsets needs a guide tree, which clustering does not use");
+        }
+        let taxon_set = records_taxon_set(records)?;
+        let k = options.kmer_size.unwrap_or(DEFAULT_KMER_SIZE);
+        let distances = kmer_distances(records.iter().map(|r| r.seq.as_slice()), k);
+        let decomp = cluster_decomp(&distances, decomp_options);
+        let collection = TreeCollection {
+            taxon_set,
+            trees: vec![],
+        };
+        (collection, decomp)
+    } else {
+        let copies = match &duplicates {
+            Some(duplicates) => &duplicates.renamed[..],
+            None => &[],
+        };
+        tree_decomp(
+            records,
+            tree,
+            &trees,
+            NameChanges {
+                rename: rename.as_ref(),
+                copies,
+                filtered: &filtered,
+            },
+            &counter,
+            strategy,
+            options,
+        )?
+    };
+    let ts = &collection.taxon_set;
     info!(
         num_subsets = decomp.decomposition_ranges.len(),
         "decomposed input tree"
     );
-    let mut reader = Reader::from_path(input)?;
-    let mut records_failable: Result<Vec<_>, _> = reader.records().into_iter().collect();
-    let records = records_failable.as_mut().unwrap();
-    let ts = &collection.taxon_set;
-    records.sort_unstable_by_key(|r| {
-        let taxon_name = String::from_utf8(r.head.clone()).unwrap();
-        let id = ts.to_id[&taxon_name];
-        decomp.taxa_positions[id]
-    });
+    let (unplaced, positions) = take_unplaced(records, ts, &decomp)?;
+    left_out.extend(queries.iter().chain(&unplaced).map(source_of));
+    let source_headers = records
+        .iter()
+        .filter_map(|r| {
+            let name = decode_text(&r.head);
+            let source = sources.get(name.as_ref())?;
+            Some((name.into_owned(), source.clone()))
+        })
+        .collect();
+    if !unplaced.is_empty() && options.missing_taxa == MissingTaxaPolicy::Error {
+        bail!(
+            "{} sequences are not in the guide tree, e.g. {}",
+            unplaced.len(),
+            decode_text(&unplaced[0].head)
+        );
+    }
+    // the position of every sorted record in the input, for --subset-order input
+    let mut keyed = positions
+        .into_iter()
+        .zip(records.drain(..))
+        .enumerate()
+        .map(|(i, (position, r))| (position, i, r))
+        .collect_vec();
+    keyed.sort_unstable_by_key(|&(position, _, _)| position);
+    let input_rank = keyed.iter().map(|&(_, i, _)| i).collect_vec();
+    records.extend(keyed.into_iter().map(|(_, _, r)| r));
     for (i, &t) in decomp.reordered_taxa.iter().enumerate() {
-        assert_eq!(&String::from_utf8(records[i].head.clone())?, &ts.names[t]);
+        assert_eq!(decode_text(&records[i].head).as_ref(), &ts.names[t]);
     }
-    let n = records.len(); // # of seqs
-    let k = records[0].seq.len(); // # of columns
-    let mut nchars_prefix = Array::<u32, _>::zeros((n + 1, k).f());
-    for i in 1..n + 1 {
-        for j in 0..k {
-            if i == 1 {
-                nchars_prefix[[i, j]] = if records[i - 1].seq[j] == b'-' { 0 } else { 1 };
-            } else {
-                nchars_prefix[[i, j]] =
-                    nchars_prefix[[i - 1, j]] + if records[i - 1].seq[j] == b'-' { 0 } else { 1 };
-            }
+    let padding = match collection.trees.first() {
+        Some(tree) => padding_rows(tree, &decomp, options.padding),
+        None => vec![vec![]; decomp.decomposition_ranges.len()],
+    };
+    let labels = match collection.trees.first() {
+        Some(tree) if options.clade_labels => clade_labels(tree, &decomp, ts),
+        _ => vec![None; decomp.decomposition_ranges.len()],
+    };
+    // # of columns
+    let k = records[0].seq.len();
+    // from here on, the sequences are only in the packed form, if any
+    let packed = options.packed.then(|| {
+        let packed = PackedAlignment::take(records, alphabet);
+        info!(bytes = packed.packed_bytes(), "packed the sequences");
+        packed
+    });
+
+    // without the prefix sums, every subset's sequences are counted on their own
+    // column blocks have prefix sums of their own
+    let nchars_prefix =
+        (!options.compact_counts && options.column_block.is_none() && packed.is_none())
+            .then(|| nchars_prefix_sums(records, &counter));
+    let class_prefix = match options.residue_classes {
+        Some(classes) if nchars_prefix.is_some() => {
+            Some(class_prefix_sums(records, &counter, classes, 0..k))
         }
-    }
+        _ => None,
+    };
     let subsets_root = outdir.join("subsets");
-    let metadata_path = outdir.join("melt.json");
+    if options.shards == Some(0) {
+        bail!("--shards must be at least 1");
+    }
+    if options.column_block == Some(0) {
+        bail!("--column-block must be at least 1");
+    }
+    if let Some(min_occupancy) = options.mask_occupancy {
+        if !(0.0..=1.0).contains(&min_occupancy) {
+            bail!("--mask-occupancy must be between 0 and 1");
+        }
+    }
+    let template = options.name_template.as_deref();
+    if let Some(template) = template {
+        check_name_template(template)?;
+    }
     create_dir_all(&subsets_root)?;
+    for i in 0..decomp
+        .decomposition_ranges
+        .len()
+        .min(options.shards.unwrap_or(0))
+    {
+        create_dir_all(subset_dir(&subsets_root, options.shards, i))?;
+    }
+    let compression = subset_compression(options);
+    if !unplaced.is_empty() {
+        write_unplaced(
+            &unplaced,
+            options.missing_taxa,
+            options.wrap_width(),
+            outdir,
+        )?;
+    }
+    if options.split_fragments.is_some() {
+        write_queries(&queries, options.wrap_width(), outdir)?;
+    }
     // for (i, &(lb, ub)) in decomp.decomposition_ranges.iter().enumerate() {
     //     let to_write = &records[lb..ub];
     //     let mut writer = BufWriter::new(File::create(subsets_root.join(format!("{}.afa", i)))?);
@@ -159,50 +2170,112 @@ pub fn oneshot_melt(
     //     }
     // }
 
-    {
-        let to_write = &records[0..collection.ntaxa()];
-        let mut writer = BufWriter::new(File::create(subsets_root.join(format!("{}.afa", 0)))?);
-        for r in to_write {
-            r.write_wrap(&mut writer, 60)?;
-        }
-    }
-
-    decomp
-        .decomposition_ranges
-        .par_iter()
-        .enumerate()
-        .for_each(|(i, &(lb, ub))| {
-            let to_write = &records[lb..ub];
-            hmmbuild(
-                to_write.iter(),
-                format!("{}", i).as_str(),
-                &subsets_root.join(format!("{}.hmm", i)),
-            )
-            .expect("Failed to build HMM");
-        });
-
-    let mut writer = BufWriter::new(File::create(metadata_path)?);
     // let mut metadata: Vec<HmmMeta> = vec![];
     // let mut buf = vec![0u32; k];
     // TODO: very probably not the best way to reuse buffer
+    let blocked = match options.column_block {
+        Some(block) => blocked_counts(
+            records,
+            &decomp.decomposition_ranges,
+            &padding,
+            &counter,
+            block,
+        )
+        .into_iter()
+        .map(Some)
+        .collect_vec(),
+        None => decomp.decomposition_ranges.iter().map(|_| None).collect(),
+    };
     let t_buf = Arc::new(ThreadLocal::new());
-    let metadata: Vec<HmmMeta> = decomp
+    let mut metadata: Vec<HmmMeta> = decomp
         .decomposition_ranges
         .par_iter()
-        .map(|&decomp_range| {
-            let local = t_buf.clone();
-            let local_value = local.get_or(|| RefCell::new(vec![0u32; k]));
-            let mut buf = local_value.borrow_mut();
-            CrucibleCtxt::retrieve_nchars_noalloc(&nchars_prefix, decomp_range, &mut buf);
-            let mut nonzero_counts: Vec<u32> = vec![];
-            let mut column_positions: Vec<usize> = vec![];
-            for (i, &c) in buf.iter().enumerate() {
-                if c > 0 {
-                    nonzero_counts.push(c);
-                    column_positions.push(i);
-                }
-            }
-            let hmm = HmmMeta::new(decomp_range, nonzero_counts, column_positions);
+        .zip(padding.par_iter())
+        .zip(blocked.into_par_iter())
+        .map(|((&decomp_range, padding), blocked)| {
+            let (nonzero_counts, column_positions) = match blocked {
+                Some(blocked) => blocked,
+                None => {
+                    let local = t_buf.clone();
+                    let local_value = local.get_or(|| RefCell::new(vec![0u32; k]));
+                    let mut buf = local_value.borrow_mut();
+                    match &nchars_prefix {
+                        Some(nchars_prefix) => CrucibleCtxt::retrieve_nchars_noalloc(
+                            nchars_prefix,
+                            decomp_range,
+                            &mut buf,
+                        ),
+                        None => {
+                            buf.fill(0);
+                            let (lb, ub) = decomp_range;
+                            match &packed {
+                                Some(packed) => {
+                                    for i in lb..ub {
+                                        packed.add_counts(i, &counter, &mut buf);
+                                    }
+                                }
+                                None => {
+                                    for r in &records[lb..ub] {
+                                        counter.add_counts(&mut buf, &r.seq);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    sparse_counts(&buf, 0, padding, records, &counter)
+                }
+            };
+            let mut hmm = HmmMeta::new(decomp_range, nonzero_counts, column_positions);
+            hmm.padding = padding.clone();
+            hmm.outlier = decomp.outliers == Some(decomp_range);
+            if let Some(classes) = options.residue_classes {
+                let (lb, ub) = decomp_range;
+                hmm.class_counts = match &class_prefix {
+                    Some(class_prefix) => class_prefix
+                        .iter()
+                        .map(|p| {
+                            hmm.column_poitions
+                                .iter()
+                                .map(|&j| p[[ub, j]] - p[[lb, j]])
+                                .collect()
+                        })
+                        .collect(),
+                    None => {
+                        let table = classes.table();
+                        let mut counts =
+                            vec![vec![0u32; hmm.column_poitions.len()]; classes.num_classes()];
+                        for r in &records[lb..ub] {
+                            for (i, &j) in hmm.column_poitions.iter().enumerate() {
+                                let c = r.seq[j];
+                                if counter.counts(c) {
+                                    counts[table[c as usize] as usize][i] += 1;
+                                }
+                            }
+                        }
+                        counts
+                    }
+                };
+            }
+            match options.sequence_weights {
+                Some(SequenceWeighting::Henikoff) => {
+                    let (lb, ub) = decomp_range;
+                    let rows = records[lb..ub]
+                        .iter()
+                        .chain(padding.iter().map(|&p| &records[p]))
+                        .collect_vec();
+                    hmm.weights = henikoff_weights(&rows, &hmm.column_poitions, &counter);
+                }
+                None => {}
+            }
+            if options.subset_quality {
+                let (lb, ub) = decomp_range;
+                let rows = records[lb..ub].iter().collect_vec();
+                hmm.quality = Some(subset_quality(&rows, &hmm.column_poitions, &counter));
+            }
+            hmm.stats = Some(SubsetStats::from_counts(
+                decomp_range.1 - decomp_range.0,
+                &hmm.chars_cnt,
+            ));
             hmm
         })
         .collect();
@@ -219,7 +2292,346 @@ pub fn oneshot_melt(
     //     let hmm = HmmMeta::new(decomp_range, nonzero_counts, column_positions);
     //     metadata.push(hmm);
     // }
-    let ctxt = CrucibleCtxt::new(metadata);
-    serde_json::to_writer(&mut writer, &ctxt)?;
+    for ((hmm, levels), label) in metadata.iter_mut().zip(decomp.range_levels).zip(labels) {
+        hmm.levels = levels;
+        hmm.label = label;
+    }
+    {
+        let whole = (0, decomp.reordered_taxa.len());
+        let to_write = &records[whole.0..whole.1];
+        let path = if options.subset_format == Some(SubsetFormat::Afa)
+            && decomp.decomposition_ranges[0] != whole
+        {
+            outdir.join("backbone.afa")
+        } else {
+            subset_dir(&subsets_root, options.shards, 0).join(subset_file_name(
+                template,
+                0,
+                &metadata[0],
+                "afa",
+            ))
+        };
+        let mut writer = create_output(&path, compression)?;
+        let mut buf = vec![];
+        for (i, r) in to_write.iter().enumerate() {
+            let seq = match &packed {
+                Some(packed) => {
+                    packed.unpack_into(i, &mut buf);
+                    &buf
+                }
+                None => &r.seq,
+            };
+            write_fasta(&mut writer, &r.head, seq, options.wrap_width())?;
+        }
+        writer.finish()?;
+    }
+    if options.trim_subsets {
+        metadata.par_iter_mut().for_each(|hmm| {
+            let (lb, ub) = hmm.sequence_range;
+            let rows = &records[lb..ub];
+            hmm.subset_columns = Some(
+                (0..k)
+                    .filter(|&j| rows.iter().any(|r| r.seq[j] != b'-'))
+                    .collect(),
+            );
+        });
+    }
+    if options.subset_format.is_some() || options.ungapped_subsets {
+        write_subsets(
+            records,
+            packed.as_ref(),
+            reference.as_deref(),
+            &input_rank,
+            &metadata,
+            options,
+            &subsets_root,
+        )?;
+    }
+    if options.profiles {
+        let residues = alphabet.residues().unwrap_or_default();
+        metadata
+            .par_iter()
+            .enumerate()
+            .try_for_each(|(i, hmm)| -> anyhow::Result<()> {
+                let (lb, ub) = hmm.sequence_range;
+                let path = subset_dir(&subsets_root, options.shards, i)
+                    .join(subset_file_name(template, i, hmm, "tsv"));
+                let mut writer = create_output(&path, compression)?;
+                write_frequency_table(
+                    &mut writer,
+                    &records[lb..ub].iter().collect_vec(),
+                    &hmm.column_poitions,
+                    residues,
+                    &counter,
+                )?;
+                writer.finish()?;
+                Ok(())
+            })?;
+    }
+    if let Some(tree) = collection.trees.first().filter(|_| options.subset_trees) {
+        for (i, &(lb, ub)) in decomp.decomposition_ranges.iter().enumerate() {
+            // quarantined taxa are not in the tree
+            if decomp.outliers == Some((lb, ub)) {
+                continue;
+            }
+            let kept = decomp.reordered_taxa[lb..ub].iter().copied().collect();
+            let path = subset_dir(&subsets_root, options.shards, i).join(subset_file_name(
+                template,
+                i,
+                &metadata[i],
+                "nwk",
+            ));
+            let mut writer = create_output(&path, compression)?;
+            writeln!(writer, "{}", induced_subtree_newick(tree, &ts.names, &kept))?;
+            writer.finish()?;
+        }
+    }
+    if options.subset_consensus {
+        let consensus = metadata
+            .par_iter()
+            .map(|hmm| {
+                let (lb, ub) = hmm.sequence_range;
+                // the weights of the padding rows, which are left out, come last
+                let weights = (!hmm.weights.is_empty()).then(|| &hmm.weights[..ub - lb]);
+                majority_consensus(&records[lb..ub].iter().collect_vec(), weights, &counter)
+            })
+            .collect::<Vec<_>>();
+        let mut writer = BufWriter::new(File::create(outdir.join("subset_consensus.fasta"))?);
+        for (i, (hmm, seq)) in metadata.iter().zip(consensus).enumerate() {
+            let head = match &hmm.label {
+                Some(label) => format!("{} {}", i, label),
+                None => i.to_string(),
+            };
+            write_fasta(&mut writer, head.as_bytes(), &seq, options.wrap_width())?;
+        }
+    }
+    if options.membership {
+        write_membership_csv(
+            BufWriter::new(File::create(outdir.join("membership.csv"))?),
+            &metadata,
+            records,
+        )?;
+    }
+    if options.dot {
+        write_decomposition_dot(
+            BufWriter::new(File::create(outdir.join("decomposition.dot"))?),
+            &metadata,
+            records,
+        )?;
+    }
+    if let Some(tree) = collection.trees.first().filter(|_| options.annotations) {
+        let subsets = finest_subsets(
+            &decomp.decomposition_ranges,
+            &decomp.reordered_taxa,
+            ts.names.len(),
+        );
+        write_itol_colorstrip(
+            BufWriter::new(File::create(outdir.join("decomposition.itol.txt"))?),
+            &ts.names,
+            &subsets,
+        )?;
+        std::fs::write(
+            outdir.join("decomposition.nex"),
+            figtree_nexus(tree, &ts.names, &subsets),
+        )?;
+    }
+    metadata.par_iter().enumerate().for_each(|(i, hmm)| {
+        let (lb, ub) = hmm.sequence_range;
+        // the input's own reference, else that of the Stockholm subset files, which
+        // marks the columns of the HMM and so can be its match states
+        let synthetic = (options.subset_format == Some(SubsetFormat::Stockholm)
+            && reference.is_none())
+        .then(|| subset_reference(hmm, None, k));
+        let rf = synthetic.as_deref().or(reference.as_deref());
+        let name = format!("{}", i);
+        let path = subset_dir(&subsets_root, options.shards, i)
+            .join(subset_file_name(template, i, hmm, "hmm"));
+        match &packed {
+            // with neither padding nor weights, which `check_packable` rules out
+            Some(packed) => hmmbuild_rows(
+                ub - lb,
+                |p, buf| {
+                    packed.unpack_into(lb + p, buf);
+                    &records[lb + p].head
+                },
+                rf,
+                synthetic.is_some(),
+                alphabet,
+                &name,
+                &path,
+            ),
+            None => hmmbuild(
+                records[lb..ub]
+                    .iter()
+                    .chain(hmm.padding.iter().map(|&p| &records[p])),
+                rf,
+                synthetic.is_some(),
+                (!hmm.weights.is_empty()).then_some(hmm.weights.as_slice()),
+                alphabet,
+                &name,
+                &path,
+            ),
+        }
+        .expect("Failed to build HMM");
+    });
+    if options.archive {
+        let compression = options.compress_output.or(Some(OutputCompression::Zstd));
+        let members = pack_directory(&subsets_root, compression)?;
+        info!(num_files = members.len(), "archived subsets");
+    }
+    let mut ctxt = CrucibleCtxt::new(metadata, decomp_options.disjoint);
+    if let Some((lb, ub)) = decomp.outliers {
+        ctxt.quarantined = decomp.reordered_taxa[lb..ub]
+            .iter()
+            .map(|&t| ts.names[t].clone())
+            .collect();
+    }
+    ctxt.duplicates = duplicates;
+    ctxt.characters = options.characters.clone();
+    ctxt.alphabet = Some(alphabet);
+    ctxt.shards = options.shards;
+    ctxt.name_template = options.name_template.clone();
+    ctxt.num_columns = k;
+    ctxt.residue_classes = options.residue_classes;
+    ctxt.taxon_names = ts.names.clone();
+    ctxt.reordered_taxa = decomp.reordered_taxa.clone();
+    ctxt.alignment_checksum = checksum;
+    ctxt.loci = loci;
+    ctxt.source_headers = source_headers;
+    ctxt.left_out = left_out;
+    ctxt.stripped_columns = stripped_columns;
+    ctxt.tree_checksum = match tree {
+        Some(tree) => input_checksum(tree)?,
+        None => None,
+    };
+    write_ctxt(ctxt, outdir, options)
+}
+
+/// masks the low-occupancy columns of the HMMs of `ctxt` and writes it as the
+/// metadata of the melt in `outdir`, along with the counts file and the checksums of
+/// every file there, as `options` say
+pub fn write_ctxt(
+    mut ctxt: CrucibleCtxt,
+    outdir: &Path,
+    options: &MeltOptions,
+) -> anyhow::Result<CrucibleCtxt> {
+    if let Some(min_occupancy) = options.mask_occupancy {
+        for i in 0..ctxt.num_hmms() {
+            ctxt.metadata[i].masked_columns = ctxt.low_occupancy_columns(i, min_occupancy);
+        }
+        ctxt.mask_occupancy = Some(min_occupancy);
+    }
+    let split = if options.split_counts {
+        Some(ctxt.write_counts(outdir)?)
+    } else {
+        None
+    };
+    ctxt.checksums = checksum_files(outdir)?;
+    match split {
+        Some(mut split) => {
+            split.checksums = ctxt.checksums.clone();
+            write_metadata(&split, outdir, options.metadata_format)?;
+        }
+        None => {
+            write_metadata(&ctxt, outdir, options.metadata_format)?;
+        }
+    }
     Ok(ctxt)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decomposition::{CentroidEdgeCut, SizeSpec};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// the subsets of taxon names `tree` is split into with the forced cuts given
+    /// as pairs of taxa whose MRCA roots each clade, in order
+    fn forced_subsets(newick: &str, pairs: &[(&str, &str)]) -> Vec<Vec<String>> {
+        let collection = parse_trees(newick).unwrap();
+        let (tree, ts) = (&collection.trees[0], &collection.taxon_set);
+        let leaves = leaf_nodes(tree, ts.len());
+        let leaf = |name: &str| leaves[ts.to_id[name]].unwrap();
+        let constraints = DecompositionConstraints {
+            forced_cuts: pairs
+                .iter()
+                .map(|&(a, b)| tree_mrca(tree, &[leaf(a), leaf(b)]).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        let options = DecompositionOptions {
+            max_size: Some(SizeSpec::Count(100)),
+            ..Default::default()
+        };
+        let decomp = hierarchical_decomp(tree, &CentroidEdgeCut, &options, &constraints, None);
+        let mut subsets = decomp
+            .disjoint_ranges
+            .iter()
+            .map(|&(lb, ub)| {
+                let mut names = decomp.reordered_taxa[lb..ub]
+                    .iter()
+                    .map(|&t| ts.names[t].clone())
+                    .collect_vec();
+                names.sort();
+                names
+            })
+            .collect_vec();
+        subsets.sort();
+        subsets
+    }
+
+    #[test]
+    fn forced_cuts_apply_in_any_order() {
+        // the first cut leaves the second in the smaller component, which is
+        // already within the size bound
+        let newick = "((T0,T1,T2,T3,T4,T5),((T6,T7),T8,T9));";
+        let forward = forced_subsets(newick, &[("T0", "T5"), ("T6", "T7")]);
+        let backward = forced_subsets(newick, &[("T6", "T7"), ("T0", "T5")]);
+        assert_eq!(forward.len(), 3);
+        assert_eq!(forward, backward);
+    }
+
+    /// the prefix sums of random sequences (with uncounted characters, over a width
+    /// that is not a whole number of SSE2 blocks) against a recount, row by row, and
+    /// the counts of random subsets taken from them
+    #[test]
+    fn prefix_sums_count_every_subset() {
+        let (n, k) = (300, 133);
+        let mut rng = StdRng::seed_from_u64(42);
+        let records = (0..n)
+            .map(|i| OwnedRecord {
+                head: format!("T{}", i).into_bytes(),
+                seq: (0..k)
+                    .map(|_| b"ACGTacgtN?-"[rng.gen_range(0..11)])
+                    .collect(),
+            })
+            .collect_vec();
+        let counter = CharCounter::new(&CharacterOptions {
+            missing: "N".to_string(),
+            ..Default::default()
+        });
+        let prefix = nchars_prefix_sums(&records, &counter);
+        assert_eq!(prefix.dim(), (n + 1, k));
+        let mut counts = vec![0u32; k];
+        for (i, r) in records.iter().enumerate() {
+            assert_eq!(prefix.row(i).to_vec(), counts, "row {}", i);
+            for (x, &c) in counts.iter_mut().zip(&r.seq) {
+                *x += counter.counts(c) as u32;
+            }
+        }
+        assert_eq!(prefix.row(n).to_vec(), counts);
+        let mut buf = vec![0u32; k];
+        for _ in 0..200 {
+            let lb = rng.gen_range(0..n);
+            let ub = rng.gen_range(lb + 1..=n);
+            let mut counts = vec![0u32; k];
+            for r in &records[lb..ub] {
+                for (x, &c) in counts.iter_mut().zip(&r.seq) {
+                    *x += counter.counts(c) as u32;
+                }
+            }
+            CrucibleCtxt::retrieve_nchars_noalloc(&prefix, (lb, ub), &mut buf);
+            assert_eq!(buf, counts, "subset {}..{}", lb, ub);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::input::{open_input, read_text};
+use anyhow::{bail, Context};
+use ogcat::ogtree::*;
+use std::path::Path;
+
+/// `name` as an unquoted Newick label: blanks and the characters Newick reserves
+/// become underscores, underscores standing for blanks in Newick anyway
+pub fn newick_label(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_whitespace() || "()[]':;,".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// rewrites the trees in `text` into the plain Newick that ogcat reads, one string per
+/// tree: comments (including `[&...]` annotations and NHX `[&&NHX:...]` tags) are
+/// dropped, quoted labels are unquoted, and labels are made safe with `newick_label`
+pub fn clean_newick(text: &str) -> Vec<String> {
+    let mut trees = Vec::new();
+    let mut out = String::new();
+    let mut label = String::new();
+    let mut chars = text.chars().peekable();
+    let mut comment_depth = 0;
+    let mut quoted = false;
+    // a blank seen inside a label, kept only if the label goes on
+    let mut blank = false;
+    let flush = |label: &mut String, out: &mut String| {
+        out.push_str(&newick_label(label));
+        label.clear();
+    };
+    while let Some(c) = chars.next() {
+        if comment_depth > 0 {
+            match c {
+                '[' => comment_depth += 1,
+                ']' => comment_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        if quoted {
+            if c != '\'' {
+                label.push(c);
+            } else if chars.peek() == Some(&'\'') {
+                chars.next();
+                label.push('\'');
+            } else {
+                quoted = false;
+            }
+            continue;
+        }
+        match c {
+            '[' => comment_depth += 1,
+            '\'' => quoted = true,
+            '(' | ')' | ',' | ':' | ';' => {
+                flush(&mut label, &mut out);
+                blank = false;
+                out.push(c);
+                if c == ';' {
+                    trees.push(std::mem::take(&mut out));
+                }
+            }
+            _ if c.is_whitespace() => blank = !label.is_empty(),
+            _ => {
+                if std::mem::take(&mut blank) {
+                    label.push(' ');
+                }
+                label.push(c);
+            }
+        }
+    }
+    flush(&mut label, &mut out);
+    if !out.is_empty() {
+        out.push(';');
+        trees.push(out);
+    }
+    trees
+}
+
+/// parses the trees in `text` with `clean_newick` as the front-end
+pub fn parse_trees(text: &str) -> anyhow::Result<TreeCollection> {
+    let mut collection = TreeCollection::new();
+    for newick in clean_newick(text) {
+        let tree = parse_newick(&mut collection.taxon_set, &newick);
+        collection.trees.push(tree);
+    }
+    if collection.trees.is_empty() {
+        bail!("no trees found");
+    }
+    Ok(collection)
+}
+
+/// reads the Newick trees at `path` (anything `open_input` takes), which may
+/// span several lines each; labels that are not UTF-8 are read as `decode_text` does
+pub fn read_trees<P: AsRef<Path>>(path: P) -> anyhow::Result<TreeCollection> {
+    let path = path.as_ref();
+    let (text, _) = read_text(open_input(path)?)
+        .with_context(|| format!("cannot read trees from {}", path.display()))?;
+    parse_trees(&text).with_context(|| format!("cannot read trees from {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_lose_blanks_and_reserved_characters() {
+        assert_eq!(newick_label("Homo sapiens"), "Homo_sapiens");
+        assert_eq!(newick_label("a(b):c;d,e[f]'g"), "a_b__c_d_e_f__g");
+        assert_eq!(newick_label("plain_name.1"), "plain_name.1");
+    }
+
+    #[test]
+    fn quoted_labels_are_unquoted() {
+        assert_eq!(
+            clean_newick("('Homo sapiens':1,'it''s (here)',B C);"),
+            ["(Homo_sapiens:1,it_s__here_,B_C);"]
+        );
+    }
+
+    #[test]
+    fn comments_are_dropped_and_trees_split() {
+        assert_eq!(
+            clean_newick(
+                "[a tree] ((A[&rate=1.5]:0.1,B):0.2[&&NHX:S=x],C)root;\n(C,[nested [comment]]D);"
+            ),
+            ["((A:0.1,B):0.2,C)root;", "(C,D);"]
+        );
+        // a last tree missing its semicolon still counts
+        assert_eq!(clean_newick("(A,B)"), ["(A,B);"]);
+    }
+}
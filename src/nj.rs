@@ -0,0 +1,68 @@
+use crate::tree_ops::EditableTree;
+
+/// builds an unrooted neighbor-joining tree over `distances`, leaf `i` being taxon `i`;
+/// the root is the last join, a trifurcation when there are at least three taxa
+pub fn neighbor_joining(distances: &[Vec<f64>]) -> EditableTree {
+    let n = distances.len();
+    let mut tree = EditableTree {
+        children: vec![vec![]; n],
+        taxa: (0..n as i32).collect(),
+        lengths: vec![f64::NAN; n],
+        support: vec![f64::NAN; n],
+        root: 0,
+    };
+    if n <= 1 {
+        return tree;
+    }
+    let mut d = distances.to_vec();
+    // node of the tree currently represented by each row of `d`
+    let mut node_of = (0..n).collect::<Vec<_>>();
+    let mut active = (0..n).collect::<Vec<_>>();
+    while active.len() > 3 {
+        let m = active.len();
+        let row_sums = active
+            .iter()
+            .map(|&a| active.iter().map(|&b| d[a][b]).sum::<f64>())
+            .collect::<Vec<_>>();
+        let mut best = (f64::INFINITY, 0, 1);
+        for x in 0..m {
+            for y in x + 1..m {
+                let q = (m - 2) as f64 * d[active[x]][active[y]] - row_sums[x] - row_sums[y];
+                if q < best.0 {
+                    best = (q, x, y);
+                }
+            }
+        }
+        let (_, x, y) = best;
+        let (a, b) = (active[x], active[y]);
+        let dab = d[a][b];
+        let la = 0.5 * dab + (row_sums[x] - row_sums[y]) / (2.0 * (m - 2) as f64);
+        let la = la.clamp(0.0, dab.max(0.0));
+        let joined = tree.add_node(vec![node_of[a], node_of[b]]);
+        tree.lengths[node_of[a]] = la;
+        tree.lengths[node_of[b]] = (dab - la).max(0.0);
+        // the row of `a` now stands for the joined node
+        for &c in &active {
+            if c != a && c != b {
+                let dc = 0.5 * (d[a][c] + d[b][c] - dab);
+                d[a][c] = dc;
+                d[c][a] = dc;
+            }
+        }
+        node_of[a] = joined;
+        active.remove(y);
+    }
+    let rest = active.iter().map(|&a| node_of[a]).collect::<Vec<_>>();
+    if active.len() == 2 {
+        let half = d[active[0]][active[1]] / 2.0;
+        tree.lengths[rest[0]] = half;
+        tree.lengths[rest[1]] = half;
+    } else {
+        let (a, b, c) = (active[0], active[1], active[2]);
+        tree.lengths[rest[0]] = (0.5 * (d[a][b] + d[a][c] - d[b][c])).max(0.0);
+        tree.lengths[rest[1]] = (0.5 * (d[a][b] + d[b][c] - d[a][c])).max(0.0);
+        tree.lengths[rest[2]] = (0.5 * (d[a][c] + d[b][c] - d[a][b])).max(0.0);
+    }
+    tree.root = tree.add_node(rest);
+    tree
+}
@@ -0,0 +1,388 @@
+use crate::input::open_input;
+use anyhow::{bail, Context};
+use clap::ArgEnum;
+use flate2::write::GzEncoder;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, metadata, read_dir, remove_dir, remove_dir_all, rename, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// how output files are compressed; `open_input` reads either back transparently
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputCompression {
+    Gzip,
+    Zstd,
+}
+
+impl OutputCompression {
+    /// the extension appended to the names of compressed files
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputCompression::Gzip => ".gz",
+            OutputCompression::Zstd => ".zst",
+        }
+    }
+}
+
+/// `path` with the extension of `compression` appended, if any
+pub fn output_path(path: &Path, compression: Option<OutputCompression>) -> PathBuf {
+    match compression {
+        Some(compression) => {
+            let mut name = path.as_os_str().to_owned();
+            name.push(compression.extension());
+            PathBuf::from(name)
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+/// `path` as written by `create_output` with any compression, if it exists
+pub fn find_output(path: &Path) -> Option<PathBuf> {
+    [
+        None,
+        Some(OutputCompression::Gzip),
+        Some(OutputCompression::Zstd),
+    ]
+    .into_iter()
+    .map(|compression| output_path(path, compression))
+    .find(|p| p.exists())
+}
+
+/// a file being written, possibly compressed; it must be `finish`ed, which is when
+/// the errors of compressing it are told
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputFile {
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(mut w) => w.flush(),
+            OutputFile::Gzip(e) => e.finish()?.flush(),
+            OutputFile::Zstd(e) => e.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(w) => w.write(buf),
+            OutputFile::Gzip(e) => e.write(buf),
+            OutputFile::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(w) => w.flush(),
+            OutputFile::Gzip(e) => e.flush(),
+            OutputFile::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// creates `path`, with the extension of `compression` appended if any
+pub fn create_output(
+    path: &Path,
+    compression: Option<OutputCompression>,
+) -> io::Result<OutputFile> {
+    compressed_writer(File::create(output_path(path, compression))?, compression)
+}
+
+/// a writer into `file` compressing with `compression`, if any
+pub fn compressed_writer(
+    file: File,
+    compression: Option<OutputCompression>,
+) -> io::Result<OutputFile> {
+    let writer = BufWriter::new(file);
+    Ok(match compression {
+        None => OutputFile::Plain(writer),
+        Some(OutputCompression::Gzip) => {
+            OutputFile::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+        }
+        Some(OutputCompression::Zstd) => {
+            OutputFile::Zstd(zstd::stream::write::Encoder::new(writer, 0)?)
+        }
+    })
+}
+
+/// an output file packed into an archive, at `offset` (in bytes, of its contents)
+/// in the uncompressed tar stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// counts the bytes written through it
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// the name of the archive `pack_directory` makes of `dir`
+pub fn archive_path(dir: &Path, compression: Option<OutputCompression>) -> PathBuf {
+    let mut name = dir.as_os_str().to_owned();
+    name.push(".tar");
+    output_path(Path::new(&name), compression)
+}
+
+/// pushes the paths of the files under `dir`, relative to it and prefixed by `prefix`
+fn list_files(dir: &Path, prefix: &str, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", name), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// packs the files under `dir`, ordered by their leading number (subset files sharing
+/// the number of their subset) and then by name, into one tar archive next to it,
+/// compressed with `compression`, and removes `dir`; the archive ends with
+/// `index.json` (in `dir` too), listing where every file is in the tar stream
+pub fn pack_directory(
+    dir: &Path,
+    compression: Option<OutputCompression>,
+) -> anyhow::Result<Vec<ArchiveMember>> {
+    let prefix = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("cannot archive {}", dir.display()))?
+        .to_string();
+    let mut names = vec![];
+    list_files(dir, "", &mut names)?;
+    names.sort_by_cached_key(|name| {
+        let file_name = name.rsplit('/').next().unwrap();
+        let digits = file_name.bytes().take_while(u8::is_ascii_digit).count();
+        (file_name[..digits].parse::<usize>().ok(), name.clone())
+    });
+    let writer = create_output(&archive_path(dir, None), compression)?;
+    let mut builder = tar::Builder::new(CountingWriter {
+        inner: writer,
+        count: 0,
+    });
+    let mut index = vec![];
+    for name in names {
+        let path = dir.join(&name);
+        let size = metadata(&path)?.len();
+        let member = format!("{}/{}", prefix, name);
+        builder.append_path_with_name(&path, &member)?;
+        // the contents end padded to the next block of 512 bytes
+        let offset = builder.get_ref().count - size.div_ceil(512) * 512;
+        index.push(ArchiveMember {
+            name: member,
+            offset,
+            size,
+        });
+    }
+    let json = serde_json::to_vec(&index)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(
+        &mut header,
+        format!("{}/index.json", prefix),
+        json.as_slice(),
+    )?;
+    builder.into_inner()?.inner.finish()?;
+    remove_dir_all(dir)?;
+    Ok(index)
+}
+
+/// unpacks the archive `pack_directory` made of `dir` (compressed or not) back into
+/// `dir`, unless it already exists; whether there was one
+pub fn unpack_directory(dir: &Path) -> anyhow::Result<bool> {
+    if dir.exists() {
+        return Ok(false);
+    }
+    unpack_directory_into(dir, dir.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+/// unpacks the archive `pack_directory` made of `dir` (compressed or not) into
+/// `parent` instead of next to the archive, leaving `dir` as it is; whether there
+/// was one
+pub fn unpack_directory_into(dir: &Path, parent: &Path) -> anyhow::Result<bool> {
+    let archive = match find_output(&archive_path(dir, None)) {
+        Some(archive) => archive,
+        None => return Ok(false),
+    };
+    tar::Archive::new(open_input(&archive)?)
+        .unpack(parent)
+        .with_context(|| format!("cannot unpack {}", archive.display()))?;
+    Ok(true)
+}
+
+/// writes a FASTA record, its sequence wrapped at `width` characters or on one
+/// line if `None`
+pub fn write_fasta<W: Write>(
+    writer: W,
+    head: &[u8],
+    seq: &[u8],
+    width: Option<usize>,
+) -> io::Result<()> {
+    match width {
+        Some(width) => seq_io::fasta::write_wrap(writer, head, seq, width),
+        None => seq_io::fasta::write(writer, head, seq),
+    }
+}
+
+/// runs `write` on a staging directory next to `outdir` (`.<name>.partial`), which
+/// then replaces `outdir` only if it succeeds, so that a killed or failed run never
+/// leaves a half-written `outdir` behind; only a directory `is_owned` is replaced as a
+/// whole (and marked with `RUN_MARKER`), what is written being moved into any other
+/// next to what it has, which is never overwritten
+pub fn write_atomically<T>(
+    outdir: &Path,
+    write: impl FnOnce(&Path) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let name = outdir
+        .file_name()
+        .with_context(|| format!("cannot write into {}", outdir.display()))?
+        .to_string_lossy()
+        .into_owned();
+    let parent = match outdir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    create_dir_all(parent)?;
+    let staging = parent.join(format!(".{}.partial", name));
+    // left by a run that was killed
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    create_dir_all(&staging)?;
+    let written = match write(&staging) {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+    if !is_owned(outdir)? {
+        let moved = read_dir(&staging)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        if let Some(clash) = moved.iter().find(|m| outdir.join(m).exists()) {
+            let _ = remove_dir_all(&staging);
+            bail!(
+                "{} already has {}, which is not replaced outside the output of an earlier run",
+                outdir.display(),
+                clash.to_string_lossy()
+            );
+        }
+        for m in &moved {
+            rename(staging.join(m), outdir.join(m))?;
+        }
+        remove_dir(&staging)?;
+        return Ok(written);
+    }
+    File::create(staging.join(RUN_MARKER))?;
+    if outdir.exists() {
+        let old = parent.join(format!(".{}.old", name));
+        if old.exists() {
+            remove_dir_all(&old)?;
+        }
+        rename(outdir, &old)?;
+        rename(&staging, outdir)?;
+        remove_dir_all(&old)?;
+    } else {
+        rename(&staging, outdir)?;
+    }
+    Ok(written)
+}
+
+/// the file marking a directory as made by crucible as a whole, so that it may be
+/// replaced by the output of another run
+pub const RUN_MARKER: &str = ".crucible";
+
+/// whether `outdir` is missing, empty or marked with `RUN_MARKER`, so that nothing
+/// but the output of an earlier run is lost by replacing it
+pub fn is_owned(outdir: &Path) -> io::Result<bool> {
+    Ok(!outdir.exists()
+        || outdir.join(RUN_MARKER).exists()
+        || (outdir.is_dir() && read_dir(outdir)?.next().is_none()))
+}
+
+/// fails if `outdir` is not `is_owned`, unless `force`d to write into the directory
+/// next to its other files; tells whether it is owned
+pub fn check_outdir(outdir: &Path, force: bool) -> anyhow::Result<bool> {
+    if is_owned(outdir)? {
+        return Ok(true);
+    }
+    if !outdir.is_dir() {
+        bail!("{} exists and is not a directory", outdir.display());
+    }
+    if !force {
+        bail!(
+            "{} exists and is not the output of an earlier run; pass --force to write into it",
+            outdir.display()
+        );
+    }
+    Ok(false)
+}
+
+/// the 64-bit FNV-1a hash of everything read from `reader`, in hex: not
+/// cryptographic, but enough to tell a truncated or altered file from the one written
+pub fn checksum<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        for &b in &buf[..read] {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+/// the `checksum` of the input file at `path`, or `None` if it is not a local file
+/// (e.g. a remote input)
+pub fn input_checksum(path: &Path) -> io::Result<Option<String>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    checksum(File::open(path)?).map(Some)
+}
+
+/// the `checksum` of every file under `dir`, by its path relative to `dir`
+pub fn checksum_files(dir: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut names = vec![];
+    list_files(dir, "", &mut names)?;
+    names
+        .into_par_iter()
+        .map(|name| {
+            let sum = checksum(File::open(dir.join(&name))?)?;
+            Ok((name, sum))
+        })
+        .collect()
+}
@@ -0,0 +1,118 @@
+use crate::{
+    stats::write_report,
+    structures::{metadata_path, CrucibleCtxt},
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::info;
+
+/// how much two HMMs overlap, from the side of one of them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OverlapEdge {
+    pub hmm: usize,
+    pub shared_taxa: usize,
+    /// `shared_taxa` as a fraction of the taxa of the smaller HMM, 1 if it is
+    /// nested in the other
+    pub taxa_overlap: f64,
+    /// the number of well-occupied columns the two HMMs share
+    pub shared_columns: usize,
+    /// `shared_columns` as a fraction of the well-occupied columns of the HMM with
+    /// fewer of them
+    pub column_overlap: f64,
+}
+
+/// the HMMs sharing taxa with an HMM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OverlapNode {
+    pub hmm: usize,
+    pub neighbors: Vec<OverlapEdge>,
+}
+
+/// the number of elements two sorted slices share
+fn shared_sorted(a: &[usize], b: &[usize]) -> usize {
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    shared
+}
+
+/// `shared` as a fraction of `total`, 0 if `total` is
+fn fraction(shared: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        shared as f64 / total as f64
+    }
+}
+
+impl CrucibleCtxt {
+    /// the overlap graph of the HMMs as an adjacency list: every pair of HMMs sharing
+    /// taxa, with how many of them and of the columns where at least `min_occupancy`
+    /// of the sequences of each have a counted character
+    pub fn overlap_graph(&self, min_occupancy: f32) -> Vec<OverlapNode> {
+        let columns = (0..self.num_hmms())
+            .map(|i| {
+                let n = self.metadata[i].num_seqs().max(1) as f32;
+                self.column_counts(i)
+                    .filter(|&(_, c)| c as f32 / n >= min_occupancy)
+                    .map(|(j, _)| j)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut nodes = (0..self.num_hmms())
+            .map(|hmm| OverlapNode {
+                hmm,
+                neighbors: vec![],
+            })
+            .collect::<Vec<_>>();
+        for a in 0..self.num_hmms() {
+            let (lb_a, ub_a) = self.metadata[a].sequence_range;
+            for b in a + 1..self.num_hmms() {
+                let (lb_b, ub_b) = self.metadata[b].sequence_range;
+                let shared_taxa = ub_a.min(ub_b).saturating_sub(lb_a.max(lb_b));
+                if shared_taxa == 0 {
+                    continue;
+                }
+                let shared_columns = shared_sorted(&columns[a], &columns[b]);
+                let edge = |hmm| OverlapEdge {
+                    hmm,
+                    shared_taxa,
+                    taxa_overlap: fraction(shared_taxa, (ub_a - lb_a).min(ub_b - lb_b)),
+                    shared_columns,
+                    column_overlap: fraction(
+                        shared_columns,
+                        columns[a].len().min(columns[b].len()),
+                    ),
+                };
+                nodes[a].neighbors.push(edge(b));
+                nodes[b].neighbors.push(edge(a));
+            }
+        }
+        nodes
+    }
+}
+
+/// writes the overlap graph (see `CrucibleCtxt::overlap_graph`) of the HMMs of the
+/// melt in `outdir` as JSON, into `output` if given or else to the standard output
+pub fn oneshot_overlap(
+    outdir: &Path,
+    output: Option<&Path>,
+    min_occupancy: f32,
+) -> anyhow::Result<()> {
+    let ctxt = CrucibleCtxt::open(&metadata_path(outdir))?;
+    let graph = ctxt.overlap_graph(min_occupancy);
+    info!(
+        num_edges = graph.iter().map(|node| node.neighbors.len()).sum::<usize>() / 2,
+        "computed the overlap graph"
+    );
+    write_report(output, &graph)
+}
@@ -0,0 +1,272 @@
+use crate::alphabet::{Alphabet, CharCounter};
+use seq_io::fasta::OwnedRecord;
+
+/// aligned nucleotide sequences packed two bits per base, with a bitmap telling their
+/// gaps, another telling their lowercase characters (only if there are any), and the
+/// few other characters (ambiguity codes, missing data) kept aside as runs, taking
+/// about three eighths of a byte per cell instead of one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedAlignment {
+    width: usize,
+    /// the four bases, coded 0 to 3
+    bases: [u8; 4],
+    /// the codes of every row, 32 to a word, each row starting on a word of its own
+    codes: Vec<u64>,
+    /// a bit set on every gap of every row, 64 to a word likewise
+    gaps: Vec<u64>,
+    /// a bit set on every lowercase character of every row, laid out as `gaps`, or
+    /// empty if there are none
+    lowercase: Vec<u64>,
+    /// the runs of columns of every row with neither a base nor a gap, as their first
+    /// column, their length and their (uppercase) character
+    others: Vec<Vec<(u32, u32, u8)>>,
+}
+
+impl PackedAlignment {
+    /// packs the sequences of `records`, freeing each as it is packed, with the bases
+    /// of the (nucleotide) `alphabet`: ACGU for RNA, ACGT otherwise
+    pub fn take(records: &mut [OwnedRecord], alphabet: Alphabet) -> Self {
+        let width = records.first().map_or(0, |r| r.seq.len());
+        let bases = match alphabet {
+            Alphabet::Rna => *b"ACGU",
+            _ => *b"ACGT",
+        };
+        let mut code = [None; 256];
+        for (i, &b) in bases.iter().enumerate() {
+            code[b as usize] = Some(i as u64);
+        }
+        let (code_words, gap_words) = (width.div_ceil(32), width.div_ceil(64));
+        let num_rows = records.len();
+        let mut packed = Self {
+            width,
+            bases,
+            codes: vec![0; num_rows * code_words],
+            gaps: vec![0; num_rows * gap_words],
+            lowercase: vec![],
+            others: vec![vec![]; num_rows],
+        };
+        for (i, r) in records.iter_mut().enumerate() {
+            let seq = std::mem::take(&mut r.seq);
+            let codes = &mut packed.codes[i * code_words..(i + 1) * code_words];
+            let gaps = &mut packed.gaps[i * gap_words..(i + 1) * gap_words];
+            let others = &mut packed.others[i];
+            for (j, &c) in seq.iter().enumerate() {
+                if c.is_ascii_lowercase() {
+                    if packed.lowercase.is_empty() {
+                        packed.lowercase = vec![0; num_rows * gap_words];
+                    }
+                    packed.lowercase[i * gap_words + j / 64] |= 1 << (j % 64);
+                }
+                let c = c.to_ascii_uppercase();
+                match code[c as usize] {
+                    Some(code) => codes[j / 32] |= code << (2 * (j % 32)),
+                    None if c == b'-' => gaps[j / 64] |= 1 << (j % 64),
+                    None => match others.last_mut() {
+                        Some((start, len, d)) if *d == c && (*start + *len) as usize == j => {
+                            *len += 1
+                        }
+                        _ => others.push((j as u32, 1, c)),
+                    },
+                }
+            }
+        }
+        packed
+    }
+
+    /// the number of columns of every row
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.others.len()
+    }
+
+    /// the bytes the packed rows take
+    pub fn packed_bytes(&self) -> usize {
+        8 * (self.codes.len() + self.gaps.len() + self.lowercase.len())
+            + self
+                .others
+                .iter()
+                .map(|o| o.len() * std::mem::size_of::<(u32, u32, u8)>())
+                .sum::<usize>()
+    }
+
+    fn row_gaps(&self, i: usize) -> &[u64] {
+        let words = self.width.div_ceil(64);
+        &self.gaps[i * words..(i + 1) * words]
+    }
+
+    /// the lowercase bitmap of row `i`, if any character is lowercase
+    fn row_lowercase(&self, i: usize) -> Option<&[u64]> {
+        let words = self.width.div_ceil(64);
+        (!self.lowercase.is_empty()).then(|| &self.lowercase[i * words..(i + 1) * words])
+    }
+
+    /// the columns and characters of the runs of row `i` with neither a base nor a
+    /// gap, in uppercase
+    fn row_others(&self, i: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.others[i].iter().flat_map(|&(start, len, c)| {
+            (start as usize..(start + len) as usize).map(move |j| (j, c))
+        })
+    }
+
+    /// the sequence of row `i`, into `buf`
+    pub fn unpack_into(&self, i: usize, buf: &mut Vec<u8>) {
+        let words = self.width.div_ceil(32);
+        let codes = &self.codes[i * words..(i + 1) * words];
+        let gaps = self.row_gaps(i);
+        buf.clear();
+        buf.extend((0..self.width).map(|j| {
+            if gaps[j / 64] >> (j % 64) & 1 == 1 {
+                b'-'
+            } else {
+                self.bases[(codes[j / 32] >> (2 * (j % 32)) & 3) as usize]
+            }
+        }));
+        for (j, c) in self.row_others(i) {
+            buf[j] = c;
+        }
+        if let Some(lowercase) = self.row_lowercase(i) {
+            for (w, &word) in lowercase.iter().enumerate() {
+                let mut lower = word;
+                while lower != 0 {
+                    let j = 64 * w + lower.trailing_zeros() as usize;
+                    buf[j] = buf[j].to_ascii_lowercase();
+                    lower &= lower - 1;
+                }
+            }
+        }
+    }
+
+    /// adds one to `counts[j]` for every character of row `i` in column `j` that
+    /// `counter` counts, going over the bitmap of gaps rather than the characters
+    /// when all four bases are counted in either case
+    pub fn add_counts(&self, i: usize, counter: &CharCounter, counts: &mut [u32]) {
+        let lowercase = self.row_lowercase(i);
+        if !self.bases.iter().all(|&b| {
+            counter.counts(b) && (lowercase.is_none() || counter.counts(b.to_ascii_lowercase()))
+        }) {
+            let mut buf = vec![];
+            self.unpack_into(i, &mut buf);
+            counter.add_counts(counts, &buf);
+            return;
+        }
+        for (w, &gaps) in self.row_gaps(i).iter().enumerate() {
+            let columns = (self.width - 64 * w).min(64);
+            let mut filled = !gaps & (u64::MAX >> (64 - columns));
+            while filled != 0 {
+                counts[64 * w + filled.trailing_zeros() as usize] += 1;
+                filled &= filled - 1;
+            }
+        }
+        // the other characters are not gaps, so they were counted above
+        let is_lowercase =
+            |j: usize| matches!(lowercase, Some(l) if l[j / 64] >> (j % 64) & 1 == 1);
+        for (j, c) in self.row_others(i) {
+            let c = if is_lowercase(j) {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            };
+            if !counter.counts(c) {
+                counts[j] -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alphabet::{CharacterOptions, LowercasePolicy};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const WIDTHS: [usize; 7] = [1, 31, 32, 33, 63, 64, 65];
+
+    /// rows `width` columns wide: bases, gaps and other characters at random in
+    /// either case, and rows that are all gaps, all missing data or end in runs of
+    /// other characters
+    fn rows(width: usize, rng: &mut StdRng) -> Vec<OwnedRecord> {
+        let chars = b"ACGTacgt-NnRy.?";
+        let mut seqs = (0..20)
+            .map(|_| {
+                (0..width)
+                    .map(|_| chars[rng.gen_range(0..chars.len())])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        seqs.push(vec![b'-'; width]);
+        seqs.push(vec![b'N'; width]);
+        for end in [&b"N"[..], b"nN", b"ANNn", b"c??"] {
+            let mut seq = vec![b'G'; width.saturating_sub(end.len())];
+            seq.extend_from_slice(&end[end.len().saturating_sub(width)..]);
+            seqs.push(seq);
+        }
+        seqs.into_iter()
+            .enumerate()
+            .map(|(i, seq)| OwnedRecord {
+                head: format!("T{}", i).into_bytes(),
+                seq,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unpacking_gives_back_the_rows() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for width in WIDTHS {
+            let records = rows(width, &mut rng);
+            let mut taken = records.clone();
+            let packed = PackedAlignment::take(&mut taken, Alphabet::Dna);
+            assert_eq!((packed.width(), packed.num_rows()), (width, records.len()));
+            assert!(taken.iter().all(|r| r.seq.is_empty()));
+            let mut buf = vec![];
+            for (i, r) in records.iter().enumerate() {
+                packed.unpack_into(i, &mut buf);
+                assert_eq!(buf, r.seq, "width {}, row {}", width, i);
+            }
+        }
+    }
+
+    #[test]
+    fn counts_are_those_of_the_unpacked_rows() {
+        let options = [
+            // all the bases counted, and nothing but gaps not
+            CharacterOptions::default(),
+            // all the bases counted, so the uncounted others are taken off
+            CharacterOptions {
+                missing: "N?".to_string(),
+                gaps: ".".to_string(),
+                ..Default::default()
+            },
+            // lowercase bases not counted, so the rows are unpacked
+            CharacterOptions {
+                lowercase: LowercasePolicy::Missing,
+                ..Default::default()
+            },
+            CharacterOptions {
+                missing: "a".to_string(),
+                ..Default::default()
+            },
+        ];
+        let mut rng = StdRng::seed_from_u64(2);
+        for width in WIDTHS {
+            let records = rows(width, &mut rng);
+            let packed = PackedAlignment::take(&mut records.clone(), Alphabet::Dna);
+            for options in &options {
+                let counter = CharCounter::new(options);
+                for (i, r) in records.iter().enumerate() {
+                    let (mut expected, mut counts) = (vec![0; width], vec![0; width]);
+                    counter.add_counts(&mut expected, &r.seq);
+                    packed.add_counts(i, &counter, &mut counts);
+                    assert_eq!(
+                        counts, expected,
+                        "width {}, row {}, {:?}",
+                        width, i, options
+                    );
+                }
+            }
+        }
+    }
+}
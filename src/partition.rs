@@ -0,0 +1,121 @@
+use anyhow::{bail, Context};
+use std::{fs, path::Path};
+
+/// a locus of a concatenated alignment, as columns ranges (`start`, `end`, `stride`),
+/// 1-based and inclusive, `end` being `None` for the last column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub ranges: Vec<(usize, Option<usize>, usize)>,
+}
+
+impl Partition {
+    /// the (0-based) columns of the locus in an alignment `width` columns wide
+    pub fn columns(&self, width: usize) -> anyhow::Result<Vec<usize>> {
+        let mut columns = vec![];
+        for &(start, end, stride) in &self.ranges {
+            let end = end.unwrap_or(width);
+            if end > width {
+                bail!(
+                    "partition {} goes up to column {}, but the alignment has {} columns",
+                    self.name,
+                    end,
+                    width
+                );
+            }
+            columns.extend((start - 1..end).step_by(stride));
+        }
+        columns.sort_unstable();
+        columns.dedup();
+        Ok(columns)
+    }
+
+    /// the name made safe for a directory name
+    pub fn dir_name(&self) -> String {
+        self.name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+/// one range of a partition: `7`, `1-500`, `1-500\3` or `501-.`
+fn parse_range(token: &str) -> anyhow::Result<(usize, Option<usize>, usize)> {
+    let (range, stride) = match token.split_once('\\') {
+        Some((range, stride)) => (range, stride.trim().parse::<usize>()?),
+        None => (token, 1),
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, ".")) => (start.trim().parse::<usize>()?, None),
+        Some((start, end)) => (start.trim().parse()?, Some(end.trim().parse()?)),
+        None => {
+            let column = range.parse()?;
+            (column, Some(column))
+        }
+    };
+    if start == 0 || stride == 0 || matches!(end, Some(end) if end < start) {
+        bail!("invalid range");
+    }
+    Ok((start, end, stride))
+}
+
+/// parses RAxML-style partition files (`DNA, gene1 = 1-500, 501-600\3`) as well as
+/// the `charset` commands of Nexus ones (`charset gene1 = 1-500;`), ignoring
+/// whatever else a Nexus file has
+pub fn parse_partitions(text: &str) -> anyhow::Result<Vec<Partition>> {
+    let mut partitions = vec![];
+    for line in text.split(['\n', ';']) {
+        let line = line.trim();
+        let (head, ranges) = match line.split_once('=') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let lower = head.trim_start().to_ascii_lowercase();
+        let head = if lower.starts_with("charset ") {
+            &head.trim_start()["charset ".len()..]
+        } else if lower.starts_with("charpartition") || lower.starts_with("taxset") {
+            continue;
+        } else {
+            // the RAxML model name comes first
+            head.rsplit(',').next().unwrap()
+        };
+        let name = head.trim().trim_matches('\'').trim().to_string();
+        if name.is_empty() {
+            bail!("partition without a name: {}", line);
+        }
+        // Nexus allows blanks around the range punctuation
+        let mut ranges = ranges.to_string();
+        for (spaced, tight) in [(" -", "-"), ("- ", "-"), (" \\", "\\"), ("\\ ", "\\")] {
+            while ranges.contains(spaced) {
+                ranges = ranges.replace(spaced, tight);
+            }
+        }
+        let ranges = ranges
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|t| !t.is_empty())
+            .map(|t| parse_range(t).with_context(|| format!("in partition {}: {}", name, t)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if ranges.is_empty() {
+            bail!("partition {} has no columns", name);
+        }
+        partitions.push(Partition { name, ranges });
+    }
+    if partitions.is_empty() {
+        bail!("no partitions found");
+    }
+    Ok(partitions)
+}
+
+pub fn read_partitions<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Partition>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("cannot read partitions from {}", path.display()))?;
+    parse_partitions(&text)
+        .with_context(|| format!("cannot read partitions from {}", path.display()))
+}
@@ -0,0 +1,170 @@
+use crate::{alphabet::CharCounter, structures::SubsetQuality};
+use seq_io::fasta::OwnedRecord;
+use std::io::{self, Write};
+
+/// the number of times every residue (in upper case) is counted in column `j` of `rows`
+pub fn column_counts(rows: &[&OwnedRecord], j: usize, counter: &CharCounter) -> [u32; 256] {
+    let mut counts = [0u32; 256];
+    for r in rows {
+        let c = r.seq[j];
+        if counter.counts(c) {
+            counts[c.to_ascii_uppercase() as usize] += 1;
+        }
+    }
+    counts
+}
+
+/// the majority-rule consensus of the aligned `rows`, each counting as much as its
+/// weight if `weights` are given: the most common residue (the first in byte order on
+/// ties) of every column where rows of at least half of the total weight have a
+/// counted character, the other columns being left out
+pub fn majority_consensus(
+    rows: &[&OwnedRecord],
+    weights: Option<&[f64]>,
+    counter: &CharCounter,
+) -> Vec<u8> {
+    let width = rows.first().map_or(0, |r| r.seq.len());
+    let weight = |i: usize| weights.map_or(1.0, |w| w[i]);
+    let total_weight = (0..rows.len()).map(weight).sum::<f64>();
+    let mut consensus = vec![];
+    for j in 0..width {
+        let mut counts = [0f64; 256];
+        for (i, r) in rows.iter().enumerate() {
+            let c = r.seq[j];
+            if counter.counts(c) {
+                counts[c.to_ascii_uppercase() as usize] += weight(i);
+            }
+        }
+        let total = counts.iter().sum::<f64>();
+        if total == 0.0 || total * 2.0 < total_weight {
+            continue;
+        }
+        let mut best = 0;
+        for c in 1..counts.len() {
+            if counts[c] > counts[best] {
+                best = c;
+            }
+        }
+        consensus.push(best as u8);
+    }
+    consensus
+}
+
+/// the most sequences of a subset its `SubsetQuality` is computed from, evenly
+/// spaced among them, as comparing every pair takes quadratic time
+const QUALITY_SAMPLE: usize = 200;
+
+/// the identity at which sequences count as redundant for the effective number
+const REDUNDANT_IDENTITY: f64 = 0.8;
+
+/// the `SubsetQuality` of the aligned `rows` over `columns`, the effective number of
+/// sequences being scaled up to all of them if only a sample is compared
+pub fn subset_quality(
+    rows: &[&OwnedRecord],
+    columns: &[usize],
+    counter: &CharCounter,
+) -> SubsetQuality {
+    let sample = if rows.len() <= QUALITY_SAMPLE {
+        rows.to_vec()
+    } else {
+        (0..QUALITY_SAMPLE)
+            .map(|i| rows[i * rows.len() / QUALITY_SAMPLE])
+            .collect()
+    };
+    let n = sample.len();
+    let mut redundant = vec![0usize; n];
+    let (mut identity_sum, mut pairs) = (0.0, 0usize);
+    for a in 0..n {
+        for b in a + 1..n {
+            let (mut shared, mut identical) = (0usize, 0usize);
+            for &j in columns {
+                let (x, y) = (sample[a].seq[j], sample[b].seq[j]);
+                if counter.counts(x) && counter.counts(y) {
+                    shared += 1;
+                    identical += x.eq_ignore_ascii_case(&y) as usize;
+                }
+            }
+            if shared == 0 {
+                continue;
+            }
+            let identity = identical as f64 / shared as f64;
+            identity_sum += identity;
+            pairs += 1;
+            if identity >= REDUNDANT_IDENTITY {
+                redundant[a] += 1;
+                redundant[b] += 1;
+            }
+        }
+    }
+    let effective = redundant.iter().map(|&m| 1.0 / (m + 1) as f64).sum::<f64>();
+    SubsetQuality {
+        mean_identity: (pairs > 0).then(|| identity_sum / pairs as f64),
+        effective_seqs: effective * rows.len() as f64 / n.max(1) as f64,
+        sampled: n,
+    }
+}
+
+/// the position-based weights of Henikoff & Henikoff (1994) of the aligned `rows`
+/// over `columns`: in a column with `r` distinct residues, a row with a residue that
+/// `n` rows have gets `1 / (r n)`, and a row without a counted character nothing; the
+/// weights are scaled to sum to the number of rows, all being 1 if no row gets any
+pub fn henikoff_weights(
+    rows: &[&OwnedRecord],
+    columns: &[usize],
+    counter: &CharCounter,
+) -> Vec<f64> {
+    let mut weights = vec![0f64; rows.len()];
+    for &j in columns {
+        let counts = column_counts(rows, j, counter);
+        let distinct = counts.iter().filter(|&&n| n > 0).count() as f64;
+        for (w, r) in weights.iter_mut().zip(rows) {
+            let c = r.seq[j];
+            if counter.counts(c) {
+                *w += 1.0 / (distinct * counts[c.to_ascii_uppercase() as usize] as f64);
+            }
+        }
+    }
+    let total = weights.iter().sum::<f64>();
+    if total == 0.0 {
+        return vec![1.0; rows.len()];
+    }
+    let scale = rows.len() as f64 / total;
+    weights.iter_mut().for_each(|w| *w *= scale);
+    weights
+}
+
+/// writes the residue frequencies of the aligned `rows` as TSV, a row for each of
+/// `columns`: its number of counted characters, then the fraction of them that is
+/// each of `residues` (upper case) and the fraction that is something else
+pub fn write_frequency_table<W: Write>(
+    mut writer: W,
+    rows: &[&OwnedRecord],
+    columns: &[usize],
+    residues: &[u8],
+    counter: &CharCounter,
+) -> io::Result<()> {
+    write!(writer, "column\tcount")?;
+    for &c in residues {
+        write!(writer, "\t{}", c as char)?;
+    }
+    writeln!(writer, "\tother")?;
+    for &j in columns {
+        let counts = column_counts(rows, j, counter);
+        let total = counts.iter().sum::<u32>();
+        write!(writer, "{}\t{}", j, total)?;
+        let mut listed = 0;
+        let fraction = |n: u32| {
+            if total == 0 {
+                0.0
+            } else {
+                n as f64 / total as f64
+            }
+        };
+        for &c in residues {
+            listed += counts[c as usize];
+            write!(writer, "\t{:.4}", fraction(counts[c as usize]))?;
+        }
+        writeln!(writer, "\t{:.4}", fraction(total - listed))?;
+    }
+    Ok(())
+}
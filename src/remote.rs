@@ -0,0 +1,19 @@
+use anyhow::Context;
+use object_store::parse_url_opts;
+use url::Url;
+
+/// downloads the object at `url` (`s3://`, `gs://`, `az://` or `http(s)://`) whole;
+/// credentials and regions are taken from the environment as the respective
+/// cloud tools do, e.g. `AWS_ACCESS_KEY_ID` and `AWS_REGION`
+pub fn fetch(url: &str) -> anyhow::Result<Vec<u8>> {
+    let parsed = Url::parse(url).with_context(|| format!("invalid URL {}", url))?;
+    let config = std::env::vars().map(|(k, v)| (k.to_ascii_lowercase(), v));
+    let (store, path) = parse_url_opts(&parsed, config)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let bytes = runtime
+        .block_on(async { store.get(&path).await?.bytes().await })
+        .with_context(|| format!("cannot fetch {}", url))?;
+    Ok(bytes.to_vec())
+}
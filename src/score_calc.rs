@@ -1,9 +1,4 @@
-use std::{
-    cmp::Reverse,
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
-};
+use std::{cmp::Reverse, fs::File, io::BufWriter, path::PathBuf};
 
 use ahash::AHashMap;
 use itertools::Itertools;
@@ -17,7 +12,8 @@ use tracing::{debug, info};
 
 use crate::{
     external::hmmsearch,
-    structures::{AdderPayload, CrucibleCtxt},
+    input::{decode_text, open_input},
+    structures::{metadata_path, AdderPayload, CrucibleCtxt},
 };
 
 pub struct ScoringCtxt {
@@ -75,18 +71,17 @@ impl BitscoreTracker {
 
 impl ScoringCtxt {
     pub fn manual_construction(base_dir: &PathBuf) -> anyhow::Result<Self> {
-        let hmm_ctxt_path = base_dir.join("melt.json");
+        let hmm_ctxt = CrucibleCtxt::from_path(metadata_path(base_dir))?;
         let queries_path = base_dir.parent().unwrap().join("queries.fasta");
-        let hmm_ctxt = serde_json::from_reader(BufReader::new(File::open(&hmm_ctxt_path)?))?;
         let queries_failiable: Result<Vec<_>, _> =
-            seq_io::fasta::Reader::new(File::open(&queries_path)?)
+            seq_io::fasta::Reader::new(open_input(&queries_path)?)
                 .records()
                 .into_iter()
                 .collect();
         let queries = queries_failiable?;
         let mut seq_ids: AHashMap<String, u32> = AHashMap::new();
         for (i, q) in queries.iter().enumerate() {
-            seq_ids.insert(String::from_utf8(q.head.clone())?, i as u32);
+            seq_ids.insert(decode_text(&q.head).into_owned(), i as u32);
         }
         Ok(Self {
             base_dir: base_dir.to_owned(),
@@ -102,7 +97,7 @@ impl ScoringCtxt {
         queries_path: &PathBuf,
     ) -> anyhow::Result<Self> {
         let queries_failiable: Result<Vec<_>, _> =
-            seq_io::fasta::Reader::new(File::open(&queries_path)?)
+            seq_io::fasta::Reader::new(open_input(&queries_path)?)
                 .records()
                 .into_iter()
                 .collect();
@@ -110,7 +105,7 @@ impl ScoringCtxt {
         info!("read {} query sequences", queries.len());
         let mut seq_ids: AHashMap<String, u32> = AHashMap::new();
         for (i, q) in queries.iter().enumerate() {
-            seq_ids.insert(String::from_utf8(q.head.clone())?, i as u32);
+            seq_ids.insert(decode_text(&q.head).into_owned(), i as u32);
         }
         Ok(Self {
             base_dir,
@@ -121,9 +116,7 @@ impl ScoringCtxt {
     }
 
     pub fn hmm_path(&self, hmm_id: u32) -> PathBuf {
-        self.base_dir
-            .join("subsets")
-            .join(format!("{}.hmm", hmm_id))
+        self.hmm_ctxt.hmm_path(&self.base_dir, hmm_id)
     }
     pub fn scores_path(&self) -> PathBuf {
         self.base_dir.join("scores.json")
@@ -0,0 +1,110 @@
+use crate::{
+    decomposition::{
+        DecompositionConstraints, DecompositionOptions, DecompositionStrategy, PolytomyPolicy,
+    },
+    melt::hierarchical_decomp,
+    newick::read_trees,
+    tree_ops::resolve_polytomies,
+};
+use ogcat::ogtree::*;
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::BufWriter, path::PathBuf};
+use tracing::info;
+
+/// how consistently taxa end up in the same disjoint subset across replicate trees
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StabilityReport {
+    pub num_trees: usize,
+    pub taxa: Vec<String>,
+    /// fraction of the trees in which each pair of taxa shares a subset
+    pub cooccurrence: Vec<Vec<f64>>,
+    /// for each taxon, the mean co-occurrence with every taxon it ever shares a subset with
+    pub taxon_stability: Vec<f64>,
+    /// mean of `taxon_stability`; 1.0 when every replicate gives the same subsets
+    pub mean_stability: f64,
+}
+
+impl StabilityReport {
+    /// decomposes every tree of `collection` and tallies the disjoint subsets
+    pub fn from_trees(
+        collection: &mut TreeCollection,
+        strategy: &dyn DecompositionStrategy,
+        options: &DecompositionOptions,
+    ) -> anyhow::Result<Self> {
+        let n = collection.ntaxa();
+        let mut counts = vec![vec![0u32; n]; n];
+        for i in 0..collection.trees.len() {
+            if options.polytomy == PolytomyPolicy::Resolve {
+                let resolved = resolve_polytomies(
+                    &collection.trees[i],
+                    &mut collection.taxon_set,
+                    options.seed,
+                );
+                collection.trees[i] = resolved;
+            }
+            let tree = &collection.trees[i];
+            let constraints = DecompositionConstraints::load(options, tree, &collection.taxon_set)?;
+            let decomp = hierarchical_decomp(tree, strategy, options, &constraints, None);
+            for &(lb, ub) in &decomp.disjoint_ranges {
+                let subset = &decomp.reordered_taxa[lb..ub];
+                for &a in subset {
+                    for &b in subset {
+                        counts[a][b] += 1;
+                    }
+                }
+            }
+        }
+        let num_trees = collection.trees.len();
+        let cooccurrence = counts
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&c| c as f64 / num_trees as f64)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let taxon_stability = cooccurrence
+            .iter()
+            .enumerate()
+            .map(|(a, row)| {
+                let partners = row
+                    .iter()
+                    .enumerate()
+                    .filter(|&(b, &f)| b != a && f > 0.0)
+                    .map(|(_, &f)| f)
+                    .collect::<Vec<_>>();
+                if partners.is_empty() {
+                    1.0
+                } else {
+                    partners.iter().sum::<f64>() / partners.len() as f64
+                }
+            })
+            .collect::<Vec<_>>();
+        let mean_stability = taxon_stability.iter().sum::<f64>() / n.max(1) as f64;
+        Ok(Self {
+            num_trees,
+            taxa: collection.taxon_set.names.clone(),
+            cooccurrence,
+            taxon_stability,
+            mean_stability,
+        })
+    }
+}
+
+pub fn oneshot_stability(
+    trees: &PathBuf,
+    strategy: &dyn DecompositionStrategy,
+    options: &DecompositionOptions,
+    output: &PathBuf,
+) -> anyhow::Result<StabilityReport> {
+    let mut collection = read_trees(trees)?;
+    let report = StabilityReport::from_trees(&mut collection, strategy, options)?;
+    info!(
+        num_trees = report.num_trees,
+        mean_stability = report.mean_stability,
+        "computed decomposition stability"
+    );
+    let writer = BufWriter::new(File::create(output)?);
+    serde_json::to_writer(writer, &report)?;
+    Ok(report)
+}
@@ -0,0 +1,161 @@
+use crate::{
+    annotate::{subset_depths, subset_parents},
+    structures::{metadata_path, CrucibleCtxt, PartitionedCtxt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// the number of equal-width bins occupancy fractions are tallied in
+const OCCUPANCY_BINS: usize = 10;
+
+/// how the sizes of the subsets are spread
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SizeDistribution {
+    pub min: usize,
+    pub median: usize,
+    pub mean: f64,
+    pub max: usize,
+}
+
+/// the number of subsets with sizes in `[lower, upper)`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeBin {
+    pub lower: usize,
+    pub upper: usize,
+    pub count: usize,
+}
+
+/// summary statistics across the subsets of a melt, for a quick look at its output
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeltStats {
+    pub num_subsets: usize,
+    pub num_sequences: usize,
+    /// the number of columns with any counted character
+    pub num_columns: usize,
+    pub sizes: SizeDistribution,
+    /// the subsets binned by size, the bins doubling in width
+    pub size_histogram: Vec<SizeBin>,
+    /// the number of sequences in exactly `i` subsets, for every `i`
+    pub membership_histogram: Vec<usize>,
+    /// how deeply the subsets are nested in each other, 0 if none is
+    pub max_depth: usize,
+    /// the number of columns of the subsets (with any counted character) whose
+    /// fraction of counted characters is in each tenth from 0 to 1
+    pub occupancy_histogram: Vec<usize>,
+    pub num_outliers: usize,
+}
+
+impl MeltStats {
+    pub fn from_ctxt(ctxt: &CrucibleCtxt) -> Self {
+        let ranges = ctxt
+            .metadata
+            .iter()
+            .map(|m| m.sequence_range)
+            .collect::<Vec<_>>();
+        let num_sequences = ranges.iter().map(|&(_, ub)| ub).max().unwrap_or(0);
+        let mut sizes = ranges.iter().map(|&(lb, ub)| ub - lb).collect::<Vec<_>>();
+        sizes.sort_unstable();
+        let distribution = SizeDistribution {
+            min: sizes.first().copied().unwrap_or(0),
+            median: sizes.get(sizes.len() / 2).copied().unwrap_or(0),
+            mean: sizes.iter().sum::<usize>() as f64 / sizes.len().max(1) as f64,
+            max: sizes.last().copied().unwrap_or(0),
+        };
+        let mut size_histogram: Vec<SizeBin> = vec![];
+        for &size in &sizes {
+            match size_histogram.last_mut() {
+                Some(bin) if size < bin.upper => bin.count += 1,
+                _ => {
+                    let lower = if size == 0 {
+                        0
+                    } else {
+                        1 << (usize::BITS - 1 - size.leading_zeros())
+                    };
+                    size_histogram.push(SizeBin {
+                        lower,
+                        upper: (lower * 2).max(1),
+                        count: 1,
+                    });
+                }
+            }
+        }
+        // the number of subsets each sequence is in, from the ends of their ranges
+        let mut boundaries = vec![0i64; num_sequences + 1];
+        for &(lb, ub) in &ranges {
+            boundaries[lb] += 1;
+            boundaries[ub] -= 1;
+        }
+        let mut membership_histogram = vec![];
+        let mut depth = 0i64;
+        for &delta in &boundaries[..num_sequences] {
+            depth += delta;
+            let depth = depth as usize;
+            if membership_histogram.len() <= depth {
+                membership_histogram.resize(depth + 1, 0);
+            }
+            membership_histogram[depth] += 1;
+        }
+        let mut occupancy_histogram = vec![0; OCCUPANCY_BINS];
+        for i in 0..ctxt.num_hmms() {
+            for occupancy in ctxt.column_occupancy(i) {
+                let bin = (occupancy * OCCUPANCY_BINS as f32) as usize;
+                occupancy_histogram[bin.min(OCCUPANCY_BINS - 1)] += 1;
+            }
+        }
+        Self {
+            num_subsets: ranges.len(),
+            num_sequences,
+            num_columns: if ctxt.metadata.is_empty() {
+                0
+            } else {
+                ctxt.num_consensus_columns()
+            },
+            sizes: distribution,
+            size_histogram,
+            membership_histogram,
+            max_depth: subset_depths(&subset_parents(&ranges))
+                .into_iter()
+                .max()
+                .unwrap_or(0),
+            occupancy_histogram,
+            num_outliers: ctxt.quarantined.len(),
+        }
+    }
+}
+
+/// writes `value` as pretty JSON into `output` if given or else to the standard output
+pub fn write_report<T: Serialize>(output: Option<&Path>, value: &T) -> anyhow::Result<()> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// summarizes the melt in `outdir` (every locus of a partitioned one, by name) as
+/// JSON, into `output` if given or else to the standard output
+pub fn oneshot_stats(outdir: &Path, output: Option<&Path>) -> anyhow::Result<()> {
+    let metadata_path = metadata_path(outdir);
+    match CrucibleCtxt::from_path(&metadata_path) {
+        Ok(ctxt) => write_report(output, &MeltStats::from_ctxt(&ctxt)),
+        Err(e) => match PartitionedCtxt::from_path(&metadata_path) {
+            Ok(partitioned) => write_report(
+                output,
+                &partitioned
+                    .loci
+                    .iter()
+                    .map(|locus| (locus.name.clone(), MeltStats::from_ctxt(&locus.ctxt)))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+            Err(_) => Err(e),
+        },
+    }
+}
@@ -0,0 +1,677 @@
+use crate::{
+    alphabet::{Alphabet, CharCounter, LowercasePolicy},
+    decomposition::DecompositionStrategy,
+    external::hmmbuild_file,
+    faidx::{fai_path, IndexedFasta},
+    input::{
+        decode_text, encode_text, is_latin1, is_remote, open_input, read_rename_table,
+        read_taxon_list, rename_records, Compression,
+    },
+    melt::{
+        clade_labels, tree_only_decomp, write_ctxt, write_provenance, DuplicatePolicy, MeltOptions,
+        MissingTaxaPolicy, SubsetFormat, SubsetOrder,
+    },
+    newick::newick_label,
+    output::{
+        check_outdir, input_checksum, pack_directory, write_atomically, write_fasta,
+        OutputCompression,
+    },
+    structures::{subset_dir, subset_file_name, CrucibleCtxt, HmmMeta, SubsetStats},
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use seq_io::fasta::OwnedRecord;
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, remove_dir_all, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::info;
+
+/// the number of records read and cleaned up at a time
+const CHUNK_SIZE: usize = 1024;
+
+/// the most subset files written to at once, the input being read again for every
+/// batch of them
+const MAX_OPEN_FILES: usize = 256;
+
+/// the most bytes of records held back for a subset file until the records before
+/// them arrive, past which they are written where they belong out of order
+const REORDER_WINDOW: usize = 64 << 10;
+
+/// a subset file written through a buffer as the records of its positions arrive
+/// in input order, records ahead of the next position being held back (up to
+/// `REORDER_WINDOW` bytes) so that most writes are sequential
+struct SubsetWriter {
+    out: BufWriter<File>,
+    /// where in the file the next write goes
+    at: u64,
+    /// the next position written without seeking
+    next: usize,
+    /// records held back by position, with where in the file they go
+    pending: BTreeMap<usize, (u64, Vec<u8>)>,
+    pending_bytes: usize,
+}
+
+impl SubsetWriter {
+    fn new(file: File, lb: usize) -> Self {
+        SubsetWriter {
+            out: BufWriter::new(file),
+            at: 0,
+            next: lb,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    /// writes the bytes of the record at position `p`, going `at` bytes into the file
+    fn put(&mut self, p: usize, at: u64, bytes: &[u8]) -> anyhow::Result<()> {
+        match p.cmp(&self.next) {
+            std::cmp::Ordering::Less => return self.write_at(at, bytes),
+            std::cmp::Ordering::Equal => {
+                self.write_at(at, bytes)?;
+                self.next += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                self.pending_bytes += bytes.len();
+                self.pending.insert(p, (at, bytes.to_vec()));
+            }
+        }
+        // skips ahead to the first record held back once the window is full
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() != self.next && self.pending_bytes <= REORDER_WINDOW {
+                break;
+            }
+            let (q, (at, bytes)) = entry.remove_entry();
+            self.pending_bytes -= bytes.len();
+            self.write_at(at, &bytes)?;
+            self.next = q + 1;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, at: u64, bytes: &[u8]) -> anyhow::Result<()> {
+        if at != self.at {
+            self.out.seek(SeekFrom::Start(at))?;
+        }
+        self.out.write_all(bytes)?;
+        self.at = at + bytes.len() as u64;
+        Ok(())
+    }
+
+    /// writes the records still held back and syncs the file
+    fn finish(mut self) -> anyhow::Result<()> {
+        for (at, bytes) in std::mem::take(&mut self.pending).into_values() {
+            self.write_at(at, &bytes)?;
+        }
+        self.out.into_inner()?.sync_data()?;
+        Ok(())
+    }
+}
+
+/// fails for the melt options that need every sequence in memory
+fn check_streamable(options: &MeltOptions) -> anyhow::Result<()> {
+    let unsupported = [
+        ("--no-tree", options.no_tree),
+        ("--cluster", options.cluster),
+        ("--padding", options.padding > 0),
+        ("--split-fragments", options.split_fragments.is_some()),
+        ("--skip-invalid", options.skip_invalid),
+        ("--duplicates", options.duplicates != DuplicatePolicy::Error),
+        (
+            "--subset-format stockholm",
+            options.subset_format == Some(SubsetFormat::Stockholm),
+        ),
+        ("--trim-subsets", options.trim_subsets),
+        ("--subset-trees", options.subset_trees),
+        ("--annotations", options.annotations),
+        ("--dot", options.dot),
+        ("--membership", options.membership),
+        ("--subset-consensus", options.subset_consensus),
+        ("--profiles", options.profiles),
+        ("--ungapped-subsets", options.ungapped_subsets),
+        (
+            "--subset-order",
+            options.subset_order != SubsetOrder::Decomposition,
+        ),
+        ("--compress-output", options.compress_output.is_some()),
+        ("--residue-classes", options.residue_classes.is_some()),
+        ("--sequence-weights", options.sequence_weights.is_some()),
+        ("--subset-quality", options.subset_quality),
+        ("--packed", options.packed),
+        (
+            "--lowercase strip",
+            options.characters.lowercase == LowercasePolicy::Strip,
+        ),
+        (
+            "--missing-taxa",
+            options.missing_taxa != MissingTaxaPolicy::Error,
+        ),
+    ];
+    if let Some((flag, _)) = unsupported.iter().find(|&&(_, set)| set) {
+        bail!("--streaming does not support {}", flag);
+    }
+    Ok(())
+}
+
+/// names and cleans up `chunk` as `prepare_alignment` does
+fn clean_chunk(
+    chunk: &mut [OwnedRecord],
+    options: &MeltOptions,
+    rename: Option<&AHashMap<String, String>>,
+    counter: &CharCounter,
+) -> anyhow::Result<()> {
+    options.header_id.apply(chunk)?;
+    if let Some(table) = rename {
+        rename_records(chunk, table);
+    }
+    for r in chunk.iter_mut() {
+        let label = newick_label(&decode_text(&r.head));
+        if label != decode_text(&r.head) {
+            r.head = encode_text(&label, is_latin1(&r.head));
+        }
+    }
+    counter.validate(chunk)?;
+    counter.normalize_gaps(chunk);
+    Ok(())
+}
+
+/// reads the FASTA file at `input`, or its memory map `map` if given, in chunks of
+/// `CHUNK_SIZE` records cleaned up by `clean_chunk`, passing each to `f` along with
+/// the bytes of its records in `map` (none without it) and their headers before the
+/// cleanup
+fn for_each_chunk(
+    input: &Path,
+    map: Option<&[u8]>,
+    options: &MeltOptions,
+    rename: Option<&AHashMap<String, String>>,
+    counter: &CharCounter,
+    mut f: impl FnMut(&[OwnedRecord], &[Range<usize>], &[String]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let reader: Box<dyn Read + Send + '_> = match map {
+        Some(map) => Box::new(map),
+        None => open_input(input)?,
+    };
+    let mut reader = seq_io::fasta::Reader::new(reader);
+    loop {
+        let mut chunk = vec![];
+        let mut spans = vec![];
+        while chunk.len() < CHUNK_SIZE {
+            match reader.next() {
+                Some(record) => chunk.push(record?.to_owned_record()),
+                None => break,
+            }
+            if let Some(map) = map {
+                let start = reader.position().byte() as usize;
+                spans.push(start..record_end(map, start));
+            }
+        }
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let sources = chunk
+            .iter()
+            .map(|r| decode_text(&r.head).into_owned())
+            .collect::<Vec<_>>();
+        clean_chunk(&mut chunk, options, rename, counter)?;
+        f(&chunk, &spans, &sources)?;
+    }
+}
+
+/// the end of the FASTA record starting at `start` in `bytes`, where the next one
+/// starts
+fn record_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .windows(2)
+        .position(|w| w == b"\n>")
+        .map_or(bytes.len(), |i| start + i + 1)
+}
+
+/// whether the bytes `raw` of a record in the input are already those `write_fasta`
+/// writes for the cleaned up record `r`, wrapped at `wrap`, so that they can be
+/// copied as they are
+fn is_verbatim(raw: &[u8], r: &OwnedRecord, wrap: Option<usize>) -> bool {
+    let h = r.head.len();
+    if raw.len() as u64 != record_size(h, r.seq.len(), wrap)
+        || raw[0] != b'>'
+        || raw[1..=h] != r.head[..]
+        || raw[h + 1] != b'\n'
+    {
+        return false;
+    }
+    let body = &raw[h + 2..];
+    match wrap {
+        Some(wrap) => body
+            .chunks(wrap + 1)
+            .zip(r.seq.chunks(wrap))
+            .all(|(line, seq)| line.split_last() == Some((&b'\n', seq))),
+        None => body.split_last() == Some((&b'\n', &r.seq[..])),
+    }
+}
+
+/// memory-maps the FASTA file at `input`, which must be local and uncompressed
+fn map_input(input: &Path) -> anyhow::Result<Mmap> {
+    if is_remote(input) || Compression::of_file(input)? != Compression::None {
+        bail!(
+            "--mmap needs an uncompressed local file, which {} is not",
+            input.display()
+        );
+    }
+    let file = File::open(input).with_context(|| format!("cannot open {}", input.display()))?;
+    // the input is taken to stay as it is while it is melted
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// the number of bytes `write_fasta` takes for a record with a header of `head_len`
+/// bytes and `width` characters, wrapped at `wrap` if given
+fn record_size(head_len: usize, width: usize, wrap: Option<usize>) -> u64 {
+    let lines = wrap.map_or(1, |wrap| width.div_ceil(wrap));
+    (1 + head_len + 1 + width + lines) as u64
+}
+
+/// writes the file of every one of `targets` (paths and ranges of positions) in
+/// turn, fetching the records of its positions from the indexed input `fasta`:
+/// `located[p]` is where in the file the record at position `p` is (as a record
+/// number) and its cleaned up header
+fn write_indexed(
+    fasta: &mut IndexedFasta,
+    targets: &[(PathBuf, (usize, usize))],
+    located: &[(usize, Vec<u8>)],
+    offsets: &[u64],
+    counter: &CharCounter,
+    wrap: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    for (path, (lb, ub)) in targets {
+        let mut out = BufWriter::new(File::create(path)?);
+        for p in *lb..*ub {
+            let (i, head) = &located[p];
+            let mut r = [OwnedRecord {
+                head: head.clone(),
+                seq: fasta.fetch_nth(*i)?,
+            }];
+            counter.normalize_gaps(&mut r);
+            buf.clear();
+            write_fasta(&mut buf, &r[0].head, &r[0].seq, wrap)?;
+            if buf.len() as u64 != offsets[p + 1] - offsets[p] {
+                bail!("{} changed since it was first read", path.display());
+            }
+            out.write_all(&buf)?;
+        }
+        out.into_inner()?.sync_data()?;
+    }
+    Ok(())
+}
+
+/// melts the FASTA alignment at `input` without holding it in memory: the guide tree
+/// `tree` is decomposed alone, a first pass over the alignment counts the characters
+/// of the subsets, and a second one writes every sequence at its place in the file of
+/// each subset it is in, so that the memory taken grows with the number of subsets
+/// and columns instead of with the whole alignment; with `mmap`, the input is
+/// memory-mapped instead, and the records the cleanup leaves as they are are copied
+/// from the map into the subsets without being parsed a second time; otherwise, an
+/// uncompressed or BGZF input with a `.fai` index has the records of every subset
+/// fetched from it in turn through the index rather than being read again for every
+/// batch of subsets
+pub fn streaming_melt(
+    input: &Path,
+    tree: Option<&PathBuf>,
+    strategy: &dyn DecompositionStrategy,
+    options: &MeltOptions,
+    mmap: bool,
+    outdir: &Path,
+) -> anyhow::Result<CrucibleCtxt> {
+    let started = SystemTime::now();
+    check_streamable(options)?;
+    if input == Path::new("-") {
+        bail!("--streaming reads the input twice, so it cannot be the standard input");
+    }
+    check_outdir(outdir, options.force)?;
+    let map = if mmap { Some(map_input(input)?) } else { None };
+    let map = map.as_deref();
+    let indexed = !mmap
+        && !is_remote(input)
+        && fai_path(input).exists()
+        && matches!(
+            Compression::of_file(input)?,
+            Compression::None | Compression::Bgzf
+        );
+    let mut indexed = if indexed {
+        info!(
+            "fetching the records of the subsets through {:?}",
+            fai_path(input)
+        );
+        Some(IndexedFasta::open(input)?)
+    } else {
+        None
+    };
+    let (collection, decomp) = tree_only_decomp(tree, strategy, options, "--streaming")?;
+    let ts = &collection.taxon_set;
+    let n = decomp.reordered_taxa.len();
+    let rename = options.rename.as_ref().map(read_rename_table).transpose()?;
+    let include = options
+        .include_taxa
+        .as_ref()
+        .map(read_taxon_list)
+        .transpose()?;
+    let exclude = options
+        .exclude_taxa
+        .as_ref()
+        .map(read_taxon_list)
+        .transpose()?;
+    let filtered = |name: &str| {
+        let included = match &include {
+            Some(list) => list.contains(name),
+            None => true,
+        };
+        !included || matches!(&exclude, Some(list) if list.contains(name))
+    };
+    // the position of every record in the decomposition, `None` if filtered out
+    let position = |r: &OwnedRecord| -> anyhow::Result<Option<usize>> {
+        let name = decode_text(&r.head);
+        match ts.to_id.get(name.as_ref()) {
+            Some(&id) if decomp.reordered_taxa.get(decomp.taxa_positions[id]) == Some(&id) => {
+                Ok(Some(decomp.taxa_positions[id]))
+            }
+            _ if filtered(&name) => Ok(None),
+            _ => bail!("{} is not a taxon of the guide tree", name),
+        }
+    };
+    // the subsets are made of the runs of positions between any two of their bounds
+    let bounds = decomp
+        .decomposition_ranges
+        .iter()
+        .flat_map(|&(lb, ub)| [lb, ub])
+        .sorted_unstable()
+        .dedup()
+        .collect_vec();
+    let run_of = |p: usize| bounds.partition_point(|&b| b <= p) - 1;
+    let counter = CharCounter::new(&options.characters);
+    let mut k = None;
+    let mut run_counts: Vec<Vec<u32>> = vec![];
+    let wrap = options.wrap_width();
+    // the bytes every position takes in the files of the subsets
+    let mut sizes = vec![None; n];
+    // the bytes of the record at every position in the map, and whether they are
+    // copied as they are
+    let mut mapped = match map {
+        Some(_) => vec![(0..0, false); n],
+        None => vec![],
+    };
+    // where in the file the record at every position is, and its cleaned up header,
+    // for fetching it through the index
+    let mut located = match indexed {
+        Some(_) => vec![(0, vec![]); n],
+        None => vec![],
+    };
+    let mut num_records = 0;
+    let mut source_headers = BTreeMap::new();
+    let mut left_out = vec![];
+    let mut sample = vec![];
+    let rename = rename.as_ref();
+    for_each_chunk(
+        input,
+        map,
+        options,
+        rename,
+        &counter,
+        |chunk, spans, sources| {
+            if sample.is_empty() {
+                sample = chunk.to_vec();
+            }
+            num_records += chunk.len();
+            for (i, r) in chunk.iter().enumerate() {
+                let p = match position(r)? {
+                    Some(p) => p,
+                    None => {
+                        left_out.push(sources[i].clone());
+                        continue;
+                    }
+                };
+                if decode_text(&r.head) != sources[i].as_str() {
+                    source_headers.insert(decode_text(&r.head).into_owned(), sources[i].clone());
+                }
+                let width = *k.get_or_insert(r.seq.len());
+                if r.seq.len() != width {
+                    bail!(
+                        "{} has {} columns instead of {}",
+                        decode_text(&r.head),
+                        r.seq.len(),
+                        width
+                    );
+                }
+                let size = record_size(r.head.len(), width, wrap);
+                if let Some(map) = map {
+                    let verbatim = is_verbatim(&map[spans[i].clone()], r, wrap);
+                    mapped[p] = (spans[i].clone(), verbatim);
+                }
+                if !located.is_empty() {
+                    located[p] = (num_records - chunk.len() + i, r.head.clone());
+                }
+                if sizes[p].replace(size).is_some() {
+                    bail!("{} names more than one sequence", decode_text(&r.head));
+                }
+                if run_counts.is_empty() {
+                    run_counts = vec![vec![0u32; width]; bounds.len() - 1];
+                }
+                counter.add_counts(&mut run_counts[run_of(p)], &r.seq);
+            }
+            Ok(())
+        },
+    )?;
+    if let Some(p) = sizes.iter().position(Option::is_none) {
+        bail!(
+            "{} of the guide tree has no sequence",
+            ts.names[decomp.reordered_taxa[p]]
+        );
+    }
+    if let Some(fasta) = &indexed {
+        if fasta.index.records.len() != num_records {
+            bail!(
+                "{:?} indexes {} records, not the {} of {}",
+                fai_path(input),
+                fasta.index.records.len(),
+                num_records,
+                input.display()
+            );
+        }
+    }
+    let k = k.unwrap_or(0);
+    let alphabet = options.characters.alphabet.resolve(&sample, &counter);
+    if options.characters.alphabet == Alphabet::Any {
+        info!(?alphabet, "detected the alphabet");
+    }
+    drop(sample);
+    let labels = match collection.trees.first() {
+        Some(tree) if options.clade_labels => clade_labels(tree, &decomp, ts),
+        _ => vec![None; decomp.decomposition_ranges.len()],
+    };
+    let mut metadata = decomp
+        .decomposition_ranges
+        .iter()
+        .zip(labels)
+        .map(|(&(lb, ub), label)| {
+            let mut counts = vec![0u32; k];
+            for run in &run_counts[run_of(lb)..run_of(ub - 1) + 1] {
+                for (c, &r) in counts.iter_mut().zip(run) {
+                    *c += r;
+                }
+            }
+            let column_positions = (0..k).filter(|&j| counts[j] > 0).collect_vec();
+            let nonzero_counts = column_positions.iter().map(|&j| counts[j]).collect();
+            let mut hmm = HmmMeta::new((lb, ub), nonzero_counts, column_positions);
+            hmm.outlier = decomp.outliers == Some((lb, ub));
+            hmm.label = label;
+            hmm.stats = Some(SubsetStats::from_counts(ub - lb, &counts));
+            hmm
+        })
+        .collect_vec();
+    drop(run_counts);
+    for (hmm, levels) in metadata.iter_mut().zip(&decomp.range_levels) {
+        hmm.levels = levels.clone();
+    }
+    info!(
+        num_subsets = metadata.len(),
+        "counted the characters of the subsets"
+    );
+    // where the record at every position starts in a file of all of them
+    let mut offsets = vec![0u64; n + 1];
+    for p in 0..n {
+        offsets[p + 1] = offsets[p] + sizes[p].unwrap();
+    }
+    let template = options.name_template.as_deref();
+    write_atomically(outdir, |outdir| {
+        let subsets_root = outdir.join("subsets");
+        if options.shards == Some(0) {
+            bail!("--shards must be at least 1");
+        }
+        create_dir_all(&subsets_root)?;
+        for i in 0..metadata.len().min(options.shards.unwrap_or(0)) {
+            create_dir_all(subset_dir(&subsets_root, options.shards, i))?;
+        }
+        // subsets not written for themselves are staged for hmmbuild only
+        let staged = outdir.join(".streaming");
+        create_dir_all(&staged)?;
+        let whole = (0, n);
+        let afa = options.subset_format == Some(SubsetFormat::Afa);
+        let whole_path = if afa && metadata[0].sequence_range != whole {
+            outdir.join("backbone.afa")
+        } else {
+            subset_dir(&subsets_root, options.shards, 0).join(subset_file_name(
+                template,
+                0,
+                &metadata[0],
+                "afa",
+            ))
+        };
+        let mut targets = vec![(whole_path.clone(), whole)];
+        let mut alignments = vec![];
+        for (i, hmm) in metadata.iter().enumerate() {
+            let path = if hmm.sequence_range == whole {
+                whole_path.clone()
+            } else if afa {
+                subset_dir(&subsets_root, options.shards, i)
+                    .join(subset_file_name(template, i, hmm, "afa"))
+            } else {
+                staged.join(format!("{}.afa", i))
+            };
+            if !targets.iter().any(|(p, _)| p == &path) {
+                targets.push((path.clone(), hmm.sequence_range));
+            }
+            alignments.push(path);
+        }
+        // the targets left for reading the input again
+        let unwritten = match &mut indexed {
+            Some(fasta) => {
+                write_indexed(fasta, &targets, &located, &offsets, &counter, wrap)?;
+                &[][..]
+            }
+            None => &targets[..],
+        };
+        for batch in unwritten.chunks(MAX_OPEN_FILES) {
+            let mut files = batch
+                .iter()
+                .map(|(path, (lb, ub))| {
+                    let file = File::create(path)?;
+                    file.set_len(offsets[*ub] - offsets[*lb])?;
+                    Ok(SubsetWriter::new(file, *lb))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            // writes the bytes of the record at position `p` into the files having it
+            let mut put = |p: usize, bytes: &[u8]| -> anyhow::Result<()> {
+                if bytes.len() as u64 != offsets[p + 1] - offsets[p] {
+                    bail!("{} changed since it was first read", input.display());
+                }
+                for ((_, (lb, ub)), file) in batch.iter().zip(files.iter_mut()) {
+                    if (*lb..*ub).contains(&p) {
+                        file.put(p, offsets[p] - offsets[*lb], bytes)?;
+                    }
+                }
+                Ok(())
+            };
+            let mut buf = vec![];
+            match map {
+                Some(map) => {
+                    for (p, (span, verbatim)) in mapped.iter().enumerate() {
+                        if !batch.iter().any(|(_, (lb, ub))| (*lb..*ub).contains(&p)) {
+                            continue;
+                        }
+                        if *verbatim {
+                            put(p, &map[span.clone()])?;
+                            continue;
+                        }
+                        let mut reader = seq_io::fasta::Reader::new(&map[span.clone()]);
+                        let mut r = match reader.next() {
+                            Some(r) => [r?.to_owned_record()],
+                            None => bail!("{} changed since it was first read", input.display()),
+                        };
+                        clean_chunk(&mut r, options, rename, &counter)?;
+                        buf.clear();
+                        write_fasta(&mut buf, &r[0].head, &r[0].seq, wrap)?;
+                        put(p, &buf)?;
+                    }
+                }
+                None => for_each_chunk(input, None, options, rename, &counter, |chunk, _, _| {
+                    for r in chunk {
+                        if let Some(p) = position(r)? {
+                            buf.clear();
+                            write_fasta(&mut buf, &r.head, &r.seq, wrap)?;
+                            put(p, &buf)?;
+                        }
+                    }
+                    Ok(())
+                })?,
+            }
+            for file in files {
+                file.finish()?;
+            }
+        }
+        info!(num_files = targets.len(), "wrote the subsets");
+        metadata.par_iter().enumerate().try_for_each(|(i, hmm)| {
+            hmmbuild_file(
+                &alignments[i],
+                alphabet,
+                format!("{}", i).as_str(),
+                &subset_dir(&subsets_root, options.shards, i)
+                    .join(subset_file_name(template, i, hmm, "hmm")),
+            )
+        })?;
+        remove_dir_all(&staged)?;
+        if options.archive {
+            let compression = options.compress_output.or(Some(OutputCompression::Zstd));
+            let members = pack_directory(&subsets_root, compression)?;
+            info!(num_files = members.len(), "archived subsets");
+        }
+        let mut ctxt = CrucibleCtxt::new(metadata.clone(), options.decomposition.disjoint);
+        if let Some((lb, ub)) = decomp.outliers {
+            ctxt.quarantined = decomp.reordered_taxa[lb..ub]
+                .iter()
+                .map(|&t| ts.names[t].clone())
+                .collect();
+        }
+        ctxt.characters = options.characters.clone();
+        ctxt.alphabet = Some(alphabet);
+        ctxt.shards = options.shards;
+        ctxt.name_template = options.name_template.clone();
+        ctxt.num_columns = k;
+        ctxt.taxon_names = ts.names.clone();
+        ctxt.reordered_taxa = decomp.reordered_taxa.clone();
+        ctxt.source_headers = source_headers;
+        ctxt.left_out = left_out;
+        ctxt.alignment_checksum = input_checksum(input)?;
+        ctxt.tree_checksum = match tree {
+            Some(tree) => input_checksum(tree)?,
+            None => None,
+        };
+        let ctxt = write_ctxt(ctxt, outdir, options)?;
+        let inputs = std::iter::once(input).chain(tree.map(PathBuf::as_path));
+        write_provenance(outdir, &inputs.collect_vec(), options, started)?;
+        Ok(ctxt)
+    })
+}
@@ -1,13 +1,242 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs::{read, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use ndarray::{Array, Ix2};
-use serde::{Deserialize, Serialize};
+use crate::{
+    alphabet::{Alphabet, CharacterOptions, ResidueClasses},
+    output::{compressed_writer, input_checksum, OutputCompression},
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use clap::ArgEnum;
+use memmap2::Mmap;
+use ndarray::{Array, Array2, Ix2};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// summary statistics of the sequences of a subset, telling whether it is worth an HMM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubsetStats {
+    pub num_taxa: usize,
+    /// the mean number of counted characters per sequence
+    pub mean_length: f64,
+    /// the fraction of gaps in the columns with any counted character
+    pub gap_fraction: f64,
+    /// the number of columns with any counted character
+    pub nonempty_columns: usize,
+}
+
+impl SubsetStats {
+    /// the statistics of `num_taxa` sequences with `counts` counted characters in
+    /// every column
+    pub fn from_counts(num_taxa: usize, counts: &[u32]) -> Self {
+        let residues = counts.iter().map(|&c| c as u64).sum::<u64>();
+        let nonempty_columns = counts.iter().filter(|&&c| c > 0).count();
+        let cells = (num_taxa * nonempty_columns) as f64;
+        Self {
+            num_taxa,
+            mean_length: if num_taxa == 0 {
+                0.0
+            } else {
+                residues as f64 / num_taxa as f64
+            },
+            gap_fraction: if cells == 0.0 {
+                0.0
+            } else {
+                1.0 - residues as f64 / cells
+            },
+            nonempty_columns,
+        }
+    }
+}
+
+/// how diverse the sequences of a subset are, telling whether they are too diverse
+/// or too redundant to be worth an HMM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubsetQuality {
+    /// the mean identity of the pairs of sequences over the columns where both have
+    /// a counted character, `None` if no pair has any
+    pub mean_identity: Option<f64>,
+    /// the number of sequences counting those at least 80% identical to `m` others
+    /// as `1 / (m + 1)` each
+    pub effective_seqs: f64,
+    /// the number of sequences the scores were estimated from, fewer than those of
+    /// the subset if it is large
+    pub sampled: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HmmMeta {
     pub sequence_range: (usize, usize),
     pub chars_cnt: Vec<u32>,
     pub column_poitions: Vec<usize>,
+    /// ensemble levels (as subset size bounds) this HMM is a subset of, if built as an ensemble
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub levels: Vec<usize>,
+    /// rows outside `sequence_range` the HMM was also trained on, being the
+    /// phylogenetically nearest taxa; `chars_cnt` does not count them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub padding: Vec<usize>,
+    /// whether this HMM is built from quarantined outlier taxa only
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub outlier: bool,
+    /// human-readable name of the subset, from its MRCA in the guide tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// the columns of the alignment kept in the files of the subset, if those
+    /// with only gaps in the subset were left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subset_columns: Option<Vec<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<SubsetStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<SubsetQuality>,
+    /// where `column_poitions` and `chars_cnt` are in the counts file, if they were
+    /// written there instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counts_span: Option<CountsSpan>,
+    /// the counts of `chars_cnt` split by residue class (see `residue_classes` of the
+    /// context), a row of every class
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub class_counts: Vec<Vec<u32>>,
+    /// the columns of the HMM where fewer than `mask_occupancy` (of the context) of
+    /// its sequences have a counted character
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub masked_columns: Vec<usize>,
+    /// the weight of every sequence the HMM was trained on, those of
+    /// `sequence_range` and then those of `padding`, if any were computed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weights: Vec<f64>,
+}
+
+/// the file next to the metadata the column counts of the subsets can be written to
+pub const COUNTS_FILE: &str = "counts.bin";
+
+/// where the columns of an HMM and their counts are in the counts file: `len`
+/// column positions from byte `offset` on, then their `len` counts, all as
+/// little-endian `u32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CountsSpan {
+    pub offset: u64,
+    pub len: usize,
+}
+
+/// the counts file of a melt, memory-mapped
+#[derive(Clone)]
+pub struct MappedCounts(Arc<Mmap>);
+
+impl MappedCounts {
+    /// the `i`-th little-endian `u32` from byte `offset` on
+    fn get(&self, offset: u64, i: usize) -> u32 {
+        let start = offset as usize + 4 * i;
+        u32::from_le_bytes(self.0[start..start + 4].try_into().unwrap())
+    }
+
+    /// where `value` is among the `len` sorted `u32` from byte `offset` on, as
+    /// `slice::binary_search` tells
+    fn binary_search(&self, offset: u64, len: usize, value: u32) -> Result<usize, usize> {
+        let (mut lo, mut hi) = (0, len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.get(offset, mid).cmp(&value) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+}
+
+impl std::fmt::Debug for MappedCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MappedCounts({} bytes)", self.0.len())
+    }
+}
+
+impl PartialEq for MappedCounts {
+    fn eq(&self, other: &Self) -> bool {
+        self.0[..] == other.0[..]
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// `label` made file-system safe
+fn safe_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        .trim_matches('_')
+        .to_string()
+}
+
+/// file name of the HMM numbered `id`, carrying its label (made file-system safe) if any
+pub fn hmm_file_name(id: usize, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{}_{}.hmm", id, safe_label(label)),
+        None => format!("{}.hmm", id),
+    }
+}
+
+/// the fields a subset name template may have
+pub const NAME_TEMPLATE_FIELDS: &[&str] = &["{index}", "{size}", "{clade}"];
+
+/// file name, with `extension`, of a file of the subset `hmm` numbered `id`: the
+/// `template` with its `{index}`, `{size}` (number of sequences) and `{clade}`
+/// (label, made file-system safe, or `subset` if unlabeled) filled in, or else
+/// `{id}` (`hmm_file_name` for HMMs)
+pub fn subset_file_name(
+    template: Option<&str>,
+    id: usize,
+    hmm: &HmmMeta,
+    extension: &str,
+) -> String {
+    match template {
+        Some(template) => {
+            let (lb, ub) = hmm.sequence_range;
+            let clade = match hmm.label.as_deref().map(safe_label) {
+                Some(label) if !label.is_empty() => label,
+                _ => "subset".to_string(),
+            };
+            let name = template
+                .replace("{index}", &id.to_string())
+                .replace("{size}", &(ub - lb).to_string())
+                .replace("{clade}", &clade);
+            format!("{}.{}", name, extension)
+        }
+        None if extension == "hmm" => hmm_file_name(id, hmm.label.as_deref()),
+        None => format!("{}.{}", id, extension),
+    }
+}
+
+/// the directory under `subsets_root` with the files of the subset numbered `id`:
+/// `subsets_root` itself, or with `shards` its shard `id % shards` (zero-padded to
+/// the same width for every shard)
+pub fn subset_dir(subsets_root: &Path, shards: Option<usize>, id: usize) -> PathBuf {
+    match shards {
+        Some(shards) => {
+            let width = (shards - 1).max(1).to_string().len();
+            subsets_root.join(format!("{:0width$}", id % shards, width = width))
+        }
+        None => subsets_root.to_path_buf(),
+    }
 }
 
 impl HmmMeta {
@@ -20,6 +249,17 @@ impl HmmMeta {
             sequence_range,
             chars_cnt,
             column_poitions,
+            levels: vec![],
+            padding: vec![],
+            outlier: false,
+            label: None,
+            subset_columns: None,
+            stats: None,
+            quality: None,
+            counts_span: None,
+            class_counts: vec![],
+            masked_columns: vec![],
+            weights: vec![],
         }
     }
 
@@ -32,21 +272,520 @@ impl HmmMeta {
 pub struct TaxaHierarchy {
     pub reordered_taxa: Vec<usize>,
     pub taxa_positions: Vec<usize>,
+    /// every emitted subset in the order it was created (the whole set first, then
+    /// the two halves of each split in split order), which is also the HMM numbering
     pub decomposition_ranges: Vec<(usize, usize)>,
+    /// the leaf-level subsets, partitioning all taxa
+    pub disjoint_ranges: Vec<(usize, usize)>,
+    /// every split performed, in order, as (lb, mid, ub): [lb, ub) became [lb, mid) and [mid, ub)
+    pub splits: Vec<(usize, usize, usize)>,
+    /// ensemble levels each of `decomposition_ranges` belongs to (empty if not an ensemble)
+    pub range_levels: Vec<Vec<usize>>,
+    /// the subset of taxa kept out of the decomposition, always the last range
+    pub outliers: Option<(usize, usize)>,
+}
+
+impl TaxaHierarchy {
+    /// the disjoint subsets the decomposition would have stopped at under a
+    /// (coarser) size bound of `max_size`; subsets are only ever split
+    /// based on their own contents, so a finer run contains every coarser one
+    pub fn disjoint_ranges_at(&self, max_size: usize) -> Vec<(usize, usize)> {
+        let mids: AHashMap<(usize, usize), usize> = self
+            .splits
+            .iter()
+            .map(|&(lb, mid, ub)| ((lb, ub), mid))
+            .collect();
+        let mut res = vec![];
+        let mut stack = vec![(0usize, self.reordered_taxa.len())];
+        while let Some((lb, ub)) = stack.pop() {
+            match mids.get(&(lb, ub)) {
+                Some(&mid) if ub - lb > max_size => {
+                    stack.push((lb, mid));
+                    stack.push((mid, ub));
+                }
+                _ => res.push((lb, ub)),
+            }
+        }
+        res.sort_unstable();
+        res
+    }
+
+    /// appends `taxa` (ids) as a subset of their own after every other subset,
+    /// belonging to every ensemble level
+    pub fn append_outliers(&mut self, taxa: &[usize]) {
+        let lb = self.reordered_taxa.len();
+        for &t in taxa {
+            if t >= self.taxa_positions.len() {
+                self.taxa_positions.resize(t + 1, usize::MAX);
+            }
+            self.taxa_positions[t] = self.reordered_taxa.len();
+            self.reordered_taxa.push(t);
+        }
+        let range = (lb, self.reordered_taxa.len());
+        let mut levels = self
+            .range_levels
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        levels.sort_unstable();
+        levels.dedup();
+        self.decomposition_ranges.push(range);
+        self.disjoint_ranges.push(range);
+        self.range_levels.push(levels);
+        self.outliers = Some(range);
+    }
+
+    /// replaces the emitted ranges by the disjoint subsets of each level,
+    /// ranges shared between levels being emitted once
+    pub fn use_ensemble_levels(&mut self, levels: &[usize]) {
+        let mut levels = levels.to_vec();
+        levels.sort_unstable();
+        levels.dedup();
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        let mut range_levels: Vec<Vec<usize>> = vec![];
+        let mut index: AHashMap<(usize, usize), usize> = AHashMap::new();
+        for &level in &levels {
+            for range in self.disjoint_ranges_at(level) {
+                let i = *index.entry(range).or_insert_with(|| {
+                    ranges.push(range);
+                    range_levels.push(vec![]);
+                    ranges.len() - 1
+                });
+                range_levels[i].push(level);
+            }
+        }
+        self.decomposition_ranges = ranges;
+        self.range_levels = range_levels;
+    }
 }
 
+/// sequences of the input that shared a name, and how that was resolved
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DuplicateNames {
+    /// `keep-first` or `suffix-rename`
+    pub policy: String,
+    /// every name given to more than one sequence, with how many it was given to
+    pub names: Vec<(String, usize)>,
+    /// every sequence renamed (by `suffix-rename`), as the name it shared and the
+    /// one it was given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renamed: Vec<(String, String)>,
+}
+
+/// how the metadata of a melt is written: JSON, or MessagePack compressed by zstd,
+/// which is much smaller for large alignments
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MetadataFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl MetadataFormat {
+    /// the name of the metadata file of a melt in this format
+    pub fn file_name(self) -> &'static str {
+        match self {
+            MetadataFormat::Json => "melt.json",
+            MetadataFormat::Msgpack => "melt.msgpack.zst",
+        }
+    }
+}
+
+/// the names the metadata file of a melt can have, one for every `MetadataFormat`
+pub const METADATA_FILES: &[&str] = &["melt.json", "melt.msgpack.zst"];
+
+/// the first bytes of a zstd frame
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// the first bytes of a gzip member
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+impl MetadataFormat {
+    /// how metadata in this format is compressed in the output of a melt
+    pub fn compression(self) -> Option<OutputCompression> {
+        match self {
+            MetadataFormat::Json => None,
+            MetadataFormat::Msgpack => Some(OutputCompression::Zstd),
+        }
+    }
+}
+
+/// the metadata file of the melt in `outdir`, in whichever format it was written
+/// (`melt.json` if there is none)
+pub fn metadata_path(outdir: &Path) -> PathBuf {
+    METADATA_FILES
+        .iter()
+        .map(|name| outdir.join(name))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| outdir.join(METADATA_FILES[0]))
+}
+
+/// reads the metadata at `path`, telling its compression and then its format from
+/// its first bytes
+pub fn read_metadata<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let bytes = read(path).with_context(|| format!("cannot open {}", path.display()))?;
+    let mut decoded = vec![];
+    let bytes = if bytes.starts_with(ZSTD_MAGIC) {
+        zstd::stream::read::Decoder::new(bytes.as_slice())?.read_to_end(&mut decoded)?;
+        decoded
+    } else if bytes.starts_with(GZIP_MAGIC) {
+        flate2::read::MultiGzDecoder::new(bytes.as_slice()).read_to_end(&mut decoded)?;
+        decoded
+    } else {
+        bytes
+    };
+    // JSON metadata is an object, MessagePack metadata a map
+    let json = matches!(
+        bytes.iter().find(|b| !b.is_ascii_whitespace()),
+        Some(b'{') | None
+    );
+    if json {
+        serde_json::from_slice(&bytes)
+            .with_context(|| format!("cannot read the metadata {}", path.display()))
+    } else {
+        rmp_serde::from_slice(&bytes)
+            .with_context(|| format!("cannot read the metadata {}", path.display()))
+    }
+}
+
+/// writes the metadata `value` to `path` in `format`, compressed with `compression`
+/// (without adding its extension to `path`)
+pub fn save_metadata<T: Serialize>(
+    value: &T,
+    path: &Path,
+    format: MetadataFormat,
+    compression: Option<OutputCompression>,
+) -> anyhow::Result<()> {
+    let mut writer = compressed_writer(File::create(path)?, compression)?;
+    match format {
+        MetadataFormat::Json => serde_json::to_writer(&mut writer, value)?,
+        // fields by name, as those left out when empty would shift the others
+        MetadataFormat::Msgpack => {
+            value.serialize(&mut rmp_serde::Serializer::new(&mut writer).with_struct_map())?
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// writes the metadata `value` of the melt in `outdir` in `format`; where it was written
+pub fn write_metadata<T: Serialize>(
+    value: &T,
+    outdir: &Path,
+    format: MetadataFormat,
+) -> anyhow::Result<PathBuf> {
+    let path = outdir.join(format.file_name());
+    save_metadata(value, &path, format, format.compression())?;
+    Ok(path)
+}
+
+/// the version of the metadata written, raised whenever older metadata needs
+/// upgrading to be read right:
+/// - 0: the first
+/// - 1: the subsets have their `stats`
+/// - 2: the column counts of the subsets can be in the counts file
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CrucibleCtxt {
+    /// the `SCHEMA_VERSION` the metadata was written at
+    #[serde(default)]
     pub version: u32,
     pub metadata: Vec<HmmMeta>,
+    /// whether the HMMs partition the taxa instead of forming a hierarchy
+    #[serde(default)]
+    pub disjoint: bool,
+    /// names of the taxa moved to the outlier subset for their long terminal branches
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quarantined: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<DuplicateNames>,
+    /// which characters `chars_cnt` counts
+    #[serde(default, skip_serializing_if = "CharacterOptions::is_default")]
+    pub characters: CharacterOptions,
+    /// the alphabet of the HMMs, as given or detected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alphabet: Option<Alphabet>,
+    /// the number of directories the subset files are spread over (see `subset_dir`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shards: Option<usize>,
+    /// the template the subset files are named after (see `subset_file_name`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_template: Option<String>,
+    /// the `checksum` of every file written along with the metadata, by its path
+    /// relative to the output directory
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checksums: BTreeMap<String, String>,
+    /// the `checksum` of the input alignment (the whole one, for the loci of a
+    /// partitioned melt) and of the guide tree, if they were local files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alignment_checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_checksum: Option<String>,
+    /// the names of the taxa, by their ids
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub taxon_names: Vec<String>,
+    /// the ids of the taxa in the order of the decomposition, which the sequence
+    /// ranges of the subsets are over
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reordered_taxa: Vec<usize>,
+    /// the occupancy the `masked_columns` of the HMMs fall short of, if masked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask_occupancy: Option<f32>,
+    /// the number of columns of the whole alignment, 0 if not recorded
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub num_columns: usize,
+    /// the residue classes the HMMs have their `class_counts` by, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub residue_classes: Option<ResidueClasses>,
+    /// the loci of the alignment, if it is a concatenated one melted as a whole
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub loci: Vec<Locus>,
+    /// the header in the input of every sequence named otherwise in the melt (by
+    /// `--header-id`, `--rename`, Newick labels or `--duplicates`), by its name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub source_headers: BTreeMap<String, String>,
+    /// the headers of the records of the input left out of the melted alignment
+    /// (invalid, filtered, duplicate, fragmentary or unplaced ones)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub left_out: Vec<String>,
+    /// the columns of the input removed as insertions by `--lowercase strip`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stripped_columns: Vec<usize>,
+    /// the counts file, when the HMMs have theirs there and they were not loaded
+    #[serde(skip)]
+    pub counts: Option<MappedCounts>,
+}
+
+/// fails if the `checksum` of the input file at `path` is not the `recorded` one
+fn check_input(recorded: Option<&str>, kind: &str, path: &Path) -> anyhow::Result<()> {
+    let (recorded, actual) = match (recorded, input_checksum(path)?) {
+        (Some(recorded), Some(actual)) => (recorded, actual),
+        _ => return Ok(()),
+    };
+    if recorded != actual {
+        bail!(
+            "{} is not the {} the melt was made from (its checksum is {} instead of {})",
+            path.display(),
+            kind,
+            actual,
+            recorded
+        );
+    }
+    Ok(())
 }
 
 impl CrucibleCtxt {
-    pub fn new(metadata: Vec<HmmMeta>) -> Self {
+    pub fn new(metadata: Vec<HmmMeta>, disjoint: bool) -> Self {
         Self {
-            version: 0,
+            version: SCHEMA_VERSION,
             metadata,
+            disjoint,
+            quarantined: vec![],
+            duplicates: None,
+            characters: CharacterOptions::default(),
+            alphabet: None,
+            shards: None,
+            name_template: None,
+            checksums: BTreeMap::new(),
+            alignment_checksum: None,
+            tree_checksum: None,
+            taxon_names: vec![],
+            reordered_taxa: vec![],
+            mask_occupancy: None,
+            num_columns: 0,
+            residue_classes: None,
+            loci: vec![],
+            source_headers: BTreeMap::new(),
+            left_out: vec![],
+            stripped_columns: vec![],
+            counts: None,
+        }
+    }
+
+    /// reads the metadata of a melt, as written to `melt.json` (or in any other
+    /// `MetadataFormat`), upgrading it if written by an older version, and memory-maps
+    /// the column counts written to the counts file, if any, leaving them there for
+    /// `column_counts` to look up until `load_counts` copies them into the HMMs
+    pub fn from_path<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open(path.as_ref())
+    }
+
+    /// reads metadata written by `save`, in any format and compression; the same as
+    /// `from_path`
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_path(path)
+    }
+
+    /// writes the metadata to `path` in `format`, compressed with `compression`, for
+    /// `load` to read back; column counts still in a counts file are written along
+    pub fn save(
+        &self,
+        path: &Path,
+        format: MetadataFormat,
+        compression: Option<OutputCompression>,
+    ) -> anyhow::Result<()> {
+        if self.counts.is_none() {
+            return save_metadata(self, path, format, compression);
         }
+        let mut loaded = self.clone();
+        loaded.load_counts();
+        save_metadata(&loaded, path, format, compression)
+    }
+
+    /// reads the metadata as `from_path` does
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut ctxt: Self = read_metadata(path)?;
+        ctxt.upgrade()
+            .with_context(|| format!("cannot read the metadata {}", path.display()))?;
+        ctxt.map_counts(path.parent().unwrap_or_else(|| Path::new(".")))?;
+        Ok(ctxt)
+    }
+
+    /// memory-maps the counts file in `dir` if the HMMs have their counts there
+    pub fn map_counts(&mut self, dir: &Path) -> anyhow::Result<()> {
+        if self.metadata.iter().all(|m| m.counts_span.is_none()) {
+            return Ok(());
+        }
+        let path = dir.join(COUNTS_FILE);
+        let file = File::open(&path).with_context(|| format!("cannot open {}", path.display()))?;
+        // the counts file is only ever written whole, before the metadata
+        let map = unsafe { Mmap::map(&file)? };
+        let needed = self
+            .metadata
+            .iter()
+            .filter_map(|m| m.counts_span)
+            .map(|span| span.offset as usize + 8 * span.len)
+            .max()
+            .unwrap_or(0);
+        if map.len() < needed {
+            bail!(
+                "{} has {} bytes, but the metadata refers to {}",
+                path.display(),
+                map.len(),
+                needed
+            );
+        }
+        self.counts = Some(MappedCounts(Arc::new(map)));
+        Ok(())
+    }
+
+    /// copies the column counts out of the counts file into the HMMs, unmapping it
+    pub fn load_counts(&mut self) {
+        let counts = match self.counts.take() {
+            Some(counts) => counts,
+            None => return,
+        };
+        for hmm in &mut self.metadata {
+            if let Some(CountsSpan { offset, len }) = hmm.counts_span.take() {
+                hmm.column_poitions = (0..len).map(|i| counts.get(offset, i) as usize).collect();
+                hmm.chars_cnt = (0..len).map(|i| counts.get(offset, len + i)).collect();
+            }
+        }
+    }
+
+    /// the columns of the HMM numbered `hmm_idx` with their character counts, from
+    /// the counts file if it is mapped
+    pub fn column_counts(&self, hmm_idx: usize) -> Box<dyn Iterator<Item = (usize, u32)> + '_> {
+        let hmm = &self.metadata[hmm_idx];
+        match (&self.counts, hmm.counts_span) {
+            (Some(counts), Some(CountsSpan { offset, len })) => Box::new(
+                (0..len)
+                    .map(move |i| (counts.get(offset, i) as usize, counts.get(offset, len + i))),
+            ),
+            _ => Box::new(
+                hmm.column_poitions
+                    .iter()
+                    .copied()
+                    .zip(hmm.chars_cnt.iter().copied()),
+            ),
+        }
+    }
+
+    /// the number of columns of the HMM numbered `hmm_idx`, as `column_counts` has
+    pub fn num_columns_of(&self, hmm_idx: usize) -> usize {
+        let hmm = &self.metadata[hmm_idx];
+        match (&self.counts, hmm.counts_span) {
+            (Some(_), Some(span)) => span.len,
+            _ => hmm.column_poitions.len(),
+        }
+    }
+
+    /// the character count of the HMM numbered `hmm_idx` in the alignment column
+    /// `col`, found by binary search over its columns; `None` if it does not have it
+    pub fn column_count_at(&self, hmm_idx: usize, col: usize) -> Option<u32> {
+        let hmm = &self.metadata[hmm_idx];
+        match (&self.counts, hmm.counts_span) {
+            (Some(counts), Some(CountsSpan { offset, len })) => {
+                let col = u32::try_from(col).ok()?;
+                let i = counts.binary_search(offset, len, col).ok()?;
+                Some(counts.get(offset, len + i))
+            }
+            _ => {
+                let i = hmm.column_poitions.binary_search(&col).ok()?;
+                Some(hmm.chars_cnt[i])
+            }
+        }
+    }
+
+    /// writes the column counts of the HMMs to the counts file in `outdir`; the
+    /// metadata referring to it instead of having them
+    pub fn write_counts(&self, outdir: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(outdir.join(COUNTS_FILE))?);
+        let mut split = self.clone();
+        let mut offset = 0u64;
+        for hmm in &mut split.metadata {
+            let len = hmm.chars_cnt.len();
+            for &j in &hmm.column_poitions {
+                writer.write_all(&(j as u32).to_le_bytes())?;
+            }
+            for &c in &hmm.chars_cnt {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+            hmm.counts_span = Some(CountsSpan { offset, len });
+            hmm.column_poitions = vec![];
+            hmm.chars_cnt = vec![];
+            offset += 8 * len as u64;
+        }
+        writer.flush()?;
+        Ok(split)
+    }
+
+    /// fails if `path` is not the input alignment the melt was made from, going by
+    /// `alignment_checksum`; passes if none was recorded or `path` is not a local file
+    pub fn check_alignment(&self, path: &Path) -> anyhow::Result<()> {
+        check_input(self.alignment_checksum.as_deref(), "alignment", path)
+    }
+
+    /// fails if `path` is not the guide tree the melt was made from, as
+    /// `check_alignment` does for the alignment
+    pub fn check_tree(&self, path: &Path) -> anyhow::Result<()> {
+        check_input(self.tree_checksum.as_deref(), "tree", path)
+    }
+
+    /// brings metadata of an older `SCHEMA_VERSION` up to the current one, failing
+    /// for metadata of a newer one
+    pub fn upgrade(&mut self) -> anyhow::Result<()> {
+        if self.version > SCHEMA_VERSION {
+            bail!(
+                "the metadata is of version {}, newer than the {} this crucible reads",
+                self.version,
+                SCHEMA_VERSION
+            );
+        }
+        if self.version < 1 {
+            for hmm in &mut self.metadata {
+                if hmm.stats.is_none() {
+                    hmm.stats = Some(SubsetStats::from_counts(hmm.num_seqs(), &hmm.chars_cnt));
+                }
+            }
+        }
+        self.version = SCHEMA_VERSION;
+        Ok(())
     }
 
     pub fn retrieve_nchars_noalloc(
@@ -54,11 +793,193 @@ impl CrucibleCtxt {
         sequence_range: (usize, usize),
         buf: &mut [u32],
     ) {
-        let shape = nchars_partial_sum.shape();
         let (start, end) = sequence_range;
-        let k = shape[1];
-        for i in 0..k {
-            buf[i] = nchars_partial_sum[(end, i)] - nchars_partial_sum[(start, i)];
+        let (start, end) = (nchars_partial_sum.row(start), nchars_partial_sum.row(end));
+        for ((b, &e), &s) in buf.iter_mut().zip(end).zip(start) {
+            *b = e - s;
+        }
+    }
+
+    /// the number of columns of the whole alignment, as recorded or else as far as
+    /// the HMMs have columns
+    pub fn alignment_width(&self) -> usize {
+        if self.num_columns > 0 {
+            return self.num_columns;
+        }
+        (0..self.num_hmms())
+            .filter_map(|i| self.column_counts(i).map(|(j, _)| j + 1).last())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// the character counts of every HMM (a row each) in every column of the
+    /// alignment, filled in one pass over the counts stored
+    pub fn all_nchars(&self) -> Array2<u32> {
+        let mut nchars = Array2::<u32>::zeros((self.num_hmms(), self.alignment_width()));
+        for (i, mut row) in nchars.outer_iter_mut().enumerate() {
+            for (j, c) in self.column_counts(i) {
+                row[j] = c;
+            }
+        }
+        nchars
+    }
+
+    /// the character counts of the rows `lb..ub` (of the sorted sequences) in every
+    /// column, from the prefix sums `nchars_partial_sum`, into `buf`; fails instead of
+    /// panicking on a range or buffer not fitting them
+    pub fn retrieve_nchars_range(
+        nchars_partial_sum: &Array<u32, Ix2>,
+        lb: usize,
+        ub: usize,
+        buf: &mut [u32],
+    ) -> anyhow::Result<()> {
+        let shape = nchars_partial_sum.shape();
+        let (rows, k) = (shape[0], shape[1]);
+        if lb > ub || ub >= rows {
+            bail!(
+                "the range {}..{} is not within the {} sequences",
+                lb,
+                ub,
+                rows.saturating_sub(1)
+            );
+        }
+        if buf.len() != k {
+            bail!(
+                "the buffer has room for {} columns instead of {}",
+                buf.len(),
+                k
+            );
+        }
+        Self::retrieve_nchars_noalloc(nchars_partial_sum, (lb, ub), buf);
+        Ok(())
+    }
+
+    /// the character counts of the HMM numbered `hmm_idx` in every column of the
+    /// alignment, or of the locus numbered `locus` (in `loci`) if given, `buf` being
+    /// as wide as it; the metadata stores those of its columns only
+    pub fn retrieve_nchars(&self, hmm_idx: usize, locus: Option<usize>, buf: &mut [u32]) {
+        buf.fill(0);
+        match locus {
+            Some(locus) => {
+                let columns = &self.loci[locus].columns;
+                for (j, c) in self.column_counts(hmm_idx) {
+                    if let Ok(i) = columns.binary_search(&j) {
+                        buf[i] = c;
+                    }
+                }
+            }
+            None => {
+                for (j, c) in self.column_counts(hmm_idx) {
+                    buf[j] = c;
+                }
+            }
+        }
+    }
+
+    /// the number of the locus named `name` in `loci`
+    pub fn locus_index(&self, name: &str) -> Option<usize> {
+        self.loci.iter().position(|locus| locus.name == name)
+    }
+
+    pub fn hmm_file_name(&self, hmm_id: u32) -> String {
+        self.subset_file_name(hmm_id as usize, "hmm")
+    }
+
+    /// the name of the file with `extension` of the subset numbered `id`
+    pub fn subset_file_name(&self, id: usize, extension: &str) -> String {
+        subset_file_name(
+            self.name_template.as_deref(),
+            id,
+            &self.metadata[id],
+            extension,
+        )
+    }
+
+    /// the path of the HMM numbered `hmm_id` melted into `base_dir`
+    pub fn hmm_path(&self, base_dir: &Path, hmm_id: u32) -> PathBuf {
+        subset_dir(&base_dir.join("subsets"), self.shards, hmm_id as usize)
+            .join(self.hmm_file_name(hmm_id))
+    }
+
+    /// the names of the taxa in the HMM numbered `hmm_idx`, in the order of the
+    /// decomposition; empty if the metadata does not have them
+    pub fn subset_taxa(&self, hmm_idx: usize) -> Vec<&str> {
+        if self.reordered_taxa.is_empty() {
+            return vec![];
+        }
+        let (lb, ub) = self.metadata[hmm_idx].sequence_range;
+        self.reordered_taxa[lb..ub]
+            .iter()
+            .map(|&t| self.taxon_names[t].as_str())
+            .collect()
+    }
+
+    /// the fraction of the sequences of the HMM numbered `hmm_idx` with a counted
+    /// character in each of its columns (as in `column_poitions`)
+    pub fn column_occupancy(&self, hmm_idx: usize) -> Vec<f32> {
+        let n = self.metadata[hmm_idx].num_seqs().max(1) as f32;
+        self.column_counts(hmm_idx)
+            .map(|(_, c)| c as f32 / n)
+            .collect()
+    }
+
+    /// the fraction of the sequences of the HMM numbered `hmm_idx` with a counted
+    /// character in the alignment column `col`, 0 if it is not a column of the HMM
+    pub fn column_occupancy_at(&self, hmm_idx: usize, col: usize) -> f32 {
+        let n = self.metadata[hmm_idx].num_seqs().max(1) as f32;
+        self.column_count_at(hmm_idx, col)
+            .map_or(0.0, |c| c as f32 / n)
+    }
+
+    /// the columns of the HMM numbered `hmm_idx` where fewer than `min_occupancy` of
+    /// its sequences have a counted character, e.g. for masking them before hmmbuild
+    pub fn low_occupancy_columns(&self, hmm_idx: usize, min_occupancy: f32) -> Vec<usize> {
+        let n = self.metadata[hmm_idx].num_seqs().max(1) as f32;
+        self.column_counts(hmm_idx)
+            .filter(|&(_, c)| (c as f32 / n) < min_occupancy)
+            .map(|(j, _)| j)
+            .collect()
+    }
+
+    /// the HMMs with a counted character in the alignment column `col`, in order
+    pub fn covering_hmms(&self, col: usize) -> Vec<usize> {
+        (0..self.metadata.len())
+            .filter(|&i| self.column_occupancy_at(i, col) > 0.0)
+            .collect()
+    }
+
+    /// the HMM owning the alignment column `col`: the one most occupying it, the
+    /// smallest of them on ties (and the first of those); `None` if none covers it
+    pub fn owning_hmm(&self, col: usize) -> Option<usize> {
+        self.covering_hmms(col).into_iter().max_by(|&a, &b| {
+            self.column_occupancy_at(a, col)
+                .total_cmp(&self.column_occupancy_at(b, col))
+                .then(
+                    self.metadata[b]
+                        .num_seqs()
+                        .cmp(&self.metadata[a].num_seqs()),
+                )
+                .then(b.cmp(&a))
+        })
+    }
+
+    /// the column of the whole alignment that column `local_col` of the files of the
+    /// HMM numbered `hmm_idx` is, going by its `subset_columns` if they were trimmed;
+    /// `None` past their last column
+    pub fn to_global_column(&self, hmm_idx: usize, local_col: usize) -> Option<usize> {
+        match &self.metadata[hmm_idx].subset_columns {
+            Some(columns) => columns.get(local_col).copied(),
+            None => (local_col < self.alignment_width()).then_some(local_col),
+        }
+    }
+
+    /// the inverse of `to_global_column`: the column of the files of the HMM
+    /// numbered `hmm_idx` that column `global_col` of the whole alignment is, `None`
+    /// if it was trimmed from them
+    pub fn to_local_column(&self, hmm_idx: usize, global_col: usize) -> Option<usize> {
+        match &self.metadata[hmm_idx].subset_columns {
+            Some(columns) => columns.binary_search(&global_col).ok(),
+            None => (global_col < self.alignment_width()).then_some(global_col),
         }
     }
 
@@ -67,10 +988,101 @@ impl CrucibleCtxt {
     }
 
     pub fn num_consensus_columns(&self) -> usize {
-        self.metadata[0].column_poitions.len()
+        self.num_columns_of(0)
+    }
+}
+
+/// a locus of a concatenated alignment melted as a whole, as its partition file
+/// delimits it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Locus {
+    pub name: String,
+    /// the columns of the alignment making up the locus, in order
+    pub columns: Vec<usize>,
+}
+
+/// a locus of a partitioned alignment, melted on its own into the directory `name`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocusCtxt {
+    pub name: String,
+    /// the columns of the whole alignment making up the locus, which the column
+    /// positions of its HMMs index into
+    pub columns: Vec<usize>,
+    pub ctxt: CrucibleCtxt,
+}
+
+/// the metadata of a partitioned alignment, one context per locus
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartitionedCtxt {
+    #[serde(default)]
+    pub version: u32,
+    pub loci: Vec<LocusCtxt>,
+}
+
+impl PartitionedCtxt {
+    pub fn new(loci: Vec<LocusCtxt>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            loci,
+        }
+    }
+
+    /// reads the metadata of a partitioned melt, upgrading that of every locus as
+    /// `CrucibleCtxt::from_path` does
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let mut ctxt: Self = read_metadata(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for locus in &mut ctxt.loci {
+            locus
+                .ctxt
+                .upgrade()
+                .with_context(|| format!("cannot read the metadata {}", path.display()))?;
+            locus.ctxt.map_counts(&dir.join(&locus.name))?;
+        }
+        ctxt.version = SCHEMA_VERSION;
+        Ok(ctxt)
     }
 }
 
+/// how a melt was run, as written to `run.json` next to its `melt.json`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Provenance {
+    pub crate_version: String,
+    pub command_line: Vec<String>,
+    /// the `checksum` of every local input file, by its path as given
+    pub inputs: BTreeMap<String, String>,
+    /// which characters counted and which were gaps
+    pub characters: CharacterOptions,
+    /// when the melt started and finished, in seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+}
+
+/// a subset a melt would make, as planned by `--dry-run`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlannedSubset {
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub outlier: bool,
+    /// the share of the input alignment's bytes that its sequences take up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_bytes: Option<u64>,
+}
+
+/// the decomposition a melt would make, without the alignment having been read
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DecompositionPlan {
+    pub num_taxa: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<usize>,
+    pub subsets: Vec<PlannedSubset>,
+    /// the bytes of all subset alignments together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_bytes: Option<u64>,
+}
+
 pub struct AdderPayload {
     /// a list of top hits tuple of HMM id and adjusted bitscore for each sequence
     pub sequence_tophits: Vec<Vec<(u32, f64)>>,
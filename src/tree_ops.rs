@@ -0,0 +1,567 @@
+use crate::newick::newick_label;
+use ahash::{AHashMap, AHashSet};
+use anyhow::bail;
+use ogcat::ogtree::*;
+use ordered_float::OrderedFloat;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{cmp::Reverse, collections::BinaryHeap};
+use tracing::warn;
+
+/// an owned copy of a tree's topology that can be restructured and then
+/// parsed back into an ogcat `Tree` sharing the same taxon set
+pub struct EditableTree {
+    pub children: Vec<Vec<usize>>,
+    /// taxon id of each node, negative for internal nodes
+    pub taxa: Vec<i32>,
+    pub lengths: Vec<f64>,
+    pub support: Vec<f64>,
+    pub root: usize,
+}
+
+impl EditableTree {
+    pub fn from_tree(tree: &Tree) -> Self {
+        let n = tree.taxa.len();
+        Self {
+            children: (0..n).map(|i| tree.children(i).collect()).collect(),
+            taxa: tree.taxa.clone(),
+            lengths: tree.lengths.clone(),
+            support: tree.support.clone(),
+            root: 0,
+        }
+    }
+
+    /// adds an internal node with a zero-length parent edge
+    pub fn add_node(&mut self, children: Vec<usize>) -> usize {
+        self.children.push(children);
+        self.taxa.push(-1);
+        self.lengths.push(0.0);
+        self.support.push(f64::NAN);
+        self.children.len() - 1
+    }
+
+    pub fn is_leaf(&self, node: usize) -> bool {
+        self.children[node].is_empty()
+    }
+
+    /// nodes reachable from the root, children before parents
+    pub fn postorder(&self) -> Vec<usize> {
+        let mut order = vec![];
+        let mut stack = vec![self.root];
+        while let Some(v) = stack.pop() {
+            order.push(v);
+            stack.extend(self.children[v].iter().copied());
+        }
+        order.reverse();
+        order
+    }
+
+    pub fn parents(&self) -> Vec<Option<usize>> {
+        let mut parents = vec![None; self.children.len()];
+        for v in self.postorder() {
+            for &c in &self.children[v] {
+                parents[c] = Some(v);
+            }
+        }
+        parents
+    }
+
+    /// length of the edge above `node`, if known
+    pub fn edge_length(&self, node: usize) -> Option<f64> {
+        let l = self.lengths[node];
+        if l.is_finite() && l >= 0.0 {
+            Some(l)
+        } else {
+            None
+        }
+    }
+
+    /// undirected view of the tree: (neighbor, length, support) per node, edge
+    /// attributes being those stored at the lower endpoint
+    fn adjacency(&self) -> Vec<Vec<(usize, f64, f64)>> {
+        let mut adj = vec![vec![]; self.children.len()];
+        for v in self.postorder() {
+            for &c in &self.children[v] {
+                adj[v].push((c, self.lengths[c], self.support[c]));
+                adj[c].push((v, self.lengths[c], self.support[c]));
+            }
+        }
+        adj
+    }
+
+    /// drops internal nodes left without children and splices out nodes with a single child
+    pub fn tidy(&mut self) {
+        for v in self.postorder() {
+            let kids = std::mem::take(&mut self.children[v]);
+            let mut tidied = Vec::with_capacity(kids.len());
+            for c in kids {
+                if self.children[c].is_empty() && self.taxa[c] < 0 {
+                    continue;
+                }
+                if self.children[c].len() == 1 {
+                    let g = self.children[c][0];
+                    self.lengths[g] = match (self.edge_length(g), self.edge_length(c)) {
+                        (None, None) => f64::NAN,
+                        (a, b) => a.unwrap_or(0.0) + b.unwrap_or(0.0),
+                    };
+                    tidied.push(g);
+                } else {
+                    tidied.push(c);
+                }
+            }
+            self.children[v] = tidied;
+        }
+        while self.children[self.root].len() == 1 {
+            self.root = self.children[self.root][0];
+        }
+    }
+
+    /// places a new root on the edge above `node`, `dist_from_node` away from it
+    pub fn reroot_on_edge(&self, node: usize, dist_from_node: f64) -> EditableTree {
+        let parents = self.parents();
+        let p = parents[node].expect("cannot reroot above the root");
+        let mut adj = self.adjacency();
+        adj[node].retain(|&(v, _, _)| v != p);
+        adj[p].retain(|&(v, _, _)| v != node);
+        let n = self.children.len();
+        let mut rerooted = EditableTree {
+            children: vec![vec![]; n + 1],
+            taxa: self.taxa.iter().copied().chain([-1]).collect(),
+            lengths: vec![f64::NAN; n + 1],
+            support: vec![f64::NAN; n + 1],
+            root: n,
+        };
+        rerooted.children[n] = vec![node, p];
+        if let Some(total) = self.edge_length(node) {
+            let d = dist_from_node.clamp(0.0, total);
+            rerooted.lengths[node] = d;
+            rerooted.lengths[p] = total - d;
+        }
+        rerooted.support[node] = self.support[node];
+        rerooted.support[p] = self.support[node];
+        let mut stack = vec![(node, n), (p, n)];
+        while let Some((v, from)) = stack.pop() {
+            for &(c, length, support) in &adj[v] {
+                if c == from {
+                    continue;
+                }
+                rerooted.children[v].push(c);
+                rerooted.lengths[c] = length;
+                rerooted.support[c] = support;
+                stack.push((c, v));
+            }
+        }
+        rerooted.tidy();
+        rerooted
+    }
+
+    /// the lowest node having every one of `nodes` below it
+    pub fn mrca(&self, nodes: &[usize]) -> usize {
+        let targets: AHashSet<usize> = nodes.iter().copied().collect();
+        let mut below = vec![0usize; self.children.len()];
+        for v in self.postorder() {
+            below[v] = targets.contains(&v) as usize
+                + self.children[v].iter().map(|&c| below[c]).sum::<usize>();
+        }
+        let mut v = self.root;
+        while let Some(&c) = self.children[v]
+            .iter()
+            .find(|&&c| below[c] == targets.len())
+        {
+            v = c;
+        }
+        v
+    }
+
+    pub fn num_leaves_below(&self, node: usize) -> usize {
+        let mut cnt = 0;
+        let mut stack = vec![node];
+        while let Some(v) = stack.pop() {
+            if self.is_leaf(v) {
+                cnt += 1;
+            }
+            stack.extend(self.children[v].iter().copied());
+        }
+        cnt
+    }
+
+    /// distances (unit edge lengths if `unit`) and predecessors of every node on
+    /// its path from `start`
+    fn distances_from(&self, start: usize, unit: bool) -> (Vec<f64>, Vec<Option<usize>>) {
+        let adj = self.adjacency();
+        let n = self.children.len();
+        let mut dist = vec![f64::INFINITY; n];
+        let mut pred = vec![None; n];
+        dist[start] = 0.0;
+        let mut stack = vec![start];
+        while let Some(v) = stack.pop() {
+            for &(c, length, _) in &adj[v] {
+                if dist[c].is_finite() {
+                    continue;
+                }
+                let l = if unit {
+                    1.0
+                } else if length.is_finite() && length > 0.0 {
+                    length
+                } else {
+                    0.0
+                };
+                dist[c] = dist[v] + l;
+                pred[c] = Some(v);
+                stack.push(c);
+            }
+        }
+        (dist, pred)
+    }
+
+    /// the (at most) `k` leaves closest by path length to any of `sources`,
+    /// nearest first, excluding the sources themselves
+    pub fn nearest_leaves(&self, sources: &[usize], k: usize) -> Vec<usize> {
+        let adj = self.adjacency();
+        let mut done = vec![false; self.children.len()];
+        let mut heap = sources
+            .iter()
+            .map(|&v| Reverse((OrderedFloat(0.0), v)))
+            .collect::<BinaryHeap<_>>();
+        let sources: AHashSet<usize> = sources.iter().copied().collect();
+        let mut res = vec![];
+        while let Some(Reverse((OrderedFloat(d), v))) = heap.pop() {
+            if done[v] {
+                continue;
+            }
+            done[v] = true;
+            if self.is_leaf(v) && !sources.contains(&v) {
+                res.push(v);
+                if res.len() == k {
+                    break;
+                }
+            }
+            for &(c, length, _) in &adj[v] {
+                if !done[c] {
+                    let l = if length.is_finite() && length > 0.0 {
+                        length
+                    } else {
+                        0.0
+                    };
+                    heap.push(Reverse((OrderedFloat(d + l), c)));
+                }
+            }
+        }
+        res
+    }
+
+    fn farthest_leaf(&self, dist: &[f64]) -> usize {
+        self.postorder()
+            .into_iter()
+            .filter(|&v| self.is_leaf(v))
+            .max_by(|&a, &b| dist[a].total_cmp(&dist[b]))
+            .expect("tree has no leaves")
+    }
+
+    /// re-roots at the middle of the longest leaf-to-leaf path, counting edges
+    /// instead when the tree carries no branch lengths
+    pub fn midpoint_rooted(&self) -> EditableTree {
+        let unit = self
+            .postorder()
+            .iter()
+            .all(|&v| self.edge_length(v).unwrap_or(0.0) == 0.0);
+        let first_leaf = self.farthest_leaf(&vec![0.0; self.children.len()]);
+        let (dist, _) = self.distances_from(first_leaf, unit);
+        let b = self.farthest_leaf(&dist);
+        let (dist_b, pred) = self.distances_from(b, unit);
+        let c = self.farthest_leaf(&dist_b);
+        let half = dist_b[c] / 2.0;
+        let mut y = c;
+        while let Some(x) = pred[y] {
+            if dist_b[x] <= half {
+                let parents = self.parents();
+                return if parents[y] == Some(x) {
+                    self.reroot_on_edge(y, dist_b[y] - half)
+                } else {
+                    self.reroot_on_edge(x, half - dist_b[x])
+                };
+            }
+            y = x;
+        }
+        unreachable!("midpoint lies on the path between the two farthest leaves")
+    }
+
+    fn write_annotations(&self, out: &mut String, node: usize) {
+        if !self.is_leaf(node) && self.support[node].is_finite() && self.support[node] >= 0.0 {
+            out.push_str(&format!("{}", self.support[node]));
+        }
+        if node != self.root && self.lengths[node].is_finite() && self.lengths[node] >= 0.0 {
+            out.push_str(&format!(":{}", self.lengths[node]));
+        }
+    }
+
+    /// serializes the tree without recursion, as decompositions can be run on caterpillars
+    pub fn to_newick(&self, names: &[String]) -> String {
+        let mut out = String::new();
+        let mut stack = vec![(self.root, 0usize)];
+        while let Some((node, next_child)) = stack.pop() {
+            if self.is_leaf(node) {
+                if self.taxa[node] >= 0 {
+                    out.push_str(&names[self.taxa[node] as usize]);
+                }
+                self.write_annotations(&mut out, node);
+                continue;
+            }
+            if next_child == 0 {
+                out.push('(');
+            }
+            if next_child < self.children[node].len() {
+                if next_child > 0 {
+                    out.push(',');
+                }
+                stack.push((node, next_child + 1));
+                stack.push((self.children[node][next_child], 0));
+            } else {
+                out.push(')');
+                self.write_annotations(&mut out, node);
+            }
+        }
+        out.push(';');
+        out
+    }
+
+    pub fn into_tree(self, taxon_set: &mut TaxonSet) -> Tree {
+        let newick = self.to_newick(&taxon_set.names);
+        parse_newick(taxon_set, &newick)
+    }
+}
+
+/// max number of children before a node counts as a multifurcation; the root
+/// of an unrooted tree conventionally has three
+fn max_binary_degree(is_root: bool) -> usize {
+    if is_root {
+        3
+    } else {
+        2
+    }
+}
+
+/// the leaf node of every taxon id, `None` for taxa absent from `tree`
+pub fn leaf_nodes(tree: &Tree, ntaxa: usize) -> Vec<Option<usize>> {
+    let mut leaves = vec![None; ntaxa];
+    for v in tree.postorder() {
+        if tree.is_leaf(v) {
+            leaves[tree.taxa[v] as usize] = Some(v);
+        }
+    }
+    leaves
+}
+
+/// the lowest node of `tree` having every one of `nodes` below it; `None` if there
+/// are no nodes
+pub fn tree_mrca(tree: &Tree, nodes: &[usize]) -> Option<usize> {
+    let (&first, rest) = nodes.split_first()?;
+    let path = std::iter::once(first)
+        .chain(tree.ancestors(first))
+        .collect::<Vec<_>>();
+    let mut highest = 0;
+    for &v in rest {
+        let meet = std::iter::once(v)
+            .chain(tree.ancestors(v))
+            .find_map(|a| path.iter().position(|&p| p == a))?;
+        highest = highest.max(meet);
+    }
+    Some(path[highest])
+}
+
+/// taxa whose terminal branch is longer than `factor` times the median terminal
+/// branch; none if the tree has no positive terminal branch lengths
+pub fn long_branch_taxa(tree: &Tree, factor: f64) -> Vec<usize> {
+    let mut pendant = tree
+        .postorder()
+        .filter(|&v| tree.is_leaf(v))
+        .map(|v| (tree.taxa[v] as usize, tree.lengths[v]))
+        .filter(|&(_, l)| l.is_finite() && l >= 0.0)
+        .collect::<Vec<_>>();
+    if pendant.is_empty() {
+        return vec![];
+    }
+    pendant.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let median = pendant[pendant.len() / 2].1;
+    if median <= 0.0 {
+        warn!("median terminal branch length is zero, not quarantining any taxa");
+        return vec![];
+    }
+    let mut outliers = pendant
+        .into_iter()
+        .filter(|&(_, l)| l > factor * median)
+        .map(|(t, _)| t)
+        .collect::<Vec<_>>();
+    outliers.sort_unstable();
+    outliers
+}
+
+/// the tree without the leaves of the `removed` taxa, tidied up afterwards
+pub fn prune_taxa(tree: &Tree, taxon_set: &mut TaxonSet, removed: &AHashSet<usize>) -> Tree {
+    let mut editable = EditableTree::from_tree(tree);
+    for v in 0..editable.children.len() {
+        let taxa = &editable.taxa;
+        editable.children[v].retain(|&c| taxa[c] < 0 || !removed.contains(&(taxa[c] as usize)));
+    }
+    editable.tidy();
+    editable.into_tree(taxon_set)
+}
+
+/// the tree with a leaf for every one of the `copies` (the name of a taxon and that
+/// of its copy) next to the leaf of the taxon, the copies and the leaf hanging by
+/// zero-length edges from a node put where the leaf was; copies of taxa not in the
+/// tree are left out. Returns the tree and the number of copies grafted
+pub fn graft_copies(
+    tree: &Tree,
+    taxon_set: &mut TaxonSet,
+    copies: &[(String, String)],
+) -> (Tree, usize) {
+    let mut editable = EditableTree::from_tree(tree);
+    let parents = editable.parents();
+    let leaves: AHashMap<i32, usize> = editable
+        .postorder()
+        .into_iter()
+        .filter(|&v| editable.is_leaf(v) && editable.taxa[v] >= 0)
+        .map(|v| (editable.taxa[v], v))
+        .collect();
+    // the node joining each leaf with its copies
+    let mut joints: AHashMap<usize, usize> = AHashMap::new();
+    let mut grafted = 0;
+    for (name, copy) in copies {
+        let leaf = match taxon_set.to_id.get(name) {
+            Some(&t) => match leaves.get(&(t as i32)) {
+                Some(&leaf) => leaf,
+                None => continue,
+            },
+            None => continue,
+        };
+        let joint = *joints.entry(leaf).or_insert_with(|| {
+            let joint = editable.add_node(vec![leaf]);
+            editable.lengths.swap(joint, leaf);
+            match parents[leaf] {
+                Some(p) => {
+                    for c in editable.children[p].iter_mut().filter(|c| **c == leaf) {
+                        *c = joint;
+                    }
+                }
+                None => editable.root = joint,
+            }
+            joint
+        });
+        let copy_leaf = editable.add_node(vec![]);
+        editable.taxa[copy_leaf] = taxon_set.request(copy.clone()) as i32;
+        editable.children[joint].push(copy_leaf);
+        grafted += 1;
+    }
+    (editable.into_tree(taxon_set), grafted)
+}
+
+/// the Newick string of the subtree of `tree` induced by the `kept` taxa, named by `names`
+pub fn induced_subtree_newick(tree: &Tree, names: &[String], kept: &AHashSet<usize>) -> String {
+    let mut editable = EditableTree::from_tree(tree);
+    for v in 0..editable.children.len() {
+        let taxa = &editable.taxa;
+        editable.children[v].retain(|&c| taxa[c] < 0 || kept.contains(&(taxa[c] as usize)));
+    }
+    editable.tidy();
+    editable.to_newick(names)
+}
+
+pub fn is_multifurcation(tree: &Tree, node: usize) -> bool {
+    tree.children(node).count() > max_binary_degree(node == 0)
+}
+
+/// randomly resolves every multifurcation into a binary subtree joined by zero-length edges
+pub fn resolve_polytomies(tree: &Tree, taxon_set: &mut TaxonSet, seed: u64) -> Tree {
+    let mut editable = EditableTree::from_tree(tree);
+    let mut rng = StdRng::seed_from_u64(seed);
+    for v in 0..editable.children.len() {
+        let max_degree = max_binary_degree(v == editable.root);
+        while editable.children[v].len() > max_degree {
+            let i = rng.gen_range(0..editable.children[v].len());
+            let a = editable.children[v].swap_remove(i);
+            let j = rng.gen_range(0..editable.children[v].len());
+            let b = editable.children[v].swap_remove(j);
+            let joined = editable.add_node(vec![a, b]);
+            editable.children[v].push(joined);
+        }
+    }
+    editable.into_tree(taxon_set)
+}
+
+pub fn midpoint_root(tree: &Tree, taxon_set: &mut TaxonSet) -> Tree {
+    EditableTree::from_tree(tree)
+        .midpoint_rooted()
+        .into_tree(taxon_set)
+}
+
+/// roots on the edge above the MRCA of the `outgroup` taxa
+pub fn outgroup_root(
+    tree: &Tree,
+    taxon_set: &mut TaxonSet,
+    outgroup: &[String],
+) -> anyhow::Result<Tree> {
+    let mut ids = AHashSet::new();
+    for name in outgroup {
+        match taxon_set.to_id.get(name) {
+            Some(&id) => ids.insert(id as i32),
+            None => bail!("outgroup taxon {} is not in the tree", name),
+        };
+    }
+    let editable = EditableTree::from_tree(tree);
+    let is_outgroup = |t: &EditableTree, v: usize| t.is_leaf(v) && ids.contains(&t.taxa[v]);
+    let ingroup_leaf = editable
+        .postorder()
+        .into_iter()
+        .find(|&v| editable.is_leaf(v) && !is_outgroup(&editable, v));
+    let ingroup_leaf = match ingroup_leaf {
+        Some(v) => v,
+        None => bail!("outgroup spans every taxon of the tree"),
+    };
+    // rooting at an ingroup leaf first makes the outgroup's MRCA well defined
+    let rerooted = editable.reroot_on_edge(ingroup_leaf, 0.0);
+    let outgroup_leaves = rerooted
+        .postorder()
+        .into_iter()
+        .filter(|&v| is_outgroup(&rerooted, v))
+        .collect::<Vec<_>>();
+    let mrca = rerooted.mrca(&outgroup_leaves);
+    if rerooted.num_leaves_below(mrca) != outgroup_leaves.len() {
+        warn!("outgroup is not monophyletic, rooting at the MRCA of its taxa");
+    }
+    if mrca == rerooted.root {
+        return Ok(rerooted.into_tree(taxon_set));
+    }
+    let half = rerooted.edge_length(mrca).unwrap_or(0.0) / 2.0;
+    Ok(rerooted.reroot_on_edge(mrca, half).into_tree(taxon_set))
+}
+
+/// renames the taxa named in `table` in place, keeping their ids; names are
+/// passed through `newick_label` so that the tree can still be written out.
+/// Returns how many taxa were renamed
+pub fn rename_taxa(
+    taxon_set: &mut TaxonSet,
+    table: &AHashMap<String, String>,
+) -> anyhow::Result<usize> {
+    let mut renamed = 0;
+    for id in 0..taxon_set.len() {
+        let to = match table.get(&taxon_set.names[id]) {
+            Some(to) => newick_label(to),
+            None => continue,
+        };
+        if to == taxon_set.names[id] {
+            continue;
+        }
+        if taxon_set.to_id.contains_key(&to) {
+            bail!(
+                "renaming {} to {} clashes with another taxon",
+                taxon_set.names[id],
+                to
+            );
+        }
+        let from = std::mem::replace(&mut taxon_set.names[id], to.clone());
+        taxon_set.to_id.remove(&from);
+        taxon_set.to_id.insert(to, id);
+        renamed += 1;
+    }
+    Ok(renamed)
+}
@@ -0,0 +1,323 @@
+use crate::{
+    alphabet::CharCounter,
+    input::{decode_text, read_alignment},
+    melt::backbone_path_under,
+    output::{find_output, unpack_directory, write_fasta},
+    structures::{metadata_path, subset_dir, CrucibleCtxt},
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use seq_io::fasta::OwnedRecord;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+use tracing::{info, warn};
+
+/// the files of a melt that have sequences only without their gaps
+const UNALIGNED_FILES: &[&str] = &["queries.fasta", "unplaced.fasta"];
+
+/// puts the sequences of the aligned subset file at `path`, of the positions `range`
+/// (with every column of the alignment or, if trimmed, only its `columns`), into their
+/// `rows`,
+/// checking that they are those any other subset has for them
+fn fill_rows(
+    path: &Path,
+    range: (usize, usize),
+    columns: Option<&[usize]>,
+    positions: &AHashMap<&str, usize>,
+    width: usize,
+    rows: &mut [Option<OwnedRecord>],
+) -> anyhow::Result<()> {
+    let records = read_alignment(path)
+        .with_context(|| format!("cannot read the melted alignment {}", path.display()))?
+        .records;
+    let (lb, ub) = range;
+    if records.len() != ub - lb {
+        bail!(
+            "{} has {} sequences, but its subset in melt.json has {}",
+            path.display(),
+            records.len(),
+            ub - lb
+        );
+    }
+    for r in records {
+        let name = decode_text(&r.head).into_owned();
+        let p = match positions.get(name.as_str()) {
+            Some(&p) if (lb..ub).contains(&p) => p,
+            _ => bail!(
+                "{} has {}, which its subset in melt.json does not",
+                path.display(),
+                name
+            ),
+        };
+        let trimmed = columns.filter(|c| r.seq.len() != width && r.seq.len() == c.len());
+        if r.seq.len() != width && trimmed.is_none() {
+            bail!(
+                "sequence {} of {} is not {} columns wide",
+                name,
+                path.display(),
+                width
+            );
+        }
+        let r = match trimmed {
+            // the columns trimmed off are gaps only
+            Some(columns) => {
+                let mut seq = vec![b'-'; width];
+                for (&j, &c) in columns.iter().zip(&r.seq) {
+                    seq[j] = c;
+                }
+                OwnedRecord { head: r.head, seq }
+            }
+            None => r,
+        };
+        match &rows[p] {
+            Some(row) if row.seq != r.seq => bail!(
+                "{} has another sequence for {} than the other subsets",
+                path.display(),
+                name
+            ),
+            Some(_) => {}
+            None => rows[p] = Some(r),
+        }
+    }
+    Ok(())
+}
+
+/// rebuilds the whole alignment melted into `outdir`, in the order of the
+/// decomposition, from the aligned files of its subsets (checking that they agree on
+/// every sequence) and, for any sequence in none of them, the whole alignment written
+/// along with them; metadata without the names of the taxa or the number of columns
+/// has the whole alignment read back instead
+pub fn unmelt_records(outdir: &Path) -> anyhow::Result<Vec<OwnedRecord>> {
+    let subsets_root = outdir.join("subsets");
+    unpack_directory(&subsets_root)?;
+    unmelt_records_from(outdir, &subsets_root)
+}
+
+/// rebuilds the whole alignment melted into `outdir` as `unmelt_records` does, its
+/// subsets being under `subsets_root` instead (e.g. unpacked elsewhere), without
+/// unpacking any archive
+pub fn unmelt_records_from(outdir: &Path, subsets_root: &Path) -> anyhow::Result<Vec<OwnedRecord>> {
+    let ctxt = CrucibleCtxt::from_path(metadata_path(outdir))?;
+    let expected = ctxt
+        .metadata
+        .iter()
+        .map(|m| m.sequence_range.1)
+        .max()
+        .unwrap_or(0);
+    if ctxt.reordered_taxa.is_empty() || ctxt.num_columns == 0 {
+        return read_whole(
+            &backbone_path_under(outdir, subsets_root, &ctxt),
+            &ctxt,
+            expected,
+        );
+    }
+    let width = ctxt.num_columns;
+    let positions = ctxt
+        .reordered_taxa
+        .iter()
+        .enumerate()
+        .map(|(p, &t)| (ctxt.taxon_names[t].as_str(), p))
+        .collect::<AHashMap<_, _>>();
+    let mut rows = vec![None; expected];
+    let whole = (0, expected);
+    let backbone = find_output(&outdir.join("backbone.afa"));
+    for (i, hmm) in ctxt.metadata.iter().enumerate() {
+        let dir = subset_dir(subsets_root, ctxt.shards, i);
+        for extension in ["afa", "sto"] {
+            let path = match find_output(&dir.join(ctxt.subset_file_name(i, extension))) {
+                Some(path) => path,
+                None => continue,
+            };
+            // without backbone.afa, 0.afa has the whole alignment instead
+            let range = if i == 0 && extension == "afa" && backbone.is_none() {
+                whole
+            } else {
+                hmm.sequence_range
+            };
+            let columns = hmm.subset_columns.as_deref();
+            fill_rows(&path, range, columns, &positions, width, &mut rows)?;
+        }
+    }
+    if rows.iter().any(Option::is_none) {
+        let path = backbone_path_under(outdir, subsets_root, &ctxt);
+        fill_rows(&path, whole, None, &positions, width, &mut rows)?;
+    }
+    let records = rows.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+    check_columns(&ctxt, &records, subsets_root)?;
+    Ok(records)
+}
+
+/// fails if the metadata refers to columns beyond those of `records`
+fn check_columns(ctxt: &CrucibleCtxt, records: &[OwnedRecord], path: &Path) -> anyhow::Result<()> {
+    let width = records.first().map_or(0, |r| r.seq.len());
+    if let Some(j) = (0..ctxt.num_hmms())
+        .flat_map(|i| ctxt.column_counts(i).map(|(j, _)| j))
+        .find(|&j| j >= width)
+    {
+        bail!(
+            "melt.json refers to column {}, but {} has {} columns",
+            j,
+            path.display(),
+            width
+        );
+    }
+    Ok(())
+}
+
+/// reads back the whole alignment written along with the subsets, at `path`, of
+/// `expected` sequences, checking it against the metadata
+fn read_whole(
+    path: &Path,
+    ctxt: &CrucibleCtxt,
+    expected: usize,
+) -> anyhow::Result<Vec<OwnedRecord>> {
+    let records = read_alignment(path)
+        .with_context(|| format!("cannot read the melted alignment {}", path.display()))?
+        .records;
+    if records.len() != expected {
+        bail!(
+            "{} has {} sequences, but the subsets in melt.json cover {}",
+            path.display(),
+            records.len(),
+            expected
+        );
+    }
+    if !ctxt.reordered_taxa.is_empty() {
+        let names = ctxt
+            .reordered_taxa
+            .iter()
+            .map(|&t| ctxt.taxon_names[t].as_str());
+        if let Some((r, name)) = records
+            .iter()
+            .zip(names)
+            .find(|(r, name)| decode_text(&r.head) != *name)
+        {
+            bail!(
+                "{} has {} where melt.json has {}",
+                path.display(),
+                decode_text(&r.head),
+                name
+            );
+        }
+    }
+    let width = records.first().map_or(0, |r| r.seq.len());
+    if let Some(r) = records.iter().find(|r| r.seq.len() != width) {
+        bail!(
+            "sequence {} of {} is not {} columns wide",
+            decode_text(&r.head),
+            path.display(),
+            width
+        );
+    }
+    if ctxt.num_columns > 0 && width != ctxt.num_columns {
+        bail!(
+            "{} has {} columns, but melt.json has {}",
+            path.display(),
+            width,
+            ctxt.num_columns
+        );
+    }
+    check_columns(ctxt, &records, path)?;
+    Ok(records)
+}
+
+/// reconstructs the alignment melted into `outdir`, writing it to `output` if given,
+/// and checks that it has the same sequences as the `source` alignment (in any
+/// order), failing otherwise; the source is taken as melted, with the headers, gaps
+/// and stripped columns the metadata records, and without the records it left out
+pub fn oneshot_unmelt(
+    outdir: &Path,
+    output: Option<&Path>,
+    source: Option<&Path>,
+) -> anyhow::Result<()> {
+    let records = unmelt_records(outdir)?;
+    info!(num_sequences = records.len(), "reconstructed the alignment");
+    for name in UNALIGNED_FILES {
+        if outdir.join(name).exists() {
+            warn!(
+                file = name,
+                "left out sequences of the input that were kept only without their gaps"
+            );
+        }
+    }
+    if let Some(output) = output {
+        let mut writer = BufWriter::new(File::create(output)?);
+        for r in &records {
+            write_fasta(&mut writer, &r.head, &r.seq, None)?;
+        }
+        writer.flush()?;
+    }
+    if let Some(source) = source {
+        let ctxt = CrucibleCtxt::from_path(metadata_path(outdir))?;
+        let mut original = read_alignment(source)?.records;
+        CharCounter::new(&ctxt.characters).normalize_gaps(&mut original);
+        if let Some(&last) = ctxt.stripped_columns.last() {
+            let mut stripped = vec![false; last + 1];
+            for &j in &ctxt.stripped_columns {
+                stripped[j] = true;
+            }
+            for r in original.iter_mut() {
+                let mut j = 0;
+                r.seq.retain(|_| {
+                    j += 1;
+                    !stripped.get(j - 1).copied().unwrap_or(false)
+                });
+            }
+        }
+        // the sequences of the source by header, which several may share
+        let mut by_header: AHashMap<String, Vec<Vec<u8>>> = AHashMap::default();
+        for r in original {
+            let header = decode_text(&r.head).into_owned();
+            by_header.entry(header).or_default().push(r.seq);
+        }
+        let mut missing = vec![];
+        let mut different = vec![];
+        for r in &records {
+            let name = decode_text(&r.head);
+            let header = ctxt
+                .source_headers
+                .get(name.as_ref())
+                .map_or(name.as_ref(), String::as_str);
+            match by_header.get_mut(header) {
+                Some(seqs) if !seqs.is_empty() => match seqs.iter().position(|s| s == &r.seq) {
+                    Some(i) => {
+                        seqs.swap_remove(i);
+                    }
+                    None => {
+                        seqs.pop();
+                        different.push(name.into_owned());
+                    }
+                },
+                _ => missing.push(name.into_owned()),
+            }
+        }
+        for header in &ctxt.left_out {
+            if let Some(seqs) = by_header.get_mut(header) {
+                seqs.pop();
+            }
+        }
+        let mut extra = by_header
+            .into_iter()
+            .flat_map(|(header, seqs)| std::iter::repeat_n(header, seqs.len()))
+            .collect::<Vec<_>>();
+        extra.sort_unstable();
+        if !missing.is_empty() || !different.is_empty() || !extra.is_empty() {
+            bail!(
+                "the reconstructed alignment does not match {}: {} sequences differ (e.g. {}), \
+                 {} are not in it (e.g. {}), {} are only in it (e.g. {})",
+                source.display(),
+                different.len(),
+                different.first().map_or("none", String::as_str),
+                extra.len(),
+                extra.first().map_or("none", String::as_str),
+                missing.len(),
+                missing.first().map_or("none", String::as_str),
+            );
+        }
+        info!("the reconstructed alignment matches {}", source.display());
+    }
+    Ok(())
+}
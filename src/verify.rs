@@ -0,0 +1,273 @@
+use crate::{
+    alphabet::CharCounter,
+    input::read_alignment,
+    output::{checksum, find_output, unpack_directory_into},
+    structures::{metadata_path, subset_dir, CrucibleCtxt, PartitionedCtxt},
+    unmelt::unmelt_records_from,
+};
+use anyhow::bail;
+use std::{
+    fs::{create_dir_all, remove_dir_all, File},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// a directory of scratch files, removed with them once dropped
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// cross-checks the metadata of the melt in `outdir` against the files there: the
+/// checksums of the files, the ranges and columns of the subsets against the whole
+/// alignment, their character counts against those of the alignment, and the
+/// sequences of the subset files; also checks that `alignment` and `tree`, if given,
+/// are the inputs the melt was made from; returns the problems found
+pub fn verify_melt(
+    outdir: &Path,
+    alignment: Option<&Path>,
+    tree: Option<&Path>,
+) -> anyhow::Result<Vec<String>> {
+    let metadata_path = metadata_path(outdir);
+    let ctxt = match CrucibleCtxt::from_path(&metadata_path) {
+        // every count is checked, so they are copied out of any counts file
+        Ok(mut ctxt) => {
+            ctxt.load_counts();
+            ctxt
+        }
+        Err(e) => {
+            // a partitioned melt has a melt of its own for every locus
+            match PartitionedCtxt::from_path(&metadata_path) {
+                Ok(partitioned) => {
+                    let mut problems = vec![];
+                    for locus in &partitioned.loci {
+                        for problem in verify_melt(&outdir.join(&locus.name), alignment, tree)? {
+                            problems.push(format!("locus {}: {}", locus.name, problem));
+                        }
+                    }
+                    return Ok(problems);
+                }
+                Err(_) => return Err(e),
+            }
+        }
+    };
+    let mut problems = vec![];
+    let bindings = [
+        alignment.map(|path| ctxt.check_alignment(path)),
+        tree.map(|path| ctxt.check_tree(path)),
+    ];
+    for e in bindings.into_iter().flatten().filter_map(Result::err) {
+        problems.push(format!("{:#}", e));
+    }
+    for (name, expected) in &ctxt.checksums {
+        match File::open(outdir.join(name)) {
+            Ok(file) => {
+                if &checksum(file)? != expected {
+                    problems.push(format!("{} does not have the checksum recorded", name));
+                }
+            }
+            Err(_) => problems.push(format!("{} is missing", name)),
+        }
+    }
+    // archived subsets are unpacked elsewhere, leaving the melt as it is
+    let mut subsets_root = outdir.join("subsets");
+    let mut _scratch = None;
+    if !subsets_root.exists() {
+        let dir = std::env::temp_dir().join(format!("crucible-verify-{}", std::process::id()));
+        create_dir_all(&dir)?;
+        let scratch = ScratchDir(dir);
+        if unpack_directory_into(&subsets_root, &scratch.0)? {
+            subsets_root = scratch.0.join("subsets");
+        }
+        _scratch = Some(scratch);
+    }
+    let records = match unmelt_records_from(outdir, &subsets_root) {
+        Ok(records) => records,
+        Err(e) => {
+            problems.push(format!("{:#}", e));
+            return Ok(problems);
+        }
+    };
+    let width = records.first().map_or(0, |r| r.seq.len());
+    let counter = CharCounter::new(&ctxt.characters);
+    let backbone = find_output(&outdir.join("backbone.afa"));
+    for (i, hmm) in ctxt.metadata.iter().enumerate() {
+        let (lb, ub) = hmm.sequence_range;
+        if lb >= ub {
+            problems.push(format!("subset {} has the empty range {}..{}", i, lb, ub));
+            continue;
+        }
+        if hmm.chars_cnt.len() != hmm.column_poitions.len() {
+            problems.push(format!(
+                "subset {} has {} character counts for {} columns",
+                i,
+                hmm.chars_cnt.len(),
+                hmm.column_poitions.len()
+            ));
+        } else if hmm.column_poitions.windows(2).any(|w| w[0] >= w[1]) {
+            problems.push(format!("the columns of subset {} are not sorted", i));
+        } else {
+            let recounted = hmm
+                .column_poitions
+                .iter()
+                .map(|&j| {
+                    records[lb..ub]
+                        .iter()
+                        .filter(|r| counter.counts(r.seq[j]))
+                        .count() as u32
+                })
+                .collect::<Vec<_>>();
+            if recounted != hmm.chars_cnt {
+                problems.push(format!(
+                    "the character counts of subset {} differ from those of the alignment",
+                    i
+                ));
+            }
+            if let Some(classes) = ctxt.residue_classes {
+                let table = classes.table();
+                let recounted = (0..classes.num_classes())
+                    .map(|class| {
+                        hmm.column_poitions
+                            .iter()
+                            .map(|&j| {
+                                records[lb..ub]
+                                    .iter()
+                                    .filter(|r| {
+                                        counter.counts(r.seq[j])
+                                            && table[r.seq[j] as usize] as usize == class
+                                    })
+                                    .count() as u32
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                if recounted != hmm.class_counts {
+                    problems.push(format!(
+                        "the residue class counts of subset {} differ from those of the alignment",
+                        i
+                    ));
+                }
+            }
+        }
+        if !hmm.weights.is_empty() && hmm.weights.len() != ub - lb + hmm.padding.len() {
+            problems.push(format!(
+                "subset {} has {} weights for {} sequences",
+                i,
+                hmm.weights.len(),
+                ub - lb + hmm.padding.len()
+            ));
+        }
+        let dir = subset_dir(&subsets_root, ctxt.shards, i);
+        if !dir.join(ctxt.subset_file_name(i, "hmm")).exists() {
+            problems.push(format!("the HMM of subset {} is missing", i));
+        }
+        let aligned_width = hmm.subset_columns.as_ref().map_or(width, Vec::len);
+        for (extension, aligned) in [("afa", true), ("sto", true), ("fasta", false)] {
+            let path = match find_output(&dir.join(ctxt.subset_file_name(i, extension))) {
+                Some(path) => path,
+                None => continue,
+            };
+            // without backbone.afa, 0.afa has the whole alignment instead, which has
+            // every column unless it is also the trimmed subset 0 (as `unmelt` tells)
+            let whole = i == 0 && extension == "afa" && backbone.is_none();
+            let expected = if whole { records.len() } else { ub - lb };
+            match read_alignment(&path) {
+                Ok(alignment) => {
+                    if alignment.records.len() != expected {
+                        problems.push(format!(
+                            "{} has {} sequences instead of {}",
+                            path.display(),
+                            alignment.records.len(),
+                            expected
+                        ));
+                    } else if aligned
+                        && alignment.records.iter().any(|r| {
+                            r.seq.len() != aligned_width && !(whole && r.seq.len() == width)
+                        })
+                    {
+                        problems.push(format!(
+                            "{} is not {} columns wide",
+                            path.display(),
+                            aligned_width
+                        ));
+                    }
+                }
+                Err(e) => problems.push(format!("{:#}", e)),
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// runs `verify_melt` on `outdir`, failing if anything is wrong
+pub fn oneshot_verify(
+    outdir: &Path,
+    alignment: Option<&Path>,
+    tree: Option<&Path>,
+) -> anyhow::Result<()> {
+    let problems = verify_melt(outdir, alignment, tree)?;
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("found {} problems in {}", problems.len(), outdir.display());
+    }
+    info!("{} is consistent with its metadata", outdir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        output::pack_directory,
+        structures::{HmmMeta, MetadataFormat},
+    };
+    use std::fs::write;
+
+    /// writes into `dir` the melt of three sequences into one subset, trimmed of its
+    /// all-gap column and written as Stockholm, subsets/0.afa having the whole
+    /// alignment
+    fn trimmed_melt(dir: &Path) {
+        let _ = remove_dir_all(dir);
+        create_dir_all(dir.join("subsets")).unwrap();
+        let mut hmm = HmmMeta::new((0, 3), vec![3, 1, 2], vec![0, 1, 3]);
+        hmm.subset_columns = Some(vec![0, 1, 3]);
+        let mut ctxt = CrucibleCtxt::new(vec![hmm], false);
+        ctxt.taxon_names = vec!["A".into(), "B".into(), "C".into()];
+        ctxt.reordered_taxa = vec![0, 1, 2];
+        ctxt.num_columns = 4;
+        let format = MetadataFormat::Json;
+        ctxt.save(&dir.join(format.file_name()), format, None)
+            .unwrap();
+        let subsets = dir.join("subsets");
+        write(subsets.join("0.afa"), ">A\nAC-G\n>B\nA--T\n>C\nT---\n").unwrap();
+        write(
+            subsets.join("0.sto"),
+            "# STOCKHOLM 1.0\nA ACG\nB A-T\nC T--\n#=GC RF xxx\n//\n",
+        )
+        .unwrap();
+        write(subsets.join("0.hmm"), "").unwrap();
+    }
+
+    #[test]
+    fn whole_alignment_keeps_its_all_gap_columns() {
+        let dir = std::env::temp_dir().join("crucible-test-verify-trimmed");
+        trimmed_melt(&dir);
+        assert_eq!(verify_melt(&dir, None, None).unwrap(), Vec::<String>::new());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn archived_subsets_stay_archived() {
+        let dir = std::env::temp_dir().join("crucible-test-verify-archived");
+        trimmed_melt(&dir);
+        pack_directory(&dir.join("subsets"), None).unwrap();
+        assert_eq!(verify_melt(&dir, None, None).unwrap(), Vec::<String>::new());
+        assert!(!dir.join("subsets").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}